@@ -0,0 +1,106 @@
+// Benchmarks for FileBlame::apply_line_diffs, the hot loop of the whole analysis.
+// Run with `cargo bench`, and compare against a baseline with `--save-baseline`/`--baseline`.
+
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use gix_of_theseus::blame::{FileBlame, LineDiffs, LineNumber};
+use std::hint::black_box;
+
+const FILE_LINES: LineNumber = 5_000;
+
+// A file that has been edited a lot already, with a change point every few lines
+fn fragmented_blame() -> FileBlame<usize> {
+    let mut blame = FileBlame::new(FILE_LINES, 0);
+    for cohort in 1..50 {
+        let diffs = (0..FILE_LINES / 100)
+            .map(|i| {
+                let start = i * 100 + (cohort as LineNumber * 7) % 90;
+                (start..start + 3, start..start + 3, cohort)
+            })
+            .collect();
+        blame = blame.apply_line_diffs(diffs).unwrap();
+    }
+    blame
+}
+
+// Small hunks spread over the whole file: replacements, insertions and deletions, in file order
+fn scattered_hunks(cohort: usize) -> LineDiffs<usize> {
+    let mut diffs = Vec::new();
+    let mut offset: i64 = 0;
+    for i in 0..(FILE_LINES / 50) {
+        let old_start = i * 50 + 10;
+        let new_start = (old_start as i64 + offset) as LineNumber;
+        let (deleted, inserted) = match i % 3 {
+            0 => (2, 2),
+            1 => (0, 4),
+            _ => (3, 0),
+        };
+        diffs.push((
+            old_start..old_start + deleted,
+            new_start..new_start + inserted,
+            cohort,
+        ));
+        offset += inserted as i64 - deleted as i64;
+    }
+    diffs
+}
+
+// Equal-length replacements clustered near the end of the file,
+// like test_apply_line_diffs_equal_length_hunks_near_end in blame.rs
+fn equal_length_hunks_near_end(cohort: usize) -> LineDiffs<usize> {
+    [
+        46..49,
+        65..66,
+        92..95,
+        99..100,
+        104..105,
+        106..107,
+        109..110,
+        111..113,
+        138..145,
+        146..149,
+        153..154,
+    ]
+    .into_iter()
+    .map(|lines| {
+        let lines = lines.start + FILE_LINES - 160..lines.end + FILE_LINES - 160;
+        (lines.clone(), lines, cohort)
+    })
+    .collect()
+}
+
+// One big rewrite in the middle of the file
+fn large_replacement(cohort: usize) -> LineDiffs<usize> {
+    vec![(1_000..3_000, 1_000..2_500, cohort)]
+}
+
+// Makes the hunks of a commit, all attributed to the given cohort
+type Scenario = fn(usize) -> LineDiffs<usize>;
+
+fn bench_apply_line_diffs(c: &mut Criterion) {
+    let scenarios: [(&str, Scenario); 3] = [
+        ("scattered_hunks", scattered_hunks),
+        ("equal_length_hunks_near_end", equal_length_hunks_near_end),
+        ("large_replacement", large_replacement),
+    ];
+    let starting_blames = [
+        ("fresh", FileBlame::new(FILE_LINES, 0)),
+        ("fragmented", fragmented_blame()),
+    ];
+
+    let mut group = c.benchmark_group("apply_line_diffs");
+    for (blame_name, blame) in &starting_blames {
+        for (scenario_name, make_diffs) in &scenarios {
+            group.bench_function(format!("{blame_name}/{scenario_name}"), |b| {
+                b.iter_batched(
+                    || make_diffs(100),
+                    |diffs| black_box(blame.apply_line_diffs(diffs)),
+                    BatchSize::SmallInput,
+                );
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_apply_line_diffs);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+// Benchmarks for the queue between the diffing threads and the BlameProcessor: how much a small
+// bounded queue costs in throughput, compared to one big enough to never block.
+// Run with `cargo bench --bench processor_queue`.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use gix::bstr::BString;
+use gix_of_theseus::actions::Action;
+use gix_of_theseus::blame::LineNumber;
+use gix_of_theseus::repo_blame_snapshot::{BlameProcessor, RepositoryBlameSnapshot};
+use std::hint::black_box;
+
+const FILES: usize = 100;
+const FILE_LINES: LineNumber = 1_000;
+const COMMITS: usize = 50;
+
+// Adds the files in the first commit, then edits each of them in every later commit, sending the
+// actions from this thread like the main thread of the analysis does
+fn run_processor(queue_size: usize) -> u64 {
+    let snapshot = RepositoryBlameSnapshot::new(gix::ObjectId::null(gix::hash::Kind::Sha1));
+    let processor = BlameProcessor::with_hooks(snapshot, queue_size, |_| Ok(()), |_| Ok(()));
+    let sender = processor.sender();
+    let path = |file: usize| BString::from(format!("src/file_{file}.rs"));
+    for file in 0..FILES {
+        sender
+            .send(Action::AddFile {
+                path: path(file),
+                total_lines: FILE_LINES,
+                cohort: 0usize,
+                id: None,
+            })
+            .unwrap();
+    }
+    sender.send(Action::FinishCommit).unwrap();
+    for commit in 1..COMMITS {
+        for file in 0..FILES {
+            // An insertion and a deletion of the same size, so the files keep their length
+            sender
+                .send(Action::ModifyFile {
+                    path: path(file),
+                    line_diffs: vec![(10..20, 10..25, commit), (500..510, 505..510, commit)],
+                })
+                .unwrap();
+        }
+        sender.send(Action::FinishCommit).unwrap();
+    }
+    drop(sender);
+    processor.finish().unwrap().total_lines()
+}
+
+fn bench_processor_queue(c: &mut Criterion) {
+    let mut group = c.benchmark_group("processor_queue");
+    group.sample_size(20);
+    for queue_size in [16, 1_000, 10_000] {
+        group.bench_function(format!("queue_size_{queue_size}"), |b| {
+            b.iter(|| black_box(run_processor(queue_size)));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_processor_queue);
+criterion_main!(benches);
@@ -1,7 +1,14 @@
 use crate::blame::{Keyable, LineDiffs, LineNumber};
 use gix::bstr::BString;
 
+// With the record feature, actions can be written to a file and read back.
+// Cohort keys are written with their Display form and read back with FromStr, like every Keyable.
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "record",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "")
+)]
 pub enum Action<CommitKey>
 where
     CommitKey: Keyable,
@@ -9,10 +16,19 @@ where
     AddFile {
         path: BString,
         total_lines: LineNumber,
+        #[cfg_attr(feature = "record", serde(with = "key_string"))]
         cohort: CommitKey,
+        // The blob that was added, if it's in the object database (worktree files aren't)
+        id: Option<gix::ObjectId>,
     },
     DeleteFile {
         path: BString,
+        // The blob that was deleted
+        id: gix::ObjectId,
+    },
+    // A file that was added but is left out of the analysis, along with its later changes
+    SkipFile {
+        path: BString,
     },
     RenameFile {
         old_path: BString,
@@ -20,8 +36,61 @@ where
     },
     ModifyFile {
         path: BString,
+        #[cfg_attr(feature = "record", serde(with = "line_diffs_key_strings"))]
         line_diffs: LineDiffs<CommitKey>,
     },
     FinishCommit,
     SetCommitId(gix::ObjectId),
 }
+
+#[cfg(feature = "record")]
+mod key_string {
+    use crate::blame::Keyable;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<K: Keyable, S: Serializer>(key: &K, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(key)
+    }
+
+    pub fn deserialize<'de, K: Keyable, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<K, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        key.parse()
+            .map_err(|_| D::Error::custom(format!("invalid cohort key {key}")))
+    }
+}
+
+#[cfg(feature = "record")]
+mod line_diffs_key_strings {
+    use crate::blame::{Keyable, LineDiffs, LineNumber};
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::ops::Range;
+
+    pub fn serialize<K: Keyable, S: Serializer>(
+        line_diffs: &LineDiffs<K>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            line_diffs
+                .iter()
+                .map(|(old, new, cohort)| (old, new, cohort.to_string())),
+        )
+    }
+
+    pub fn deserialize<'de, K: Keyable, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<LineDiffs<K>, D::Error> {
+        Vec::<(Range<LineNumber>, Range<LineNumber>, String)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(old, new, cohort)| {
+                let key = cohort
+                    .parse()
+                    .map_err(|_| D::Error::custom(format!("invalid cohort key {cohort}")))?;
+                Ok((old, new, key))
+            })
+            .collect()
+    }
+}
@@ -9,7 +9,19 @@ pub type LineDiffs<CommitKey> = Vec<(
     std::ops::Range<LineNumber>,
     CommitKey,
 )>;
-
+// Runs of lines that went from the first cohort to the second, see FileBlame::diff
+pub type CohortTransitions<CommitKey> = Vec<(std::ops::Range<LineNumber>, CommitKey, CommitKey)>;
+
+/// What a cohort key needs to be usable in the blame engine. A cohort key is whatever
+/// you want to attribute lines to: a commit index, a year, an author id or a composite of these.
+/// - `Copy`, since a key is stored at every change point of every file.
+/// - `Hash + Eq`, since cohort stats are kept in hash maps.
+/// - `Ord`, so results can be sorted deterministically.
+/// - `Send + Sync + 'static`, since keys travel in `Action`s from the diffing threads to the blame processor thread.
+/// - `Debug + Display + FromStr`, to print keys and read them back.
+///
+/// It's implemented for every type that meets these bounds. Tuples don't implement `Display` or `FromStr`,
+/// so composite keys need a small newtype (e.g. a year and an author id packed into a `u64`).
 pub trait Keyable:
     std::fmt::Debug
     + Send
@@ -37,23 +49,28 @@ impl<T> Keyable for T where
 {
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct BlameRange<CommitKey>
+/// A run of consecutive lines attributed to the same cohort: lines `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlameSpan<CommitKey>
 where
     CommitKey: Keyable,
 {
-    pub start_line: LineNumber,
-    pub line_count: LineNumber,
+    pub start: LineNumber,
+    pub end: LineNumber,
     pub cohort: CommitKey,
 }
 
-impl<CommitKey: Keyable> BlameRange<CommitKey> {
-    pub fn new(start_line: LineNumber, line_count: LineNumber, cohort: CommitKey) -> Self {
-        Self {
-            start_line,
-            line_count,
-            cohort,
-        }
+impl<CommitKey: Keyable> BlameSpan<CommitKey> {
+    pub fn new(start: LineNumber, end: LineNumber, cohort: CommitKey) -> Self {
+        Self { start, end, cohort }
+    }
+
+    pub fn line_count(&self) -> LineNumber {
+        self.end - self.start
+    }
+
+    pub fn lines(&self) -> std::ops::Range<LineNumber> {
+        self.start..self.end
     }
 }
 
@@ -84,6 +101,24 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
         }
     }
 
+    /// Rebuilds a blame from its spans, as returned by `ranges`.
+    pub fn from_spans(
+        total_lines: LineNumber,
+        spans: impl IntoIterator<Item = BlameSpan<CommitKey>>,
+    ) -> Self {
+        let mut change_points = BTreeMap::new();
+        let mut cohort_stats = std::collections::HashMap::new();
+        for span in spans {
+            change_points.insert(span.start, span.cohort);
+            *cohort_stats.entry(span.cohort).or_insert(0) += span.line_count() as u64;
+        }
+        Self {
+            change_points,
+            total_lines,
+            cohort_stats,
+        }
+    }
+
     pub fn total_lines(&self) -> LineNumber {
         self.total_lines
     }
@@ -121,8 +156,8 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
         }
     }
 
-    /// Get an iterator over ranges as (start_line, end_line, cohort)
-    pub fn ranges(&self) -> impl Iterator<Item = (LineNumber, LineNumber, CommitKey)> + '_ {
+    /// Get an iterator over the spans of lines attributed to each cohort, from the top of the file
+    pub fn ranges(&self) -> impl Iterator<Item = BlameSpan<CommitKey>> + '_ {
         let mut iter = self.change_points.iter().peekable();
         std::iter::from_fn(move || {
             if let Some((&start, &cohort)) = iter.next() {
@@ -139,17 +174,57 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
                     iter.peek().map(|(k, _)| *k),
                     self.total_lines
                 );
-                Some((start, end, cohort))
+                Some(BlameSpan::new(start, end, cohort))
             } else {
                 None
             }
         })
     }
 
+    /// The widest span of the file, e.g. to find its biggest block of untouched code.
+    /// Ties go to the span nearest the top of the file. `None` for an empty file.
+    pub fn longest_range(&self) -> Option<BlameSpan<CommitKey>> {
+        self.ranges()
+            .filter(|span| span.line_count() > 0)
+            .min_by_key(|span| std::cmp::Reverse(span.line_count()))
+    }
+
+    /// Splits the blame in two at `line`: the blame of lines `0..line` and the blame of
+    /// lines `line..total_lines`, renumbered from 0. `line` is clamped to `total_lines`,
+    /// so splitting at 0 or at the end gives an empty blame on one side.
+    pub fn split_at(&self, line: LineNumber) -> (Self, Self) {
+        let line = line.min(self.total_lines);
+        let mut head = Self {
+            change_points: self
+                .change_points
+                .range(..line)
+                .map(|(&start, &cohort)| (start, cohort))
+                .collect(),
+            total_lines: line,
+            cohort_stats: std::collections::HashMap::new(),
+        };
+        let mut tail = Self {
+            change_points: self
+                .change_points
+                .range(line..)
+                .map(|(&start, &cohort)| (start - line, cohort))
+                .collect(),
+            total_lines: self.total_lines - line,
+            cohort_stats: std::collections::HashMap::new(),
+        };
+        // The range that covers `line` usually starts above it, so the tail needs its own first change point
+        if let Some(cohort) = self.cohort_at_index(line) {
+            tail.change_points.insert(0, cohort);
+        }
+        head.cohort_stats = head.compute_cohort_stats();
+        tail.cohort_stats = tail.compute_cohort_stats();
+        (head, tail)
+    }
+
     fn compute_cohort_stats(&self) -> std::collections::HashMap<CommitKey, u64> {
         let mut stats = std::collections::HashMap::new();
-        for (start, end, cohort) in self.ranges() {
-            *stats.entry(cohort).or_insert(0) += (end - start) as u64;
+        for span in self.ranges() {
+            *stats.entry(span.cohort).or_insert(0) += span.line_count() as u64;
         }
         stats
     }
@@ -158,6 +233,55 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
         self.cohort_stats.clone()
     }
 
+    // The same counts as cohort_stats, but sorted by cohort so the output is deterministic
+    pub fn cohort_line_counts(&self) -> Vec<(CommitKey, u64)> {
+        self.sorted_cohort_stats().into_iter().collect()
+    }
+
+    pub fn sorted_cohort_stats(&self) -> BTreeMap<CommitKey, u64> {
+        self.cohort_stats
+            .iter()
+            .map(|(&cohort, &count)| (cohort, count))
+            .collect()
+    }
+
+    /// The lines whose cohort differs between this blame and `other`, compared line by line, as
+    /// `(lines, old_cohort, new_cohort)` runs from the top of the file. Only the lines both blames
+    /// have are compared, so lines added or removed at the end don't show up.
+    /// With `other = self.apply_line_diffs(diffs)`, this gives the lines a commit took over.
+    pub fn diff(&self, other: &Self) -> CohortTransitions<CommitKey> {
+        let end = self.total_lines.min(other.total_lines);
+        let starts: std::collections::BTreeSet<LineNumber> = self
+            .change_points
+            .range(..end)
+            .chain(other.change_points.range(..end))
+            .map(|(&start, _)| start)
+            .collect();
+        let mut transitions: CohortTransitions<CommitKey> = Vec::new();
+        let mut starts = starts.into_iter().peekable();
+        while let Some(start) = starts.next() {
+            let segment_end = starts.peek().copied().unwrap_or(end);
+            // Both blames have a change point at 0, so every line below `end` has a cohort on each side
+            let (Some(old), Some(new)) =
+                (self.cohort_at_index(start), other.cohort_at_index(start))
+            else {
+                continue;
+            };
+            if old == new {
+                continue;
+            }
+            match transitions.last_mut() {
+                Some((lines, last_old, last_new))
+                    if lines.end == start && *last_old == old && *last_new == new =>
+                {
+                    lines.end = segment_end;
+                }
+                _ => transitions.push((start..segment_end, old, new)),
+            }
+        }
+        transitions
+    }
+
     pub fn validate(&self) -> Result<(), String> {
         if self.total_lines == 0 {
             if !self.change_points.is_empty() {
@@ -205,15 +329,27 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
     // to track the delta we need to update the line numbers by.
     //
     // We have property tests against a reference implementation to validate correctness.
-    pub fn apply_line_diffs(&self, line_diffs: LineDiffs<CommitKey>) -> Self {
+    pub fn apply_line_diffs(&self, line_diffs: LineDiffs<CommitKey>) -> Result<Self, String> {
         if line_diffs.is_empty() {
-            return self.clone();
+            return Ok(self.clone());
         }
         // Order the diffs by the start of the delete range, in case they aren't.
         // Shouldn't be needed, but typically sorting a presorted list is not
         // very expensive.
         let mut diffs = line_diffs.clone();
         diffs.sort_by_key(|(before, _, _)| before.start);
+        let old_total = self.total_lines;
+        // The deletes have to be within the file and not overlap, or the line numbers below
+        // would go out of bounds and the blame would silently come out corrupt
+        let mut previous_end = 0;
+        for (delete, insert, _) in &diffs {
+            if delete.start < previous_end || delete.end < delete.start || delete.end > old_total {
+                return Err(format!(
+                    "hunk {delete:?} -> {insert:?} doesn't fit in a file of {old_total} lines"
+                ));
+            }
+            previous_end = delete.end;
+        }
 
         // This algorithm works by building a new version of the change points,
         // copying them over. Instead of mutating the blame in place, we apply
@@ -222,7 +358,6 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
         // As we copy change points into the new blame, we also apply the offset to the line numbers.
         let mut new_change_points: BTreeMap<LineNumber, CommitKey> = BTreeMap::new();
         let mut cp_iter = self.change_points.iter().peekable();
-        let old_total = self.total_lines;
         let mut offset: LineDelta = 0;
 
         // Helper to append a change point to the new blame only if it's different than the current last one
@@ -249,11 +384,7 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
             // offset to them.
             while let Some((&line, &line_cohort)) = cp_iter.peek().copied() {
                 if line < delete_start {
-                    push_cp(
-                        (line as LineDelta + offset) as LineNumber,
-                        line_cohort,
-                        &mut new_change_points,
-                    );
+                    push_cp(shift(line, offset)?, line_cohort, &mut new_change_points);
                     cp_iter.next();
                 } else {
                     break;
@@ -262,11 +393,7 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
 
             // Insert the new lines' cohort at delete_start
             if insert_len > 0 {
-                push_cp(
-                    (delete_start as LineDelta + offset) as LineNumber,
-                    cohort,
-                    &mut new_change_points,
-                );
+                push_cp(shift(delete_start, offset)?, cohort, &mut new_change_points);
             }
 
             // Skip change points that lie within [delete_start, delete_end)
@@ -283,8 +410,7 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
             if delete_end < old_total {
                 if let Some(resume_cohort) = self.cohort_at_index(delete_end) {
                     push_cp(
-                        (delete_start as LineDelta + insert_len as LineDelta + offset)
-                            as LineNumber,
+                        shift(delete_start, insert_len as LineDelta + offset)?,
                         resume_cohort,
                         &mut new_change_points,
                     );
@@ -296,14 +422,10 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
 
         // Copy over the remaining change points after the last delete
         while let Some((&line, &line_cohort)) = cp_iter.next() {
-            push_cp(
-                (line as LineDelta + offset) as LineNumber,
-                line_cohort,
-                &mut new_change_points,
-            );
+            push_cp(shift(line, offset)?, line_cohort, &mut new_change_points);
         }
 
-        let new_total = (old_total as LineDelta + offset) as LineNumber;
+        let new_total = shift(old_total, offset)?;
         let mut new_blame = Self {
             change_points: new_change_points,
             total_lines: new_total,
@@ -333,10 +455,16 @@ impl<CommitKey: Keyable> FileBlame<CommitKey> {
             old_total,
             offset.abs(),
         );
-        new_blame
+        Ok(new_blame)
     }
 }
 
+// Moves a line number by an offset, with an error instead of a wrap around if it goes out of bounds
+fn shift(line: LineNumber, offset: LineDelta) -> Result<LineNumber, String> {
+    LineNumber::try_from(line as LineDelta + offset)
+        .map_err(|_| format!("line {line} moved by {offset} is out of bounds"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -347,11 +475,77 @@ mod tests {
     #[test]
     fn test_cohort_stats() {
         let blame = FileBlame::new(0, 2022);
-        let blame = blame.apply_line_diffs(vec![(0..0, 0..10, 2022), (0..0, 5..10, 2023)]);
+        let blame = blame
+            .apply_line_diffs(vec![(0..0, 0..10, 2022), (0..0, 5..10, 2023)])
+            .unwrap();
 
         let stats = blame.cohort_stats();
         assert_eq!(stats.get(&2022), Some(&10));
         assert_eq!(stats.get(&2023), Some(&5));
+        assert_eq!(blame.cohort_line_counts(), vec![(2022, 10), (2023, 5)]);
+    }
+
+    #[test]
+    fn test_ranges_yield_spans() {
+        let blame = FileBlame::new(10, 2022);
+        let blame = blame.apply_line_diffs(vec![(4..6, 4..7, 2023)]).unwrap();
+
+        let spans: Vec<_> = blame.ranges().collect();
+        assert_eq!(
+            spans,
+            vec![
+                BlameSpan::new(0, 4, 2022),
+                BlameSpan::new(4, 7, 2023),
+                BlameSpan::new(7, 11, 2022),
+            ]
+        );
+        assert_eq!(spans[1].line_count(), 3);
+    }
+
+    #[test]
+    fn test_longest_range_prefers_the_earliest_tie() {
+        let blame = FileBlame::new(10, 2022);
+        let blame = blame.apply_line_diffs(vec![(4..6, 4..8, 2023)]).unwrap();
+        assert_eq!(blame.longest_range(), Some(BlameSpan::new(0, 4, 2022)));
+
+        assert_eq!(FileBlame::new(0, 2022).longest_range(), None);
+    }
+
+    #[test]
+    fn test_diff_lists_the_lines_that_changed_cohort() {
+        let old = FileBlame::new(10, 2022)
+            .apply_line_diffs(vec![(2..4, 2..4, 2023)])
+            .unwrap();
+        let new = old
+            .apply_line_diffs(vec![(1..3, 1..3, 2024), (6..8, 6..8, 2024)])
+            .unwrap();
+        assert_eq!(
+            old.diff(&new),
+            vec![(1..2, 2022, 2024), (2..3, 2023, 2024), (6..8, 2022, 2024)]
+        );
+        assert_eq!(new.diff(&new), vec![]);
+        // Only the lines both blames have are compared
+        assert_eq!(old.diff(&FileBlame::new(3, 2022)), vec![(2..3, 2023, 2022)]);
+    }
+
+    #[test]
+    fn test_split_at_edges() {
+        let blame = FileBlame::new(10, 2022)
+            .apply_line_diffs(vec![(4..6, 4..6, 2023)])
+            .unwrap();
+
+        let (head, tail) = blame.split_at(5);
+        assert_eq!(expand_file_blame(&head), vec![2022, 2022, 2022, 2022, 2023]);
+        assert_eq!(expand_file_blame(&tail), vec![2023, 2022, 2022, 2022, 2022]);
+        assert_eq!(tail.cohort_stats().get(&2023), Some(&1));
+
+        for (line, head_lines, tail_lines) in [(0, 0, 10), (10, 10, 0), (20, 10, 0)] {
+            let (head, tail) = blame.split_at(line);
+            head.validate().unwrap();
+            tail.validate().unwrap();
+            assert_eq!(head.total_lines(), head_lines);
+            assert_eq!(tail.total_lines(), tail_lines);
+        }
     }
 
     #[test]
@@ -374,7 +568,7 @@ mod tests {
         ];
 
         let old_total = blame.total_lines();
-        let blame = blame.apply_line_diffs(diffs);
+        let blame = blame.apply_line_diffs(diffs).unwrap();
         assert_eq!(blame.total_lines(), old_total);
         blame.validate().unwrap();
     }
@@ -383,11 +577,15 @@ mod tests {
     fn test_apply_line_diffs_insertion_then_deletion_tail() {
         let blame = FileBlame::new(200, 1999);
         // Insert 5 lines at position 50
-        let blame = blame.apply_line_diffs(vec![(50..50, 50..55, 2001)]);
+        let blame = blame
+            .apply_line_diffs(vec![(50..50, 50..55, 2001)])
+            .unwrap();
         assert_eq!(blame.total_lines(), 205);
         blame.validate().unwrap();
         // Now delete last 10 lines (from position 195..205 -> 195..195)
-        let blame = blame.apply_line_diffs(vec![(195..205, 195..195, 2002)]);
+        let blame = blame
+            .apply_line_diffs(vec![(195..205, 195..195, 2002)])
+            .unwrap();
         assert_eq!(blame.total_lines(), 195);
         blame.validate().unwrap();
     }
@@ -396,12 +594,15 @@ mod tests {
     fn test_apply_line_diffs_insertion_and_followup_replacements() {
         let blame = FileBlame::new(150, 2015);
         // Insert 4 lines at 20
-        let blame = blame.apply_line_diffs(vec![(20..20, 20..24, 2016)]);
+        let blame = blame
+            .apply_line_diffs(vec![(20..20, 20..24, 2016)])
+            .unwrap();
         assert_eq!(blame.total_lines(), 154);
         // Multiple replacements later in the file
         let old_total = blame.total_lines();
-        let blame =
-            blame.apply_line_diffs(vec![(100..102, 100..102, 2017), (150..151, 150..151, 2017)]);
+        let blame = blame
+            .apply_line_diffs(vec![(100..102, 100..102, 2017), (150..151, 150..151, 2017)])
+            .unwrap();
         assert_eq!(blame.total_lines(), old_total);
         blame.validate().unwrap();
     }
@@ -412,11 +613,23 @@ mod tests {
         // Hunk 1: simple replacement 90..95 -> 90..95 (delta 0)
         // Hunk 2: delete tail 95..100 -> 95..95 (delta -5)
         let diffs = vec![(90..95, 90..95, 2), (95..100, 95..95, 3)];
-        let blame = blame.apply_line_diffs(diffs);
+        let blame = blame.apply_line_diffs(diffs).unwrap();
         assert_eq!(blame.total_lines(), 95);
         blame.validate().unwrap();
     }
 
+    #[test]
+    fn test_apply_line_diffs_rejects_hunks_out_of_bounds() {
+        let blame = FileBlame::new(10, 2022);
+        assert!(blame.apply_line_diffs(vec![(8..12, 8..8, 2023)]).is_err());
+        assert!(
+            blame
+                .apply_line_diffs(vec![(2..6, 2..2, 2023), (4..5, 4..5, 2023)])
+                .is_err()
+        );
+        assert!(blame.apply_line_diffs(vec![(8..10, 8..8, 2023)]).is_ok());
+    }
+
     // Reference implementation that stores each line as an entry in a Vec.
     // So 3 lines of 2022 -> [2022, 2022, 2022]. Deleting 2 lines at pos X means
     // literally going to pos X and removing 2 items. No fancy bookkeeping.
@@ -497,10 +710,9 @@ mod tests {
 
     fn expand_file_blame<C: Keyable + Eq>(fb: &FileBlame<C>) -> Vec<C> {
         let mut out: Vec<C> = Vec::with_capacity(fb.total_lines() as usize);
-        for (start, end, cohort) in fb.ranges() {
-            let count = end - start;
-            for _ in 0..count {
-                out.push(cohort);
+        for span in fb.ranges() {
+            for _ in span.lines() {
+                out.push(span.cohort);
             }
         }
         out
@@ -544,7 +756,7 @@ mod tests {
                 batch_last_end = position + before_len; // enforce non-overlap and ascending order
 
                 if pos_seed % BATCH_AVG_LEN == 0 {
-                    fb = fb.apply_line_diffs(pending.clone());
+                    fb = fb.apply_line_diffs(pending.clone()).unwrap();
                     naive.apply_line_diffs(pending.clone());
                     pending.clear();
 
@@ -563,7 +775,7 @@ mod tests {
             }
 
             if !pending.is_empty() {
-                fb = fb.apply_line_diffs(pending.clone());
+                fb = fb.apply_line_diffs(pending.clone()).unwrap();
                 naive.apply_line_diffs(pending.clone());
             }
 
@@ -576,5 +788,32 @@ mod tests {
             prop_assert_eq!(fb.cohort_stats(), naive.cohort_stats());
             prop_assert_eq!(fb.range_count(), naive.range_count());
         }
+
+        #[test]
+        fn pbt_split_at_matches_naive(
+            initial_len in 0u32..FILE_START_LEN,
+            replacements in proptest::collection::vec((0u32..FILE_START_LEN, 0u32..8, 0u32..NUM_COHORTS), 0..20),
+            split_seed in any::<u32>(),
+        ) {
+            let mut fb: FileBlame<u32> = FileBlame::new(initial_len, 0);
+            for (position, len, cohort) in replacements {
+                let position = position.min(fb.total_lines());
+                let end = (position + len).min(fb.total_lines());
+                fb = fb.apply_line_diffs(vec![(position..end, position..end, cohort)]).unwrap();
+            }
+            let line = split_seed % (fb.total_lines() + 1);
+            let lines = expand_file_blame(&fb);
+
+            let (head, tail) = fb.split_at(line);
+            prop_assert!(head.validate().is_ok());
+            prop_assert!(tail.validate().is_ok());
+            prop_assert_eq!(expand_file_blame(&head), lines[..line as usize].to_vec());
+            prop_assert_eq!(expand_file_blame(&tail), lines[line as usize..].to_vec());
+            let mut stats = head.cohort_stats();
+            for (cohort, count) in tail.cohort_stats() {
+                *stats.entry(cohort).or_insert(0) += count;
+            }
+            prop_assert_eq!(stats, fb.cohort_stats());
+        }
     }
 }
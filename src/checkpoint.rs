@@ -0,0 +1,301 @@
+// A saved copy of the blame state at the end of an analysis, so a later run on the same repo can
+// pick up from there and only analyze the commits made since.
+// Cohort keys are written with their Display form and read back with FromStr, like every Keyable.
+// We keep the stats of every analyzed commit too, so the resumed run can redraw the whole chart.
+
+use crate::blame::{BlameSpan, FileBlame, Keyable, LineNumber};
+use crate::commit_results::CommitResults;
+use crate::error::{BoxError, Result, TheseusError};
+use crate::repo_blame_snapshot::{CommitChurn, RepositoryBlameSnapshot};
+use crate::theseus::CommitCohortInfo;
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use gix::bstr::{BString, ByteSlice};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    // The last analyzed commit
+    head: String,
+    commits: Vec<CheckpointCommit>,
+    results: Vec<Vec<(String, i64)>>,
+    files: Vec<CheckpointFile>,
+    skipped_files: Vec<String>,
+    running_cohort_stats: Vec<(String, i64)>,
+    lines_added: Vec<(String, u64)>,
+    // The (added, removed) lines of each commit, missing from older checkpoints
+    #[serde(default)]
+    churn: Vec<(u64, u64)>,
+    // Every path an analyzed file ever had, missing from older checkpoints
+    #[serde(default)]
+    paths_seen: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointCommit {
+    id: String,
+    // In RFC 3339, missing from older checkpoints
+    #[serde(default)]
+    time: Option<String>,
+    time_string: String,
+    year: u32,
+    summary: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointFile {
+    path: String,
+    total_lines: LineNumber,
+    // The (start, end, cohort) of each span of the file's blame
+    spans: Vec<(LineNumber, LineNumber, String)>,
+}
+
+// What a run picks up from
+pub struct Resumed<K: Keyable> {
+    pub head: gix::ObjectId,
+    pub commit_infos: Vec<CommitCohortInfo>,
+    // With the stats of the commits analyzed so far already in its commit results
+    pub snapshot: RepositoryBlameSnapshot<K>,
+}
+
+pub fn save<K: Keyable>(
+    path: &Path,
+    head: gix::ObjectId,
+    commit_infos: &[CommitCohortInfo],
+    snapshot: &RepositoryBlameSnapshot<K>,
+) -> Result<()> {
+    let write_error = |source: BoxError| TheseusError::CheckpointWrite {
+        path: path.to_owned(),
+        source,
+    };
+    let path_string = |file_path: &BString| -> Result<String> {
+        file_path
+            .to_str()
+            .map(str::to_string)
+            .map_err(|_| write_error(format!("{file_path} is not valid UTF-8").into()))
+    };
+    let keyed = |stats: Vec<(K, i64)>| -> Vec<(String, i64)> {
+        stats
+            .into_iter()
+            .map(|(key, lines)| (key.to_string(), lines))
+            .collect()
+    };
+    let checkpoint = Checkpoint {
+        head: head.to_string(),
+        commits: commit_infos
+            .iter()
+            .map(|info| CheckpointCommit {
+                id: info.id.to_string(),
+                time: Some(info.time.to_rfc3339()),
+                time_string: info.time_string.clone(),
+                year: info.year,
+                summary: info.summary.clone(),
+            })
+            .collect(),
+        results: snapshot
+            .commit_results
+            .iter()
+            .map(|stats| stats.map(keyed).map_err(TheseusError::ResultsSpool))
+            .collect::<Result<_>>()?,
+        files: snapshot
+            .file_blames
+            .iter()
+            .map(|(file_path, blame)| {
+                Ok(CheckpointFile {
+                    path: path_string(file_path)?,
+                    total_lines: blame.total_lines(),
+                    spans: blame
+                        .ranges()
+                        .map(|span| (span.start, span.end, span.cohort.to_string()))
+                        .collect(),
+                })
+            })
+            .collect::<Result<_>>()?,
+        skipped_files: snapshot
+            .skipped_files
+            .iter()
+            .map(path_string)
+            .collect::<Result<_>>()?,
+        running_cohort_stats: keyed(snapshot.repository_cohort_stats()),
+        lines_added: snapshot
+            .lines_added
+            .iter()
+            .map(|(key, lines)| (key.to_string(), *lines))
+            .collect(),
+        churn: snapshot
+            .churn
+            .iter()
+            .map(|churn| (churn.added, churn.removed))
+            .collect(),
+        paths_seen: snapshot
+            .paths_seen
+            .iter()
+            .map(path_string)
+            .collect::<Result<_>>()?,
+    };
+
+    // Written next to the old one then moved over it, so a failed run doesn't lose the old checkpoint
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(dir).map_err(|e| write_error(e.into()))?;
+    let mut writer = BufWriter::new(file.as_file_mut());
+    serde_json::to_writer(&mut writer, &checkpoint).map_err(|e| write_error(e.into()))?;
+    writer.flush().map_err(|e| write_error(e.into()))?;
+    drop(writer);
+    file.persist(path).map_err(|e| write_error(e.into()))?;
+    Ok(())
+}
+
+// Loads a checkpoint, putting the stats of the commits it covers in `commit_results`
+pub fn load<K: Keyable>(path: &Path, mut commit_results: CommitResults<K>) -> Result<Resumed<K>> {
+    let read_error = |source: BoxError| TheseusError::CheckpointRead {
+        path: path.to_owned(),
+        source,
+    };
+    let parse_key = |key: &str| -> Result<K> {
+        key.parse()
+            .map_err(|_| read_error(format!("invalid cohort key {key}").into()))
+    };
+    let parse_id = |id: &str| -> Result<gix::ObjectId> {
+        gix::ObjectId::from_hex(id.as_bytes()).map_err(|e| read_error(e.into()))
+    };
+    // Older checkpoints only have the local time_string, which is taken to be in UTC
+    let commit_time = |time: Option<&str>, time_string: &str| -> Result<DateTime<FixedOffset>> {
+        match time {
+            Some(time) => DateTime::parse_from_rfc3339(time).map_err(|e| read_error(e.into())),
+            None => NaiveDateTime::parse_from_str(time_string, "%Y-%m-%d %H:%M:%S")
+                .map(|time| time.and_utc().fixed_offset())
+                .map_err(|e| read_error(e.into())),
+        }
+    };
+
+    let file = File::open(path).map_err(|e| read_error(e.into()))?;
+    let checkpoint: Checkpoint =
+        serde_json::from_reader(BufReader::new(file)).map_err(|e| read_error(e.into()))?;
+
+    for stats in checkpoint.results {
+        let stats = stats
+            .into_iter()
+            .map(|(key, lines)| Ok((parse_key(&key)?, lines)))
+            .collect::<Result<_>>()?;
+        commit_results
+            .push(stats)
+            .map_err(TheseusError::ResultsSpool)?;
+    }
+    let head = parse_id(&checkpoint.head)?;
+    let mut snapshot = RepositoryBlameSnapshot::with_commit_results(head, commit_results);
+    for file in checkpoint.files {
+        let spans = file
+            .spans
+            .iter()
+            .map(|(start, end, cohort)| Ok(BlameSpan::new(*start, *end, parse_key(cohort)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let blame = FileBlame::from_spans(file.total_lines, spans);
+        blame
+            .validate()
+            .map_err(|e| read_error(format!("bad blame for {}: {e}", file.path).into()))?;
+        snapshot.file_blames.insert(file.path.into(), blame);
+    }
+    snapshot.skipped_files = checkpoint
+        .skipped_files
+        .into_iter()
+        .map(BString::from)
+        .collect();
+    for (key, lines) in checkpoint.running_cohort_stats {
+        snapshot
+            .running_cohort_stats
+            .insert(parse_key(&key)?, lines);
+    }
+    for (key, lines) in checkpoint.lines_added {
+        snapshot.lines_added.insert(parse_key(&key)?, lines);
+    }
+    snapshot.churn = checkpoint
+        .churn
+        .into_iter()
+        .map(|(added, removed)| CommitChurn { added, removed })
+        .collect();
+    // An older checkpoint only tells us about the files it has
+    snapshot.paths_seen = if checkpoint.paths_seen.is_empty() {
+        snapshot.file_blames.keys().cloned().collect()
+    } else {
+        checkpoint
+            .paths_seen
+            .into_iter()
+            .map(BString::from)
+            .collect()
+    };
+    let commit_infos = checkpoint
+        .commits
+        .into_iter()
+        .map(|commit| {
+            Ok(CommitCohortInfo {
+                id: parse_id(&commit.id)?,
+                time: commit_time(commit.time.as_deref(), &commit.time_string)?,
+                time_string: commit.time_string,
+                year: commit.year,
+                summary: commit.summary,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Resumed {
+        head,
+        commit_infos,
+        snapshot,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_save_then_load_round_trips() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("checkpoint.json");
+        let head = gix::ObjectId::from_hex(b"0123456789abcdef0123456789abcdef01234567").unwrap();
+        let mut snapshot = RepositoryBlameSnapshot::<usize>::new(head);
+        snapshot.add_file(&BString::from("a.rs"), 10, 0);
+        snapshot
+            .modify_file(&BString::from("a.rs"), vec![(2..4, 2..5, 1)])
+            .unwrap();
+        snapshot.skipped_files.insert(BString::from("tiny.rs"));
+        snapshot
+            .commit_results
+            .push(snapshot.repository_cohort_stats())
+            .unwrap();
+        let commit_infos = vec![CommitCohortInfo {
+            id: head,
+            time: DateTime::parse_from_rfc3339("2020-01-01T00:00:00+01:00").unwrap(),
+            time_string: "2020-01-01 00:00:00".to_string(),
+            year: 2020,
+            summary: Some("Initial commit".to_string()),
+        }];
+
+        save(&path, head, &commit_infos, &snapshot).unwrap();
+        let resumed = load::<usize>(&path, CommitResults::in_memory()).unwrap();
+        assert_eq!(resumed.head, head);
+        assert_eq!(resumed.commit_infos[0].summary, commit_infos[0].summary);
+        assert_eq!(resumed.commit_infos[0].time, commit_infos[0].time);
+        let blame = &resumed.snapshot.file_blames[&BString::from("a.rs")];
+        assert_eq!(
+            blame.ranges().collect::<Vec<_>>(),
+            snapshot.file_blames[&BString::from("a.rs")]
+                .ranges()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(blame.cohort_stats(), HashMap::from([(0, 8), (1, 3)]));
+        assert!(
+            resumed
+                .snapshot
+                .skipped_files
+                .contains(&BString::from("tiny.rs"))
+        );
+        assert_eq!(resumed.snapshot.lines_added, snapshot.lines_added);
+        assert_eq!(
+            resumed.snapshot.commit_results.last().unwrap(),
+            snapshot.commit_results.last().unwrap()
+        );
+    }
+}
@@ -0,0 +1,107 @@
+// How many lines each analyzed commit added and removed, whether they survived or not.
+// Written to churn.json next to cohorts.json.
+
+use crate::repo_blame_snapshot::CommitChurn;
+use crate::theseus::{CommitCohortInfo, TheseusResult};
+use serde::{Deserialize, Serialize};
+
+// Commits that add and remove at least this many lines are reported, since they're usually a
+// vendored dump or regenerated code that swamps its cohort
+pub const DEFAULT_LARGE_COMMIT_LINES: u64 = 100_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChurnData {
+    pub ts: Vec<String>,
+    pub added: Vec<u64>,
+    pub removed: Vec<u64>,
+}
+
+pub fn churn_data(result: &TheseusResult) -> ChurnData {
+    let ts = result
+        .analyzed_commits()
+        .iter()
+        .map(|info| info.time_string.clone())
+        .collect();
+    ChurnData {
+        ts,
+        added: result.churn.iter().map(|churn| churn.added).collect(),
+        removed: result.churn.iter().map(|churn| churn.removed).collect(),
+    }
+}
+
+// An analyzed commit with a suspiciously large diff, to decide whether to leave it out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LargeCommit {
+    pub commit: String,
+    pub date: String,
+    pub added: u64,
+    pub removed: u64,
+}
+
+// The commits whose churn, added plus removed lines, is at least `threshold`, in order
+pub fn large_commits(
+    commits: &[CommitCohortInfo],
+    churn: &[CommitChurn],
+    threshold: u64,
+) -> Vec<LargeCommit> {
+    commits
+        .iter()
+        .zip(churn)
+        .filter(|(_, churn)| churn.added + churn.removed >= threshold)
+        .map(|(info, churn)| LargeCommit {
+            commit: info.id.to_string(),
+            date: info.time_string.clone(),
+            added: churn.added,
+            removed: churn.removed,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{AnalysisOptions, Verbosity};
+    use crate::test_utils::TestRepo;
+    use crate::theseus::run_theseus;
+
+    #[test]
+    fn test_churn_counts_the_lines_of_each_commit() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .write("old.rs", "x\ny\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.git(&["rm", "--quiet", "old.rs"]);
+        repo.write("main.rs", "a\nc\nd\ne\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let options = AnalysisOptions {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+
+        let churn = churn_data(&run_theseus(repo.path(), &options).unwrap());
+        assert_eq!(churn.ts.len(), 2);
+        assert_eq!(churn.added, vec![5, 2]);
+        assert_eq!(churn.removed, vec![0, 3]);
+    }
+
+    #[test]
+    fn test_large_commits_are_reported() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("vendor.rs", "a\nb\nc\nd\ne\nf\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let head = repo.git(&["rev-parse", "HEAD"]);
+        let options = AnalysisOptions::default()
+            .verbosity(Verbosity::Quiet)
+            .large_commit_lines(5);
+
+        let large_commits = run_theseus(repo.path(), &options)
+            .unwrap()
+            .stats
+            .large_commits;
+        assert_eq!(large_commits.len(), 1);
+        assert_eq!(large_commits[0].commit, head.trim());
+        assert_eq!(large_commits[0].added, 6);
+    }
+}
@@ -0,0 +1,84 @@
+// Clones a remote repo to a temp dir, for one-off analyses of repos that aren't checked out locally.
+// The clone is bare, since we only read objects, and full, since a shallow clone can't be diffed
+// back to its first commit.
+
+use crate::error::{Result, TheseusError};
+use tempfile::TempDir;
+
+// A clone that is deleted when it is dropped, which also happens when the analysis fails
+pub struct TempClone {
+    _dir: TempDir,
+    path: String,
+}
+
+impl TempClone {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+pub fn clone_to_temp_dir(url: &str) -> Result<TempClone> {
+    let clone_failed = |source| TheseusError::CloneFailed {
+        url: url.to_string(),
+        source,
+    };
+    let dir = tempfile::Builder::new()
+        .prefix("gix-of-theseus-")
+        .tempdir()
+        .map_err(|e| clone_failed(e.into()))?;
+    gix::prepare_clone_bare(url, dir.path())
+        .map_err(|e| clone_failed(e.into()))?
+        .fetch_only(gix::progress::Discard, &gix::interrupt::IS_INTERRUPTED)
+        .map_err(|e| clone_failed(e.into()))?;
+    let path = dir.path().to_string_lossy().into_owned();
+    Ok(TempClone { _dir: dir, path })
+}
+
+// The name of the repo a URL points to, e.g. "linux" for "https://github.com/torvalds/linux.git"
+// or "git@github.com:torvalds/linux"
+pub fn repo_name_from_url(url: &str) -> String {
+    let url = url.trim_end_matches('/');
+    let name = url.rsplit(['/', ':']).next().unwrap_or(url);
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    if name.is_empty() {
+        "repo".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::AnalysisOptions;
+    use crate::test_utils::TestRepo;
+    use crate::theseus::run_theseus;
+
+    #[test]
+    fn test_repo_name_from_url() {
+        assert_eq!(
+            repo_name_from_url("https://github.com/torvalds/linux.git"),
+            "linux"
+        );
+        assert_eq!(repo_name_from_url("git@github.com:torvalds/linux"), "linux");
+        assert_eq!(repo_name_from_url("https://example.com/linux/"), "linux");
+        assert_eq!(repo_name_from_url(".git"), "repo");
+    }
+
+    #[test]
+    fn test_clone_is_analyzed_and_removed_on_drop() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let clone = clone_to_temp_dir(repo.path()).unwrap();
+        let result = run_theseus(clone.path(), &AnalysisOptions::default()).unwrap();
+        assert_eq!(result.stats.lines_at_head, 3);
+
+        let clone_path = clone.path().to_string();
+        drop(clone);
+        assert!(!std::path::Path::new(&clone_path).exists());
+    }
+}
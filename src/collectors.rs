@@ -0,0 +1,4 @@
+// Front-ends that feed the blame engine from something other than a git history.
+// Each one turns its source into the same Action stream run_theseus sends to the BlameProcessor.
+
+pub mod tarball;
@@ -0,0 +1,342 @@
+// Builds a history out of source archives, one per release, for when the git history isn't available.
+// Each release counts as a commit made on its date: we extract the archives in date order and diff
+// the files of each release against the previous one's.
+// There is no rename detection, a moved file counts as deleted and added again.
+
+use crate::actions::Action;
+use crate::churn::{DEFAULT_LARGE_COMMIT_LINES, large_commits};
+use crate::error::{Result, TheseusError};
+use crate::file_types::PathFilter;
+use crate::gix_helpers::{diff_bytes, measure_size};
+use crate::options::AnalysisOptions;
+use crate::profile::PhaseTimings;
+use crate::repo_blame_snapshot::RepositoryBlameSnapshot;
+use crate::theseus::{
+    CohortKey, CommitCohortInfo, CommitContext, RunStats, TheseusResult, file_groups,
+    handle_file_deletion, new_commit_results, send_action, send_file_addition, start_processor,
+};
+use chrono::{Datelike, NaiveDate, NaiveTime};
+use gix::bstr::{BString, ByteSlice};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::AtomicUsize;
+use tempfile::TempDir;
+
+// A source archive and the date of the release it holds
+#[derive(Debug, Clone)]
+pub struct Release {
+    pub date: NaiveDate,
+    pub archive: PathBuf,
+}
+
+// Parses the DATE=ARCHIVE form used on the command line, e.g. "2019-06-01=foo-1.0.tar.gz"
+impl std::str::FromStr for Release {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (date, archive) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected DATE=ARCHIVE, got '{s}'"))?;
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|e| format!("invalid date '{date}': {e}"))?;
+        Ok(Release {
+            date,
+            archive: archive.into(),
+        })
+    }
+}
+
+// Like run_theseus, but the snapshots are the releases instead of weekly commits.
+// The options that are about the git history (sampling, merges, the worktree, the cache) don't apply.
+pub fn run_tarballs(releases: &[Release], options: &AnalysisOptions) -> Result<TheseusResult> {
+    let mut releases = releases.to_vec();
+    releases.sort_by_key(|release| release.date);
+    if releases.is_empty() {
+        return Err(TheseusError::EmptyRepo);
+    }
+    // Releases aren't commits, so they have no id
+    let null_id = gix::ObjectId::null(gix::hash::Kind::Sha1);
+    let commit_infos: Vec<CommitCohortInfo> = releases
+        .iter()
+        .map(|release| CommitCohortInfo {
+            id: null_id,
+            time: release
+                .date
+                .and_time(NaiveTime::MIN)
+                .and_utc()
+                .fixed_offset(),
+            time_string: release.date.format("%Y-%m-%d 00:00:00").to_string(),
+            year: release.date.year() as u32,
+            summary: release
+                .archive
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+        })
+        .collect();
+    let mut snapshot =
+        RepositoryBlameSnapshot::with_commit_results(null_id, new_commit_results(options)?);
+    snapshot.revive_deleted = options.revive_deleted;
+    snapshot.final_only = options.final_only;
+    if options.ever_written {
+        snapshot.ever_written = Some(new_commit_results(options)?);
+    }
+    snapshot.groups = file_groups(options);
+    let processor = start_processor(snapshot, options, None)?;
+    let sender = processor.sender();
+    let path_filter = PathFilter::from_options(options)?;
+    let files_skipped = AtomicUsize::new(0);
+    let small_files_skipped = AtomicUsize::new(0);
+
+    let accumulate = || -> Result<()> {
+        let mut previous: Option<ExtractedRelease> = None;
+        for (release_idx, release) in releases.iter().enumerate() {
+            if options.verbosity.is_verbose() {
+                eprintln!("Extracting {}", release.archive.display());
+            }
+            let current = ExtractedRelease::extract(&release.archive)?;
+            let ctx = CommitContext {
+                sender: &sender,
+                options,
+                cohort: release_idx,
+                files_skipped: &files_skipped,
+                small_files_skipped: &small_files_skipped,
+                path_filter: &path_filter,
+            };
+            diff_releases(&ctx, previous.as_ref(), &current)?;
+            send_action(&sender, Action::FinishCommit)?;
+            previous = Some(current);
+        }
+        Ok(())
+    };
+    let accumulated = accumulate();
+    drop(sender);
+    // If the processor failed, the producers only saw a closed channel: report the processor's error.
+    let snapshot = processor.finish()?;
+    accumulated?;
+    let stats = RunStats {
+        files_at_head: snapshot.file_count(),
+        lines_at_head: snapshot.total_lines(),
+        files_skipped: files_skipped.into_inner(),
+        small_files_skipped: small_files_skipped.into_inner(),
+        // Tarballs have no history to follow renames through
+        renames_detected: 0,
+        copies_detected: 0,
+        files_ever_seen: snapshot.paths_seen.len(),
+        baseline_commits: 0,
+        large_commits: large_commits(
+            &commit_infos,
+            &snapshot.churn,
+            options
+                .large_commit_lines
+                .unwrap_or(DEFAULT_LARGE_COMMIT_LINES),
+        ),
+        phases: PhaseTimings::default(),
+    };
+
+    Ok(TheseusResult {
+        commit_cohort_info: commit_infos,
+        cohort_data: snapshot.commit_results,
+        lines_added: snapshot.lines_added.into_iter().collect(),
+        churn: snapshot.churn,
+        ever_written: snapshot.ever_written,
+        by_extension: snapshot.groups.by_extension.map(|stats| stats.results),
+        by_directory: snapshot.groups.by_directory.map(|stats| stats.results),
+        failures: Vec::new(),
+        stats,
+    })
+}
+
+// Sends the actions that turn the previous release into the current one
+fn diff_releases(
+    ctx: &CommitContext<CohortKey>,
+    previous: Option<&ExtractedRelease>,
+    current: &ExtractedRelease,
+) -> Result<()> {
+    let no_files = HashMap::new();
+    let previous_files = previous.map_or(&no_files, |release| &release.files);
+    previous_files
+        .par_iter()
+        .filter(|(path, _)| {
+            !current.files.contains_key(*path) && ctx.path_filter.is_analyzed(path.as_bstr())
+        })
+        .try_for_each(|(path, full_path)| {
+            let data = read_file(full_path)?;
+            handle_file_deletion(ctx, path.clone(), blob_id(&data)?)
+        })?;
+    current
+        .files
+        .par_iter()
+        .try_for_each(|(path, full_path)| -> Result<()> {
+            let previous_path = previous_files.get(path);
+            let analyzed = match previous_path {
+                Some(_) => ctx.path_filter.is_analyzed(path.as_bstr()),
+                None => ctx.is_addition_analyzed(path.as_bstr()),
+            };
+            if !analyzed {
+                return Ok(());
+            }
+            let new = read_file(full_path)?;
+            let Some(previous_path) = previous_path else {
+                return send_file_addition(
+                    ctx,
+                    path,
+                    measure_size(&new, ctx.options),
+                    Some(blob_id(&new)?),
+                );
+            };
+            let old = read_file(previous_path)?;
+            if old == new {
+                return Ok(());
+            }
+            let line_diffs = diff_bytes(&old, &new, ctx.cohort, ctx.options);
+            send_action(
+                ctx.sender,
+                Action::ModifyFile {
+                    path: path.clone(),
+                    line_diffs,
+                },
+            )
+        })
+}
+
+// The files of a release, extracted to a temp dir that lives as long as this does
+struct ExtractedRelease {
+    _dir: TempDir,
+    // The full path of each file, by its path within the release
+    files: HashMap<BString, PathBuf>,
+}
+
+impl ExtractedRelease {
+    fn extract(archive: &Path) -> Result<Self> {
+        let read_error = |source: std::io::Error| TheseusError::ArchiveRead {
+            path: archive.to_owned(),
+            source,
+        };
+        let dir = TempDir::new().map_err(read_error)?;
+        let is_zip = archive
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+        let mut command = if is_zip {
+            let mut command = Command::new("unzip");
+            command.arg("-q").arg(archive).arg("-d").arg(dir.path());
+            command
+        } else {
+            // tar works out the compression by itself
+            let mut command = Command::new("tar");
+            command.arg("-xf").arg(archive).arg("-C").arg(dir.path());
+            command
+        };
+        let status = command.stdout(Stdio::null()).status().map_err(read_error)?;
+        if !status.success() {
+            return Err(read_error(std::io::Error::other(format!(
+                "extracting it failed with {status}"
+            ))));
+        }
+        let root = release_root(dir.path()).map_err(read_error)?;
+        let mut files = HashMap::new();
+        collect_files(&root, &root, &mut files).map_err(read_error)?;
+        Ok(Self { _dir: dir, files })
+    }
+}
+
+// Release archives usually hold a single top-level directory like foo-1.0/, whose name changes
+// with every release. We look inside it so the paths of the files stay the same across releases.
+fn release_root(dir: &Path) -> std::io::Result<PathBuf> {
+    let entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    match entries.as_slice() {
+        [entry] if entry.file_type()?.is_dir() => Ok(entry.path()),
+        _ => Ok(dir.to_owned()),
+    }
+}
+
+// Only regular files are analyzed, like only blobs are in a git tree
+fn collect_files(
+    root: &Path,
+    dir: &Path,
+    files: &mut HashMap<BString, PathBuf>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            collect_files(root, &path, files)?;
+        } else if file_type.is_file() {
+            let relative = path.strip_prefix(root).expect("walked outside of the root");
+            let key = gix::path::to_unix_separators_on_windows(gix::path::into_bstr(relative))
+                .into_owned();
+            files.insert(key, path);
+        }
+    }
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|source| TheseusError::ArchiveRead {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+// The id git would give this content, so --revive-deleted recognizes a file that comes back unchanged
+fn blob_id(data: &[u8]) -> Result<gix::ObjectId> {
+    gix::objs::compute_hash(gix::hash::Kind::Sha1, gix::objs::Kind::Blob, data)
+        .map_err(TheseusError::odb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Verbosity;
+
+    // Makes a foo.tar.gz archive with a single foo/ directory holding the files
+    fn make_release(dir: &Path, name: &str, files: &[(&str, &str)]) -> PathBuf {
+        for (path, contents) in files {
+            let full_path = dir.join(name).join(path);
+            std::fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+            std::fs::write(full_path, contents).unwrap();
+        }
+        let archive = dir.join(format!("{name}.tar.gz"));
+        let status = Command::new("tar")
+            .arg("-czf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(dir)
+            .arg(name)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        archive
+    }
+
+    #[test]
+    fn test_releases_are_diffed_in_date_order() {
+        let dir = TempDir::new().unwrap();
+        let v1 = make_release(
+            dir.path(),
+            "foo-1.0",
+            &[("src/main.rs", "a\nb\nc\n"), ("src/old.rs", "x\n")],
+        );
+        let v2 = make_release(dir.path(), "foo-2.0", &[("src/main.rs", "a\nb\nc\nd\n")]);
+        let releases: Vec<Release> = [
+            format!("2021-03-01={}", v2.display()),
+            format!("2019-03-01={}", v1.display()),
+        ]
+        .iter()
+        .map(|arg| arg.parse().unwrap())
+        .collect();
+        let options = AnalysisOptions {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+
+        let result = run_tarballs(&releases, &options).unwrap();
+        assert_eq!(result.commit_cohort_info[0].year, 2019);
+        let mut last_snapshot = result.cohort_data.last().unwrap().unwrap();
+        last_snapshot.sort();
+        assert_eq!(last_snapshot, vec![(0, 3), (1, 1)]);
+        assert_eq!(result.stats.files_at_head, 1);
+    }
+}
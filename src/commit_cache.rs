@@ -0,0 +1,129 @@
+// An on-disk cache of the commits selected for analysis, so that analyzing the same repo
+// again doesn't re-walk its whole history. Entries are keyed by the HEAD they were walked from,
+// so a new commit on HEAD simply misses the cache, and by the day the weekly buckets start on.
+
+use chrono::{DateTime, FixedOffset, Weekday};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+// A commit selected for analysis, with what we need to know about it to diff its tree
+#[derive(Debug, Clone)]
+pub struct SelectedCommit {
+    pub id: gix::ObjectId,
+    pub tree_id: gix::ObjectId,
+    // The commit time in the time zone it was made in, which time_string and year are made from
+    pub time: DateTime<FixedOffset>,
+    pub time_string: String,
+    pub year: u32,
+    // With skip_merges, the time of the newest branch tip merged in since the commit analyzed
+    // before this one. It depends on which commits end up analyzed, so it isn't cached.
+    pub merged_branch_time: Option<DateTime<FixedOffset>>,
+    // The first line of the commit message
+    pub summary: String,
+}
+
+// What actually gets written to disk, with the ids as hex strings.
+// The fields have no serde default, so caches written before one was added are treated as misses.
+#[derive(Serialize, Deserialize)]
+struct CachedCommit {
+    id: String,
+    tree_id: String,
+    // In RFC 3339, with the offset of the commit's time zone
+    time: String,
+    time_string: String,
+    year: u32,
+    summary: String,
+}
+
+// Sunday weeks keep the name they had before the week start could be changed
+fn cache_file(cache_dir: &Path, head_id: gix::ObjectId, week_start: Weekday) -> PathBuf {
+    match week_start {
+        Weekday::Sun => cache_dir.join(format!("{head_id}.weekly.json")),
+        _ => cache_dir.join(format!(
+            "{head_id}.weekly-{}.json",
+            week_start.to_string().to_lowercase()
+        )),
+    }
+}
+
+// Returns None on a cache miss, including when the cache file is unreadable or corrupt
+pub fn load(
+    cache_dir: &Path,
+    head_id: gix::ObjectId,
+    week_start: Weekday,
+) -> Option<Vec<SelectedCommit>> {
+    let file = File::open(cache_file(cache_dir, head_id, week_start)).ok()?;
+    let cached: Vec<CachedCommit> = serde_json::from_reader(BufReader::new(file)).ok()?;
+    cached
+        .into_iter()
+        .map(|commit| {
+            Some(SelectedCommit {
+                id: gix::ObjectId::from_hex(commit.id.as_bytes()).ok()?,
+                tree_id: gix::ObjectId::from_hex(commit.tree_id.as_bytes()).ok()?,
+                time: DateTime::parse_from_rfc3339(&commit.time).ok()?,
+                time_string: commit.time_string,
+                year: commit.year,
+                merged_branch_time: None,
+                summary: commit.summary,
+            })
+        })
+        .collect()
+}
+
+pub fn store(
+    cache_dir: &Path,
+    head_id: gix::ObjectId,
+    week_start: Weekday,
+    commits: &[SelectedCommit],
+) -> std::io::Result<()> {
+    fs::create_dir_all(cache_dir)?;
+    let cached: Vec<CachedCommit> = commits
+        .iter()
+        .map(|commit| CachedCommit {
+            id: commit.id.to_string(),
+            tree_id: commit.tree_id.to_string(),
+            time: commit.time.to_rfc3339(),
+            time_string: commit.time_string.clone(),
+            year: commit.year,
+            summary: commit.summary.clone(),
+        })
+        .collect();
+    let file = File::create(cache_file(cache_dir, head_id, week_start))?;
+    serde_json::to_writer(BufWriter::new(file), &cached)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_then_load_round_trips() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let head_id = gix::ObjectId::from_hex(b"0123456789abcdef0123456789abcdef01234567").unwrap();
+        let commits = vec![SelectedCommit {
+            id: head_id,
+            tree_id: gix::ObjectId::empty_tree(gix::hash::Kind::Sha1),
+            time: DateTime::parse_from_rfc3339("2020-01-01T00:00:00-05:00").unwrap(),
+            time_string: "2020-01-01 00:00:00".to_string(),
+            year: 2020,
+            merged_branch_time: None,
+            summary: "Fix the build".to_string(),
+        }];
+        assert!(load(dir.path(), head_id, Weekday::Sun).is_none());
+        store(dir.path(), head_id, Weekday::Sun, &commits).unwrap();
+        // Weeks starting on another day are bucketed differently
+        assert!(load(dir.path(), head_id, Weekday::Mon).is_none());
+
+        let loaded = load(dir.path(), head_id, Weekday::Sun).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].id, head_id);
+        assert_eq!(loaded[0].tree_id, commits[0].tree_id);
+        assert_eq!(loaded[0].time, commits[0].time);
+        assert_eq!(loaded[0].time_string, commits[0].time_string);
+        assert_eq!(loaded[0].year, 2020);
+        assert_eq!(loaded[0].summary, commits[0].summary);
+    }
+}
@@ -0,0 +1,172 @@
+// The cohort stats of the repo after each analyzed commit, in commit order.
+// By default they're kept in memory. On very long histories they can instead be spooled to a
+// temp file, one JSON line per commit, so the run's memory doesn't grow with the history.
+// Cohort keys are written with Display and read back with FromStr.
+
+use crate::blame::Keyable;
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use tempfile::NamedTempFile;
+
+#[derive(Debug)]
+pub enum CommitResults<K: Keyable> {
+    InMemory(Vec<Vec<(K, i64)>>),
+    Spooled {
+        writer: BufWriter<NamedTempFile>,
+        // Where the line of each commit starts in the file, so one can be read without the others
+        offsets: Vec<u64>,
+        end: u64,
+    },
+}
+
+impl<K: Keyable> CommitResults<K> {
+    pub fn in_memory() -> Self {
+        Self::InMemory(Vec::new())
+    }
+
+    pub fn spooled() -> io::Result<Self> {
+        Ok(Self::Spooled {
+            writer: BufWriter::new(NamedTempFile::new()?),
+            offsets: Vec::new(),
+            end: 0,
+        })
+    }
+
+    pub fn push(&mut self, stats: Vec<(K, i64)>) -> io::Result<()> {
+        match self {
+            Self::InMemory(results) => results.push(stats),
+            Self::Spooled {
+                writer,
+                offsets,
+                end,
+            } => {
+                let stats: Vec<(String, i64)> = stats
+                    .into_iter()
+                    .map(|(key, lines)| (key.to_string(), lines))
+                    .collect();
+                let mut line = serde_json::to_vec(&stats)?;
+                line.push(b'\n');
+                writer.write_all(&line)?;
+                offsets.push(*end);
+                *end += line.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    // Spooled results are only visible to readers once flushed
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::InMemory(_) => Ok(()),
+            Self::Spooled { writer, .. } => writer.flush(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::InMemory(results) => results.len(),
+            Self::Spooled { offsets, .. } => offsets.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Iterates over the stats of each commit. Spooled results are read back from the temp file,
+    // one commit at a time, and can fail like any read, e.g. if the temp dir was cleaned up.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = io::Result<Vec<(K, i64)>>> + '_> {
+        match self {
+            Self::InMemory(results) => Box::new(results.iter().cloned().map(Ok)),
+            Self::Spooled { writer, .. } => match writer.get_ref().reopen() {
+                Ok(file) => Box::new(BufReader::new(file).lines().map(|line| parse_line(&line?))),
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            },
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> io::Result<Option<Vec<(K, i64)>>> {
+        match self {
+            Self::InMemory(results) => Ok(results.get(idx).cloned()),
+            Self::Spooled {
+                writer, offsets, ..
+            } => {
+                let Some(offset) = offsets.get(idx) else {
+                    return Ok(None);
+                };
+                let mut file = writer.get_ref().reopen()?;
+                file.seek(SeekFrom::Start(*offset))?;
+                let mut line = String::new();
+                BufReader::new(file).read_line(&mut line)?;
+                parse_line(&line).map(Some)
+            }
+        }
+    }
+
+    pub fn last(&self) -> io::Result<Option<Vec<(K, i64)>>> {
+        match self.len() {
+            0 => Ok(None),
+            len => self.get(len - 1),
+        }
+    }
+}
+
+fn parse_line<K: Keyable>(line: &str) -> io::Result<Vec<(K, i64)>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let stats: Vec<(String, i64)> = serde_json::from_str(line)
+        .map_err(|e| invalid(format!("corrupt line in the spooled commit results: {e}")))?;
+    stats
+        .into_iter()
+        .map(|(key, lines)| match key.parse() {
+            Ok(key) => Ok((key, lines)),
+            Err(_) => Err(invalid(format!(
+                "invalid cohort key in the spooled commit results: {key}"
+            ))),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spooled_results_read_back_like_in_memory_ones() {
+        let commits = vec![vec![(0usize, 3i64)], vec![(0, 2), (1, 5)], vec![]];
+        let mut in_memory = CommitResults::in_memory();
+        let mut spooled = CommitResults::spooled().unwrap();
+        for stats in &commits {
+            in_memory.push(stats.clone()).unwrap();
+            spooled.push(stats.clone()).unwrap();
+        }
+        spooled.flush().unwrap();
+
+        assert_eq!(spooled.len(), 3);
+        assert_eq!(
+            in_memory.iter().collect::<io::Result<Vec<_>>>().unwrap(),
+            commits
+        );
+        assert_eq!(
+            spooled.iter().collect::<io::Result<Vec<_>>>().unwrap(),
+            commits
+        );
+        assert_eq!(spooled.get(1).unwrap(), Some(commits[1].clone()));
+        assert_eq!(spooled.get(3).unwrap(), None);
+        assert_eq!(spooled.last().unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn test_truncated_spool_is_an_error() {
+        let mut spooled = CommitResults::<usize>::spooled().unwrap();
+        spooled.push(vec![(0, 3)]).unwrap();
+        spooled.push(vec![(0, 2), (1, 5)]).unwrap();
+        spooled.flush().unwrap();
+        let CommitResults::Spooled { writer, .. } = &spooled else {
+            unreachable!()
+        };
+        // Cuts the second commit's line short
+        writer.get_ref().as_file().set_len(12).unwrap();
+
+        assert!(spooled.iter().any(|stats| stats.is_err()));
+        assert!(spooled.last().is_err());
+    }
+}
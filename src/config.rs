@@ -0,0 +1,127 @@
+// A project's own defaults for the analyze subcommands, read from a gix-of-theseus.toml at the root
+// of the repo, so everyone working on it gets the same chart without remembering the flags:
+//
+// [analysis]
+// sample = 2
+// include = ["src/**"]
+// first_commit_cohort = "Pre-history"
+//
+// [plot]
+// theme = "dark"
+//
+// The keys of [analysis] are the names of the flags with underscores, and [plot] takes the same
+// fields as a --theme-config file. Flags given on the command line override the file.
+
+use crate::plot::PlotStyle;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const PROJECT_CONFIG_FILE: &str = "gix-of-theseus.toml";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConfig {
+    pub analysis: AnalysisConfig,
+    pub plot: PlotStyle,
+}
+
+// The flags that make sense to pin for a project: the granularity, the filters and how the cohorts
+// are made and labelled. The values that are enums are kept as strings and parsed like the flags.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalysisConfig {
+    pub all_filetypes: Option<bool>,
+    pub include: Option<Vec<String>>,
+    pub only_extensions: Option<Vec<String>>,
+    pub only_languages: Option<Vec<String>>,
+    pub filetypes: Option<PathBuf>,
+    pub ignore_eol: Option<bool>,
+    pub measure: Option<String>,
+    pub detect_copies: Option<bool>,
+    pub rename_limit: Option<usize>,
+    pub sample: Option<usize>,
+    pub max_commits: Option<usize>,
+    pub week_start: Option<String>,
+    pub first_commit_cohort: Option<String>,
+    pub skip_merges: Option<bool>,
+    pub min_lines: Option<u32>,
+    pub label_template: Option<String>,
+    pub labels_from: Option<PathBuf>,
+    pub sort_labels: Option<String>,
+    pub cohort_granularity: Option<String>,
+    pub top_n: Option<usize>,
+}
+
+impl ProjectConfig {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut config: Self = toml::from_str(&text)
+            .with_context(|| format!("Failed to read the config from {}", path.display()))?;
+        // The paths in the file are relative to it, not to where the tool is run from
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for file in [
+            &mut config.analysis.filetypes,
+            &mut config.analysis.labels_from,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *file = dir.join(&*file);
+        }
+        Ok(config)
+    }
+
+    // The gix-of-theseus.toml at the root of the repo, if there's one
+    pub fn find_in_repo(repo_path: &Path) -> Result<Option<Self>> {
+        let path = repo_path.join(PROJECT_CONFIG_FILE);
+        if path.is_file() {
+            Self::from_file(&path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plot::Theme;
+
+    #[test]
+    fn test_project_config_reads_a_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(ProjectConfig::find_in_repo(dir.path()).unwrap(), None);
+
+        let path = dir.path().join(PROJECT_CONFIG_FILE);
+        fs::write(
+            &path,
+            r#"
+[analysis]
+sample = 2
+include = ["src/**"]
+week_start = "monday"
+labels_from = "labels.json"
+
+[plot]
+theme = "dark"
+"#,
+        )
+        .unwrap();
+        let config = ProjectConfig::find_in_repo(dir.path()).unwrap().unwrap();
+        assert_eq!(config.analysis.sample, Some(2));
+        assert_eq!(config.analysis.include, Some(vec!["src/**".to_string()]));
+        assert_eq!(config.analysis.week_start.as_deref(), Some("monday"));
+        assert_eq!(
+            config.analysis.labels_from,
+            Some(dir.path().join("labels.json"))
+        );
+        assert_eq!(config.analysis.top_n, None);
+        assert_eq!(config.plot.theme, Some(Theme::Dark));
+
+        fs::write(&path, "[analysis]\nsampel = 2\n").unwrap();
+        assert!(ProjectConfig::from_file(&path).is_err());
+    }
+}
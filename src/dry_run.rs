@@ -0,0 +1,158 @@
+// A quick look at what an analysis would cover, without running it.
+// It only walks the history and scans the tree at HEAD (or at the end of the range), skipping all the diffing and blaming.
+
+use crate::error::{Result, TheseusError};
+use crate::file_types::PathFilter;
+use crate::gix_helpers::{
+    Granularity, RevisionRange, first_parent_commits, local_commit_time, period_key,
+};
+use crate::options::AnalysisOptions;
+use crate::theseus::{commits_to_analyze, open_repo};
+use gix::bstr::ByteSlice;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone)]
+pub struct DryRunReport {
+    pub weekly_commits: usize,
+    pub monthly_commits: usize,
+    pub yearly_commits: usize,
+    // The commits the analysis would diff, picked like it does: with its excluded commits,
+    // sampling, tag bounds and authors
+    pub commits_to_analyze: usize,
+    pub first_commit_time: Option<String>,
+    pub last_commit_time: Option<String>,
+    pub files_at_head: usize,
+    // The files at HEAD that pass the include globs and the filetype filter
+    pub files_to_analyze: usize,
+}
+
+pub fn dry_run(repo_path: &str, options: &AnalysisOptions) -> Result<DryRunReport> {
+    let repo = open_repo(repo_path)?;
+    let path_filter = PathFilter::from_options(options)?.with_text_attributes(&repo)?;
+    let range = match &options.range {
+        Some(spec) => RevisionRange::parse(&repo, spec)?,
+        None => RevisionRange::head(&repo)?,
+    };
+    // One walk buckets the history by week, month and year at once
+    let week_start = options.week_start.unwrap_or(chrono::Weekday::Sun);
+    let granularities = [
+        Granularity::Weekly(week_start),
+        Granularity::Monthly,
+        Granularity::Yearly,
+    ];
+    let mut buckets: [HashSet<String>; 3] = Default::default();
+    let (mut first_commit_time, mut last_commit_time) = (None, None);
+    for commit in first_parent_commits(&repo, range, !options.no_commit_graph)? {
+        let time = local_commit_time(commit?.time().map_err(TheseusError::odb)?);
+        for (granularity, keys) in granularities.iter().zip(&mut buckets) {
+            keys.insert(period_key(time, *granularity));
+        }
+        // The walk goes from the newest commit to the oldest
+        let time_string = time.format("%Y-%m-%d %H:%M:%S").to_string();
+        last_commit_time.get_or_insert_with(|| time_string.clone());
+        first_commit_time = Some(time_string);
+    }
+    let [weekly_commits, monthly_commits, yearly_commits] = buckets.map(|keys| keys.len());
+    // The dry run doesn't write any files, so the selection doesn't go through the commit cache
+    let selection_options = AnalysisOptions {
+        cache_dir: None,
+        ..options.clone()
+    };
+    let commits_to_analyze = commits_to_analyze(&repo, &selection_options, None)?.len();
+
+    let mut recorder = gix::traverse::tree::Recorder::default();
+    repo.find_commit(range.tip)
+        .map_err(TheseusError::odb)?
+        .tree()
+        .map_err(TheseusError::odb)?
+        .traverse()
+        .breadthfirst(&mut recorder)
+        .map_err(TheseusError::traversal)?;
+    let files: Vec<_> = recorder
+        .records
+        .iter()
+        .filter(|entry| entry.mode.is_blob())
+        .collect();
+    let files_to_analyze = files
+        .iter()
+        .filter(|entry| path_filter.is_analyzed(entry.filepath.as_bstr()))
+        .count();
+
+    Ok(DryRunReport {
+        weekly_commits,
+        monthly_commits,
+        yearly_commits,
+        commits_to_analyze,
+        first_commit_time,
+        last_commit_time,
+        files_at_head: files.len(),
+        files_to_analyze,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestRepo;
+
+    #[test]
+    fn test_dry_run_counts_buckets_and_files() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\n")
+            .write("notes.bin", "a\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\n")
+            .commit("2019-03-20T12:00:00+00:00");
+        repo.write("lib.rs", "a\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let report = dry_run(repo.path(), &AnalysisOptions::default()).unwrap();
+        assert_eq!(report.weekly_commits, 3);
+        assert_eq!(report.monthly_commits, 2);
+        assert_eq!(report.yearly_commits, 2);
+        assert_eq!(report.commits_to_analyze, 3);
+        assert_eq!(
+            report.first_commit_time.as_deref(),
+            Some("2019-03-01 12:00:00")
+        );
+        assert_eq!(
+            report.last_commit_time.as_deref(),
+            Some("2021-03-01 12:00:00")
+        );
+        assert_eq!(report.files_at_head, 3);
+        assert_eq!(report.files_to_analyze, 2);
+    }
+
+    #[test]
+    fn test_dry_run_picks_the_commits_to_analyze_like_the_analysis() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\n")
+            .commit("2019-03-20T12:00:00+00:00");
+        let same_week = repo.git(&["rev-parse", "HEAD"]);
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-21T12:00:00+00:00");
+
+        let report = dry_run(repo.path(), &AnalysisOptions::default()).unwrap();
+        assert_eq!(report.weekly_commits, 2);
+        assert_eq!(report.commits_to_analyze, 2);
+        // Every commit gets its own diff with authors to credit
+        let authors = AnalysisOptions {
+            authors: vec!["test@example.com".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            dry_run(repo.path(), &authors).unwrap().commits_to_analyze,
+            3
+        );
+        let excluded = AnalysisOptions {
+            authors: vec!["test@example.com".to_string()],
+            exclude_commits: vec![same_week.trim().to_string()],
+            ..Default::default()
+        };
+        let report = dry_run(repo.path(), &excluded).unwrap();
+        assert_eq!(report.weekly_commits, 2);
+        assert_eq!(report.commits_to_analyze, 2);
+    }
+}
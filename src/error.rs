@@ -0,0 +1,176 @@
+use gix::bstr::{BStr, BString};
+use std::path::PathBuf;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The ways an analysis can fail.
+/// The binary just bubbles these up through anyhow, but library users can match on them.
+#[derive(Debug, thiserror::Error)]
+pub enum TheseusError {
+    #[error("could not open a git repository at {path}")]
+    RepoNotFound {
+        path: PathBuf,
+        #[source]
+        source: Box<gix::open::Error>,
+    },
+    #[error("failed to clone {url}")]
+    CloneFailed {
+        url: String,
+        #[source]
+        source: BoxError,
+    },
+    #[error("the repository at {0} is a shallow clone, its full history is needed")]
+    ShallowRepo(PathBuf),
+    #[error("the repository at {0} is bare, it has no worktree to include")]
+    BareRepo(PathBuf),
+    #[error("failed to read {path} from the worktree")]
+    WorktreeRead {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed to read the archive {path}")]
+    ArchiveRead {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("the repository has no commits to analyze")]
+    EmptyRepo,
+    #[error("could not resolve {spec} to a commit")]
+    RevisionNotFound {
+        spec: String,
+        #[source]
+        source: BoxError,
+    },
+    #[error("failed to read the checkpoint {path}")]
+    CheckpointRead {
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    #[error("failed to write the checkpoint {path}")]
+    CheckpointWrite {
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    #[error("failed to read the recorded actions in {path}")]
+    ActionsRead {
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    #[error("failed to record the actions to {path}")]
+    ActionsWrite {
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    #[error("failed to stream the snapshots to {path}")]
+    SnapshotStreamWrite {
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    #[error("{0}")]
+    IncompatibleOptions(&'static str),
+    #[error("invalid glob pattern {pattern}")]
+    InvalidGlob {
+        pattern: String,
+        #[source]
+        source: globset::Error,
+    },
+    #[error("failed to read the filetypes file {path}")]
+    FiletypesRead {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("invalid glob patterns in {path}: {}", join_invalid_lines(.lines))]
+    InvalidFiletypes {
+        path: PathBuf,
+        lines: Vec<InvalidGlobLine>,
+    },
+    #[error("unknown language {0}")]
+    UnknownLanguage(String),
+    #[error("symmetric ranges like {0} are not supported, use A..B")]
+    UnsupportedRange(String),
+    #[error("there is no tag named {0}")]
+    TagNotFound(String),
+    #[error("failed to walk the commit history")]
+    Traversal(#[source] BoxError),
+    #[error("failed to diff the tree of commit {commit} against its predecessor")]
+    TreeDiffFailed {
+        commit: gix::ObjectId,
+        #[source]
+        source: BoxError,
+    },
+    #[error("failed to diff {path}")]
+    DiffFailed {
+        path: BString,
+        #[source]
+        source: BoxError,
+    },
+    #[error("failed to blame {path}")]
+    BlameFailed {
+        path: BString,
+        #[source]
+        source: BoxError,
+    },
+    #[error("failed to read from the object database")]
+    Odb(#[source] BoxError),
+    #[error("invalid diff of {path}: {reason}")]
+    InvalidLineDiffs { path: BString, reason: String },
+    #[error("file not found in the blame snapshot: {0}")]
+    FileNotFound(BString),
+    #[error("failed to spool the per-commit results through a temp file")]
+    ResultsSpool(#[source] std::io::Error),
+    #[error("the blame processor stopped before the analysis finished")]
+    ProcessorStopped,
+}
+
+impl TheseusError {
+    pub(crate) fn traversal(err: impl Into<BoxError>) -> Self {
+        Self::Traversal(err.into())
+    }
+
+    pub(crate) fn odb(err: impl Into<BoxError>) -> Self {
+        Self::Odb(err.into())
+    }
+
+    // Errors about reading or diffing a single file, which the analysis can skip the file over
+    pub(crate) fn is_file_error(&self) -> bool {
+        matches!(self, Self::DiffFailed { .. } | Self::Odb(_))
+    }
+
+    pub(crate) fn diff_failed(path: &BStr, err: impl Into<BoxError>) -> Self {
+        Self::DiffFailed {
+            path: path.to_owned(),
+            source: err.into(),
+        }
+    }
+}
+
+pub type Result<T, E = TheseusError> = std::result::Result<T, E>;
+
+// A line of a filetypes file whose glob doesn't compile
+#[derive(Debug)]
+pub struct InvalidGlobLine {
+    pub line: usize,
+    pub source: globset::Error,
+}
+
+impl std::fmt::Display for InvalidGlobLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.source)
+    }
+}
+
+fn join_invalid_lines(lines: &[InvalidGlobLine]) -> String {
+    lines
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,184 @@
+// The blame of a single file through its history, for a "code age" chart of just that file.
+// Instead of blaming the whole repo at weekly commits, we walk every first-parent commit, only look
+// the file up in each tree, and diff it whenever its blob changes. Each of those commits is a snapshot.
+// Renames aren't followed, the history of the file starts when it got its current path.
+
+use crate::blame::FileBlame;
+use crate::churn::{DEFAULT_LARGE_COMMIT_LINES, large_commits};
+use crate::error::{Result, TheseusError};
+use crate::gix_helpers::{RevisionRange, diff_bytes, list_every_commit, measure_size};
+use crate::options::AnalysisOptions;
+use crate::profile::PhaseTimings;
+use crate::repo_blame_snapshot::CommitChurn;
+use crate::theseus::{
+    CohortKey, CommitCohortInfo, RunStats, TheseusResult, new_commit_results, open_repo,
+    selected_commit,
+};
+use std::collections::HashMap;
+
+// The file at some commit: its blob, its content and its blame
+struct FileVersion {
+    id: gix::ObjectId,
+    data: Vec<u8>,
+    blame: FileBlame<CohortKey>,
+}
+
+// Only ignore_eol, measure, spool_results and no_commit_graph apply, the other options are about which files to analyze
+pub fn file_history(
+    repo_path: &str,
+    path: &str,
+    options: &AnalysisOptions,
+) -> Result<TheseusResult> {
+    let repo = open_repo(repo_path)?;
+    let commits = list_every_commit(&repo, RevisionRange::head(&repo)?, !options.no_commit_graph)?;
+
+    let mut commit_infos = Vec::new();
+    let mut commit_results = new_commit_results(options)?;
+    let mut lines_added: HashMap<CohortKey, u64> = HashMap::new();
+    let mut churn = Vec::new();
+    let mut current: Option<FileVersion> = None;
+    for commit in commits {
+        let entry = commit
+            .tree()
+            .map_err(TheseusError::odb)?
+            .lookup_entry_by_path(path)
+            .map_err(TheseusError::odb)?;
+        let id = entry
+            .filter(|entry| entry.mode().is_blob())
+            .map(|entry| entry.object_id());
+        if id == current.as_ref().map(|version| version.id) {
+            continue;
+        }
+
+        let cohort = commit_infos.len();
+        let selected = selected_commit(&commit)?;
+        commit_infos.push(CommitCohortInfo {
+            id: selected.id,
+            time: selected.time,
+            time_string: selected.time_string,
+            year: selected.year,
+            summary: Some(selected.summary),
+        });
+        let mut commit_churn = CommitChurn::default();
+        current = match id {
+            Some(id) => {
+                let data = repo
+                    .find_object(id)
+                    .map_err(TheseusError::odb)?
+                    .detach()
+                    .data;
+                let blame = match current {
+                    Some(previous) => {
+                        let line_diffs = diff_bytes(&previous.data, &data, cohort, options);
+                        for (deleted, inserted, _) in &line_diffs {
+                            commit_churn.added += inserted.len() as u64;
+                            commit_churn.removed += deleted.len() as u64;
+                        }
+                        previous
+                            .blame
+                            .apply_line_diffs(line_diffs)
+                            .map_err(|reason| TheseusError::InvalidLineDiffs {
+                                path: path.into(),
+                                reason,
+                            })?
+                    }
+                    None => {
+                        let total_lines = measure_size(&data, options);
+                        commit_churn.added = total_lines as u64;
+                        FileBlame::new(total_lines, cohort)
+                    }
+                };
+                Some(FileVersion { id, data, blame })
+            }
+            None => {
+                commit_churn.removed =
+                    current.map_or(0, |previous| previous.blame.total_lines() as u64);
+                None
+            }
+        };
+        if commit_churn.added > 0 {
+            *lines_added.entry(cohort).or_insert(0) += commit_churn.added;
+        }
+        churn.push(commit_churn);
+        let stats = current
+            .as_ref()
+            .map(|version| {
+                version
+                    .blame
+                    .cohort_line_counts()
+                    .into_iter()
+                    .map(|(cohort, lines)| (cohort, lines as i64))
+                    .collect()
+            })
+            .unwrap_or_default();
+        commit_results
+            .push(stats)
+            .map_err(TheseusError::ResultsSpool)?;
+    }
+    if commit_infos.is_empty() {
+        return Err(TheseusError::FileNotFound(path.into()));
+    }
+    commit_results.flush().map_err(TheseusError::ResultsSpool)?;
+
+    let stats = RunStats {
+        files_at_head: usize::from(current.is_some()),
+        lines_at_head: current.map_or(0, |version| version.blame.total_lines() as u64),
+        files_skipped: 0,
+        small_files_skipped: 0,
+        renames_detected: 0,
+        copies_detected: 0,
+        // Renames aren't followed, so there's only ever the one path
+        files_ever_seen: 1,
+        baseline_commits: 0,
+        large_commits: large_commits(
+            &commit_infos,
+            &churn,
+            options
+                .large_commit_lines
+                .unwrap_or(DEFAULT_LARGE_COMMIT_LINES),
+        ),
+        phases: PhaseTimings::default(),
+    };
+    Ok(TheseusResult {
+        commit_cohort_info: commit_infos,
+        cohort_data: commit_results,
+        lines_added: lines_added.into_iter().collect(),
+        churn,
+        ever_written: None,
+        by_extension: None,
+        by_directory: None,
+        failures: Vec::new(),
+        stats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestRepo;
+
+    #[test]
+    fn test_only_the_commits_touching_the_file_are_snapshots() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("other.rs", "x\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nc\nd\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let result = file_history(repo.path(), "main.rs", &AnalysisOptions::default()).unwrap();
+        assert_eq!(result.commit_cohort_info.len(), 2);
+        assert_eq!(result.commit_cohort_info[1].year, 2021);
+        assert_eq!(
+            result.cohort_data.last().unwrap().unwrap(),
+            vec![(0, 2), (1, 1)]
+        );
+        assert_eq!(result.stats.lines_at_head, 3);
+
+        assert!(matches!(
+            file_history(repo.path(), "missing.rs", &AnalysisOptions::default()),
+            Err(TheseusError::FileNotFound(_))
+        ));
+    }
+}
@@ -1,21 +1,67 @@
+use crate::error::{InvalidGlobLine, Result, TheseusError};
+use crate::options::AnalysisOptions;
 use gix::bstr::BStr;
 use gix::path::from_bstr;
 use globset::{Glob, GlobSet, GlobSetBuilder};
-use std::sync::OnceLock;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
 
 static ALLOWLIST: OnceLock<GlobSet> = OnceLock::new();
 
 fn get_allowlist() -> &'static GlobSet {
     ALLOWLIST.get_or_init(|| {
-        let mut builder = GlobSetBuilder::new();
-        include_str!("allowed_filetypes.txt")
-            .lines()
-            .map(str::trim)
-            .filter(|l| !l.is_empty() && !l.starts_with('#'))
-            .for_each(|p| {
-                builder.add(Glob::new(p).expect("invalid glob pattern"));
-            });
-        builder.build().expect("failed to build globset")
+        // A bad pattern in the built-in list only loses that filetype, the tests catch it
+        let (globs, _) = parse_filetypes(include_str!("allowed_filetypes.txt"));
+        build_filetypes(globs).unwrap_or_else(|_| GlobSet::empty())
+    })
+}
+
+// The globs of a filetypes file, one per line with # comments, along with the lines whose glob
+// doesn't compile. Line numbers start at 1.
+fn parse_filetypes(contents: &str) -> (Vec<Glob>, Vec<InvalidGlobLine>) {
+    let mut globs = Vec::new();
+    let mut invalid = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let pattern = line.trim();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            continue;
+        }
+        match Glob::new(pattern) {
+            Ok(glob) => globs.push(glob),
+            Err(source) => invalid.push(InvalidGlobLine {
+                line: i + 1,
+                source,
+            }),
+        }
+    }
+    (globs, invalid)
+}
+
+fn build_filetypes(globs: Vec<Glob>) -> std::result::Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob);
+    }
+    builder.build()
+}
+
+// Reads a filetypes file in the format of allowed_filetypes.txt, to use instead of the built-in one.
+// Every invalid line is reported at once, so they can all be fixed in one go.
+pub fn load_filetypes(path: &Path) -> Result<GlobSet> {
+    let contents = std::fs::read_to_string(path).map_err(|source| TheseusError::FiletypesRead {
+        path: path.to_owned(),
+        source,
+    })?;
+    let (globs, invalid) = parse_filetypes(&contents);
+    if !invalid.is_empty() {
+        return Err(TheseusError::InvalidFiletypes {
+            path: path.to_owned(),
+            lines: invalid,
+        });
+    }
+    build_filetypes(globs).map_err(|source| TheseusError::InvalidGlob {
+        pattern: path.display().to_string(),
+        source,
     })
 }
 
@@ -24,3 +70,289 @@ pub fn is_allowed_filetype(path: &BStr) -> bool {
     let filename = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
     get_allowlist().is_match(&filename)
 }
+
+// The extension of a file, e.g. "rs" for "src/main.rs", or "" for files without one like "Makefile"
+pub fn file_extension(path: &BStr) -> String {
+    from_bstr(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// The top-level directory of a file, e.g. "src" for "src/bin/main.rs", or "." for the files at the root
+pub fn top_level_dir(path: &BStr) -> String {
+    match path.iter().position(|&byte| byte == b'/') {
+        Some(end) => String::from_utf8_lossy(&path[..end]).into_owned(),
+        None => ".".to_string(),
+    }
+}
+
+// The extensions of each language's source files, for --only-languages
+const LANGUAGE_EXTENSIONS: &[(&str, &[&str])] = &[
+    ("c", &["c", "h"]),
+    ("cpp", &["cpp", "cc", "cxx", "hpp", "hh", "hxx", "h"]),
+    ("csharp", &["cs"]),
+    ("go", &["go"]),
+    ("haskell", &["hs"]),
+    ("java", &["java"]),
+    ("javascript", &["js", "mjs", "cjs", "jsx"]),
+    ("kotlin", &["kt", "kts"]),
+    ("php", &["php"]),
+    ("python", &["py", "pyi"]),
+    ("ruby", &["rb"]),
+    ("rust", &["rs"]),
+    ("scala", &["scala"]),
+    ("shell", &["sh", "bash", "zsh"]),
+    ("swift", &["swift"]),
+    ("typescript", &["ts", "tsx"]),
+];
+
+pub fn language_extensions(language: &str) -> Option<&'static [&'static str]> {
+    LANGUAGE_EXTENSIONS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(language))
+        .map(|(_, extensions)| *extensions)
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|source| TheseusError::InvalidGlob {
+            pattern: pattern.clone(),
+            source,
+        })?;
+        builder.add(glob);
+    }
+    builder.build().map_err(|source| TheseusError::InvalidGlob {
+        pattern: patterns.join(" "),
+        source,
+    })
+}
+
+// The repo's .gitattributes, to analyze the files it marks as text whatever their extension.
+// Looking a path up needs the stack's buffers, so the threads take turns. Only the paths the
+// allowlist doesn't know about are looked up.
+struct TextAttributes {
+    stack: Mutex<(gix::worktree::Stack, gix::OdbHandle)>,
+}
+
+impl TextAttributes {
+    // Like the diffs, this reads the .gitattributes files of the index, or of HEAD in a bare repo
+    fn new(repo: &gix::Repository) -> Result<Self> {
+        let index = repo
+            .index_or_load_from_head_or_empty()
+            .map_err(TheseusError::odb)?;
+        let stack = repo
+            .attributes_only(
+                &index,
+                gix::worktree::stack::state::attributes::Source::IdMapping,
+            )
+            .map_err(TheseusError::odb)?
+            .detach();
+        Ok(Self {
+            stack: Mutex::new((stack, repo.objects.clone())),
+        })
+    }
+
+    // Whether the path is marked `diff`, `diff=<driver>` or `text`, i.e. to be diffed line by line
+    fn is_marked_text(&self, path: &BStr) -> bool {
+        let mut guard = self.stack.lock().unwrap();
+        let (stack, objects) = &mut *guard;
+        let mut outcome = gix::attrs::search::Outcome::default();
+        outcome.initialize_with_selection(stack.attributes_collection(), ["diff", "text"]);
+        let Ok(platform) = stack.at_entry(path, Some(gix::index::entry::Mode::FILE), &*objects)
+        else {
+            return false;
+        };
+        platform.matching_attributes(&mut outcome);
+        outcome.iter_selected().any(|matched| {
+            let assignment = &matched.assignment;
+            match assignment.state {
+                gix::attrs::StateRef::Set => true,
+                gix::attrs::StateRef::Value(_) => assignment.name.as_str() == "diff",
+                _ => false,
+            }
+        })
+    }
+}
+
+impl std::fmt::Debug for TextAttributes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TextAttributes")
+    }
+}
+
+// Decides which files get analyzed: the ones under the include globs, if there are any,
+// that also look like source code, unless all_filetypes is set.
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    all_filetypes: bool,
+    // Replaces the built-in allowlist when set
+    filetypes: Option<GlobSet>,
+    // Adds the files .gitattributes marks as text to the built-in allowlist
+    text_attributes: Option<Arc<TextAttributes>>,
+}
+
+impl PathFilter {
+    // The include globs match whole paths, e.g. "src/**" or "crates/*/src/**"
+    pub fn new(include: &[String], all_filetypes: bool) -> Result<Self> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(build_globset(include)?)
+        };
+        Ok(Self {
+            include,
+            all_filetypes,
+            filetypes: None,
+            text_attributes: None,
+        })
+    }
+
+    // Also analyze the files the repo's .gitattributes mark as `diff` or `text`, e.g. templates
+    // with an unusual extension. Only applies on top of the built-in allowlist.
+    pub fn with_text_attributes(mut self, repo: &gix::Repository) -> Result<Self> {
+        self.text_attributes = Some(Arc::new(TextAttributes::new(repo)?));
+        Ok(self)
+    }
+
+    // Only analyze the files with one of these extensions, e.g. "rs" or ".toml",
+    // instead of the ones the built-in allowlist knows about
+    pub fn only_extensions(mut self, extensions: &[String]) -> Result<Self> {
+        let patterns: Vec<String> = extensions
+            .iter()
+            .map(|extension| format!("*.{}", extension.trim_start_matches('.')))
+            .collect();
+        self.filetypes = Some(build_globset(&patterns)?);
+        Ok(self)
+    }
+
+    pub fn from_options(options: &AnalysisOptions) -> Result<Self> {
+        let mut extensions = options.only_extensions.clone();
+        for language in &options.only_languages {
+            let language_extensions = language_extensions(language)
+                .ok_or_else(|| TheseusError::UnknownLanguage(language.clone()))?;
+            extensions.extend(language_extensions.iter().map(|ext| ext.to_string()));
+        }
+        let mut filter = Self::new(&options.include, options.all_filetypes)?;
+        if let Some(path) = &options.filetypes {
+            filter.filetypes = Some(load_filetypes(path)?);
+        }
+        if extensions.is_empty() {
+            Ok(filter)
+        } else {
+            filter.only_extensions(&extensions)
+        }
+    }
+
+    // Whether the path is in the part of the repo being analyzed, whatever its filetype
+    pub fn is_included(&self, path: &BStr) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(from_bstr(path)))
+    }
+
+    pub fn is_analyzed(&self, path: &BStr) -> bool {
+        self.is_included(path) && self.is_allowed_filetype(path)
+    }
+
+    fn is_allowed_filetype(&self, path: &BStr) -> bool {
+        match &self.filetypes {
+            Some(filetypes) => {
+                let path = from_bstr(path);
+                let filename = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                filetypes.is_match(filename)
+            }
+            None => {
+                self.all_filetypes
+                    || is_allowed_filetype(path)
+                    || self
+                        .text_attributes
+                        .as_ref()
+                        .is_some_and(|attributes| attributes.is_marked_text(path))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_extension() {
+        assert_eq!(file_extension(BStr::new("src/main.rs")), "rs");
+        assert_eq!(file_extension(BStr::new("a.tar.gz")), "gz");
+        assert_eq!(file_extension(BStr::new("Makefile")), "");
+        assert_eq!(file_extension(BStr::new(".gitignore")), "");
+    }
+
+    #[test]
+    fn test_top_level_dir() {
+        assert_eq!(top_level_dir(BStr::new("src/bin/main.rs")), "src");
+        assert_eq!(top_level_dir(BStr::new("build.rs")), ".");
+    }
+
+    #[test]
+    fn test_include_globs_restrict_analyzed_paths() {
+        let filter = PathFilter::new(&["src/**".to_string()], false).unwrap();
+        assert!(filter.is_analyzed(BStr::new("src/main.rs")));
+        assert!(filter.is_analyzed(BStr::new("src/deep/mod.rs")));
+        assert!(!filter.is_analyzed(BStr::new("tests/main.rs")));
+        // The filetype filter still applies inside the included paths
+        assert!(filter.is_included(BStr::new("src/logo.png")));
+        assert!(!filter.is_analyzed(BStr::new("src/logo.png")));
+
+        let everything = PathFilter::new(&[], true).unwrap();
+        assert!(everything.is_analyzed(BStr::new("tests/logo.png")));
+    }
+
+    #[test]
+    fn test_only_languages_replaces_the_allowlist() {
+        let options = AnalysisOptions {
+            only_extensions: vec![".toml".to_string()],
+            only_languages: vec!["Rust".to_string()],
+            ..Default::default()
+        };
+        let filter = PathFilter::from_options(&options).unwrap();
+        assert!(filter.is_analyzed(BStr::new("src/main.rs")));
+        assert!(filter.is_analyzed(BStr::new("Cargo.toml")));
+        assert!(!filter.is_analyzed(BStr::new("src/main.py")));
+
+        let unknown = AnalysisOptions {
+            only_languages: vec!["klingon".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            PathFilter::from_options(&unknown),
+            Err(TheseusError::UnknownLanguage(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_filetypes_are_reported_by_line() {
+        let (_, invalid) = parse_filetypes(include_str!("allowed_filetypes.txt"));
+        assert!(invalid.is_empty());
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("filetypes.txt");
+        std::fs::write(&path, "# Rust and docs\n*.rs\n*.[md\n\n{*.txt\n").unwrap();
+        match load_filetypes(&path) {
+            Err(TheseusError::InvalidFiletypes { lines, .. }) => {
+                let numbers: Vec<usize> = lines.iter().map(|invalid| invalid.line).collect();
+                assert_eq!(numbers, [3, 5]);
+            }
+            other => panic!("expected invalid filetypes, got {other:?}"),
+        }
+
+        std::fs::write(&path, "*.rs\n").unwrap();
+        let options = AnalysisOptions {
+            filetypes: Some(path),
+            ..Default::default()
+        };
+        let filter = PathFilter::from_options(&options).unwrap();
+        assert!(filter.is_analyzed(BStr::new("src/main.rs")));
+        assert!(!filter.is_analyzed(BStr::new("src/main.py")));
+    }
+}
@@ -1,39 +1,318 @@
 // A collection of functions that formats data into the right shape for plotting functions.
 
-use crate::theseus::TheseusResult;
+use crate::commit_results::CommitResults;
+use crate::error::{Result, TheseusError};
+use crate::options::Verbosity;
+use crate::repo_blame_snapshot::GroupSnapshot;
+use crate::theseus::{CohortKey, CommitCohortInfo, TheseusResult};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDateTime};
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 // The data format of cohorts.json
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CohortData {
     pub y: Vec<Vec<i64>>,
     pub ts: Vec<String>,
     pub labels: Vec<String>,
+    // What each snapshot in ts is, when asked for with FormatOptions::commit_metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commits: Option<Vec<CommitMetadata>>,
 }
 
-pub fn format_cohort_data(result: TheseusResult) -> CohortData {
-    sum_commit_data_by_year(result)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitMetadata {
+    // Empty for snapshots that aren't commits, like the worktree
+    pub hash: String,
+    pub summary: String,
+}
+
+pub const DEFAULT_LABEL_TEMPLATE: &str = "Code added in {y}";
+
+// The order of the cohorts in cohorts.json, which is the order they're stacked in from the bottom
+// and listed in the legend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LabelOrder {
+    // The oldest cohort first, or alphabetical for labels that aren't years
+    #[default]
+    Asc,
+    Desc,
+    // The biggest cohort at the last snapshot first, so it's at the bottom of the chart
+    Size,
+}
+
+impl std::str::FromStr for LabelOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(LabelOrder::Asc),
+            "desc" => Ok(LabelOrder::Desc),
+            "size" => Ok(LabelOrder::Size),
+            _ => Err(format!(
+                "unknown label order '{s}', expected asc, desc or size"
+            )),
+        }
+    }
+}
+
+// How finely the cohorts split the time the code was added in. Only the labels change, the commits
+// are still analyzed weekly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CohortGranularity {
+    #[default]
+    Year,
+    Quarter,
+    Month,
+}
+
+impl std::str::FromStr for CohortGranularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "year" => Ok(CohortGranularity::Year),
+            "quarter" => Ok(CohortGranularity::Quarter),
+            "month" => Ok(CohortGranularity::Month),
+            _ => Err(format!(
+                "unknown cohort granularity '{s}', expected year, quarter or month"
+            )),
+        }
+    }
+}
+
+impl CohortGranularity {
+    // The part of the year a commit's lines are counted in: the quarter or the month, and 0 for
+    // yearly cohorts
+    fn period_of(self, info: &CommitCohortInfo) -> u32 {
+        let month = info.time.month();
+        match self {
+            CohortGranularity::Year => 0,
+            CohortGranularity::Quarter => (month - 1) / 3 + 1,
+            CohortGranularity::Month => month,
+        }
+    }
+}
+
+// How the per-commit blame counts get grouped into labelled cohorts
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// Put the lines of the first analyzed commit in a cohort with this label, ahead of the
+    /// yearly ones, instead of counting them in the year of that commit
+    pub first_commit_label: Option<String>,
+    /// How the yearly cohorts are labelled, with `{y}` standing for the year, or for the quarter
+    /// or month like "2021-Q1" or "2021-03" with a finer cohort_granularity.
+    /// Defaults to DEFAULT_LABEL_TEMPLATE
+    pub label_template: Option<String>,
+    /// Split the cohorts by quarter or month of the year instead of by year
+    pub cohort_granularity: CohortGranularity,
+    /// Labels for specific years, used instead of the template
+    pub year_labels: HashMap<u32, String>,
+    /// Add the short hash and summary of the commit of each snapshot
+    pub commit_metadata: bool,
+    /// The order of the cohorts, oldest first by default
+    pub label_order: LabelOrder,
+    /// Collapse the cohorts on one side of a commit into a single one, e.g. to see how much of the
+    /// code from before a rewrite is left
+    pub boundary: Option<CohortBoundary>,
+    /// Only keep the N biggest cohorts and sum the rest into an "Other" one, so the legend stays readable
+    pub top_n: Option<usize>,
+    /// Replace the dates in ts with the number of weeks since the first snapshot, e.g. "52", so
+    /// repos that started in different years can be overlaid on the same timeline
+    pub relative_time: bool,
+    pub verbosity: Verbosity,
+}
+
+#[derive(Debug, Clone)]
+pub struct CohortBoundary {
+    /// The time of the boundary commit, like CommitCohortInfo::time. The commits made at
+    /// or before it are on its before side.
+    pub time: DateTime<FixedOffset>,
+    pub label: String,
+    /// Collapse the cohorts after the boundary instead of the ones before it
+    pub collapse_after: bool,
+}
+
+// The cohort a commit's lines are counted in. They're ordered like the rows of cohorts.json.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Cohort {
+    FirstCommit,
+    BeforeBoundary,
+    // The year, and the quarter or month in it with a finer CohortGranularity
+    Period(u32, u32),
+    AfterBoundary,
+}
+
+impl FormatOptions {
+    pub fn year_label(&self, year: u32) -> String {
+        if let Some(label) = self.year_labels.get(&year) {
+            return label.clone();
+        }
+        self.template_label(&year.to_string())
+    }
+
+    fn period_label(&self, year: u32, period: u32) -> String {
+        match self.cohort_granularity {
+            CohortGranularity::Year => self.year_label(year),
+            CohortGranularity::Quarter => self.template_label(&format!("{year}-Q{period}")),
+            CohortGranularity::Month => self.template_label(&format!("{year}-{period:02}")),
+        }
+    }
+
+    fn template_label(&self, period: &str) -> String {
+        self.label_template
+            .as_deref()
+            .unwrap_or(DEFAULT_LABEL_TEMPLATE)
+            .replace("{y}", period)
+    }
+}
+
+pub fn format_cohort_data(result: &TheseusResult, options: &FormatOptions) -> Result<CohortData> {
+    sum_commit_data_by_year(result, options)
+}
+
+// The lines each cohort ever wrote at each snapshot, in the same shape as the surviving ones
+pub fn format_ever_written(
+    result: &TheseusResult,
+    options: &FormatOptions,
+) -> Result<Option<CohortData>> {
+    result
+        .ever_written
+        .as_ref()
+        .map(|ever_written| sum_snapshots_by_year(result, ever_written, options))
+        .transpose()
 }
 
-pub fn sum_commit_data_by_year(result: TheseusResult) -> CohortData {
-    let commit_infos = result.commit_cohort_info;
-    let snapshots = result.cohort_data;
+// The cohorts of the files of each extension, keyed by extension ("" for files without one).
+// Every extension gets a snapshot at every commit, empty before its first file.
+pub fn format_by_extension(
+    result: &TheseusResult,
+    options: &FormatOptions,
+) -> Result<Option<BTreeMap<String, CohortData>>> {
+    result
+        .by_extension
+        .as_ref()
+        .map(|by_extension| format_groups(result, by_extension, options))
+        .transpose()
+}
+
+// The cohorts of the files of each top-level directory, keyed by directory ("." for the files at the root)
+pub fn format_by_directory(
+    result: &TheseusResult,
+    options: &FormatOptions,
+) -> Result<Option<BTreeMap<String, CohortData>>> {
+    result
+        .by_directory
+        .as_ref()
+        .map(|by_directory| format_groups(result, by_directory, options))
+        .transpose()
+}
 
-    let ts: Vec<String> = commit_infos
+fn format_groups(
+    result: &TheseusResult,
+    group_snapshots: &[GroupSnapshot<CohortKey>],
+    options: &FormatOptions,
+) -> Result<BTreeMap<String, CohortData>> {
+    let groups: BTreeSet<&String> = group_snapshots
+        .iter()
+        .flat_map(|snapshot| snapshot.iter().map(|(group, _)| group))
+        .collect();
+    groups
+        .into_iter()
+        .map(|group| {
+            let snapshots = group_snapshots
+                .iter()
+                .map(|snapshot| {
+                    snapshot
+                        .iter()
+                        .find(|(other, _)| other == group)
+                        .map(|(_, stats)| stats.clone())
+                        .unwrap_or_default()
+                })
+                .collect();
+            let snapshots = CommitResults::InMemory(snapshots);
+            Ok((
+                group.clone(),
+                sum_snapshots_by_year(result, &snapshots, options)?,
+            ))
+        })
+        .collect()
+}
+
+pub fn sum_commit_data_by_year(
+    result: &TheseusResult,
+    options: &FormatOptions,
+) -> Result<CohortData> {
+    sum_snapshots_by_year(result, &result.cohort_data, options)
+}
+
+fn sum_snapshots_by_year(
+    result: &TheseusResult,
+    snapshots: &CommitResults<CohortKey>,
+    options: &FormatOptions,
+) -> Result<CohortData> {
+    let commit_infos = &result.commit_cohort_info;
+    let verbosity = options.verbosity;
+
+    // The snapshots are of the last commits, which is only the last one with final_only
+    let snapshot_infos = &commit_infos[commit_infos.len() - snapshots.len()..];
+    let ts: Vec<String> = snapshot_infos
         .iter()
         .map(|info| info.time_string.clone())
         .collect();
 
-    let all_blame_years: std::collections::BTreeSet<u32> =
-        commit_infos.iter().map(|info| info.year).collect();
-    let sorted_blame_years: Vec<u32> = all_blame_years.into_iter().collect();
-    let labels: Vec<String> = sorted_blame_years
+    // The first commit of an imported repo usually holds all of its earlier history,
+    // which would otherwise swamp the cohort of its year.
+    // With accurate_baseline, the first analyzed commit comes after the made-up baseline ones.
+    let first_commit_idx = options
+        .first_commit_label
+        .as_ref()
+        .map(|_| result.stats.baseline_commits);
+    let period_cohort = |info: &CommitCohortInfo| {
+        Cohort::Period(info.year, options.cohort_granularity.period_of(info))
+    };
+    let commit_cohorts: Vec<Cohort> = commit_infos
+        .iter()
+        .enumerate()
+        .map(|(commit_idx, info)| {
+            if first_commit_idx == Some(commit_idx) {
+                return Cohort::FirstCommit;
+            }
+            match &options.boundary {
+                Some(boundary) if info.time <= boundary.time => {
+                    if boundary.collapse_after {
+                        period_cohort(info)
+                    } else {
+                        Cohort::BeforeBoundary
+                    }
+                }
+                Some(boundary) if boundary.collapse_after => Cohort::AfterBoundary,
+                _ => period_cohort(info),
+            }
+        })
+        .collect();
+    let sorted_cohorts: Vec<Cohort> = commit_cohorts
         .iter()
-        .map(|y| format!("Code added in {y}"))
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
         .collect();
-    let year_to_label_index: std::collections::HashMap<u32, usize> = sorted_blame_years
+    let labels: Vec<String> = sorted_cohorts
+        .iter()
+        .map(|cohort| match cohort {
+            Cohort::FirstCommit => options.first_commit_label.clone().unwrap_or_default(),
+            Cohort::BeforeBoundary | Cohort::AfterBoundary => options
+                .boundary
+                .as_ref()
+                .map(|boundary| boundary.label.clone())
+                .unwrap_or_default(),
+            Cohort::Period(year, period) => options.period_label(*year, *period),
+        })
+        .collect();
+    let cohort_to_label_index: HashMap<Cohort, usize> = sorted_cohorts
         .iter()
         .enumerate()
-        .map(|(i, &year)| (year, i))
+        .map(|(i, &cohort)| (cohort, i))
         .collect();
 
     let num_labels = labels.len();
@@ -41,25 +320,406 @@ pub fn sum_commit_data_by_year(result: TheseusResult) -> CohortData {
     let mut y = vec![vec![0i64; num_snapshots]; num_labels];
 
     for (commit_idx, snapshot) in snapshots.iter().enumerate() {
+        let snapshot = snapshot.map_err(TheseusError::ResultsSpool)?;
         let mut is_snapshot_bad = false;
-        for (commit_key, line_count) in snapshot {
-            let blame_year = commit_infos[*commit_key].year;
-            let label_idx = year_to_label_index
-                .get(&blame_year)
+        for (commit_key, line_count) in &snapshot {
+            let label_idx = *cohort_to_label_index
+                .get(&commit_cohorts[*commit_key])
                 .expect("Label index not found");
-            y[*label_idx][commit_idx] += *line_count;
-            if y[*label_idx][commit_idx] > 1_000_000_000 {
-                println!(
-                    "Warning: commit {} has {} lines in year {} (line count: {})",
-                    commit_idx, y[*label_idx][commit_idx], blame_year, line_count
-                );
+            y[label_idx][commit_idx] += *line_count;
+            if y[label_idx][commit_idx] > 1_000_000_000 {
+                if !verbosity.is_quiet() {
+                    eprintln!(
+                        "Warning: commit {} has {} lines in cohort '{}' (line count: {})",
+                        commit_idx, y[label_idx][commit_idx], labels[label_idx], line_count
+                    );
+                }
                 is_snapshot_bad = true;
             }
         }
-        if is_snapshot_bad {
-            println!("Snapshot {} is bad", commit_idx);
-            println!("{:?}", snapshot);
+        if is_snapshot_bad && !verbosity.is_quiet() {
+            eprintln!("Snapshot {} is bad", commit_idx);
+            if verbosity.is_verbose() {
+                eprintln!("{:?}", snapshot);
+            }
+        }
+    }
+    let commits = options.commit_metadata.then(|| {
+        snapshot_infos
+            .iter()
+            .map(|info| CommitMetadata {
+                hash: if info.id.is_null() {
+                    String::new()
+                } else {
+                    info.id.to_hex_with_len(7).to_string()
+                },
+                summary: info.summary.clone().unwrap_or_default(),
+            })
+            .collect()
+    });
+    let ts = if options.relative_time {
+        weeks_since_start(&ts)
+    } else {
+        ts
+    };
+    let data = sort_labels(
+        CohortData {
+            y,
+            ts,
+            labels,
+            commits,
+        },
+        options.label_order,
+    );
+    Ok(match options.top_n {
+        Some(n) => keep_top_cohorts(data, n),
+        None => data,
+    })
+}
+
+// Reorders the cohorts, keeping each row of y with its label. The data is taken to be in
+// ascending order already, as it's made. Ties in size keep that order.
+pub fn sort_labels(data: CohortData, order: LabelOrder) -> CohortData {
+    let mut rows: Vec<(String, Vec<i64>)> = data.labels.into_iter().zip(data.y).collect();
+    match order {
+        LabelOrder::Asc => {}
+        LabelOrder::Desc => rows.reverse(),
+        LabelOrder::Size => {
+            rows.sort_by_key(|(_, row)| std::cmp::Reverse(row.last().copied().unwrap_or(0)))
+        }
+    }
+    let (labels, y) = rows.into_iter().unzip();
+    CohortData { labels, y, ..data }
+}
+
+// Keeps the n cohorts with the most lines summed over the whole series, in their order, and sums
+// the rest into an "Other" cohort after them
+pub fn keep_top_cohorts(data: CohortData, n: usize) -> CohortData {
+    if data.labels.len() <= n {
+        return data;
+    }
+    let mut by_size: Vec<usize> = (0..data.labels.len()).collect();
+    by_size.sort_by_key(|&i| std::cmp::Reverse(data.y[i].iter().sum::<i64>()));
+    let kept: HashSet<usize> = by_size.into_iter().take(n).collect();
+    let mut labels = Vec::new();
+    let mut y = Vec::new();
+    let mut other = vec![0i64; data.ts.len()];
+    for (i, (label, row)) in data.labels.into_iter().zip(data.y).enumerate() {
+        if kept.contains(&i) {
+            labels.push(label);
+            y.push(row);
+        } else {
+            for (sum, lines) in other.iter_mut().zip(row) {
+                *sum += lines;
+            }
+        }
+    }
+    labels.push("Other".to_string());
+    y.push(other);
+    CohortData { labels, y, ..data }
+}
+
+// Merges the cohort data of several repos into one, aligning their snapshots by timestamp.
+// At each timestamp a repo contributes its latest snapshot at or before it, and nothing
+// before its first snapshot. Cohorts with the same label are summed across repos.
+// The commits of different repos don't line up, so the merged data has no commit metadata.
+pub fn merge_cohort_data(datasets: &[CohortData]) -> CohortData {
+    let mut ts: Vec<String> = datasets
+        .iter()
+        .flat_map(|data| data.ts.iter().cloned())
+        .collect();
+    ts.sort_by(|a, b| compare_timestamps(a, b));
+    ts.dedup();
+    let labels: Vec<String> = datasets
+        .iter()
+        .flat_map(|data| data.labels.iter().cloned())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let label_to_index: HashMap<&str, usize> = labels
+        .iter()
+        .enumerate()
+        .map(|(i, label)| (label.as_str(), i))
+        .collect();
+
+    let mut y = vec![vec![0i64; ts.len()]; labels.len()];
+    for data in datasets {
+        // The timestamps are sorted, so we walk both series together
+        let mut next_snapshot = 0;
+        for (ts_idx, timestamp) in ts.iter().enumerate() {
+            while next_snapshot < data.ts.len()
+                && compare_timestamps(&data.ts[next_snapshot], timestamp) != Ordering::Greater
+            {
+                next_snapshot += 1;
+            }
+            if next_snapshot == 0 {
+                continue;
+            }
+            for (label, row) in data.labels.iter().zip(&data.y) {
+                y[label_to_index[label.as_str()]][ts_idx] += row[next_snapshot - 1];
+            }
+        }
+    }
+    CohortData {
+        y,
+        ts,
+        labels,
+        commits: None,
+    }
+}
+
+// The number of whole weeks between the first timestamp and each of them
+pub fn weeks_since_start(ts: &[String]) -> Vec<String> {
+    let parse =
+        |timestamp: &str| NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S").ok();
+    let first = ts.first().and_then(|timestamp| parse(timestamp));
+    ts.iter()
+        .map(|timestamp| match (first, parse(timestamp)) {
+            (Some(first), Some(time)) => (time - first).num_weeks().to_string(),
+            _ => "0".to_string(),
+        })
+        .collect()
+}
+
+// Dates sort as strings, but week numbers from weeks_since_start have to sort as numbers
+fn compare_timestamps(a: &str, b: &str) -> Ordering {
+    match (a.parse::<i64>(), b.parse::<i64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+// Averages each cohort's series over a centered window of `window` snapshots, to even out jagged
+// weekly charts. At the edges the window shrinks to the snapshots there are, so no point is dropped.
+pub fn smooth_cohort_data(data: &CohortData, window: usize) -> CohortData {
+    let before = window.saturating_sub(1) / 2;
+    let after = window.saturating_sub(1) - before;
+    let y = data
+        .y
+        .iter()
+        .map(|row| {
+            (0..row.len())
+                .map(|i| {
+                    let values = &row[i.saturating_sub(before)..(i + after + 1).min(row.len())];
+                    (values.iter().sum::<i64>() as f64 / values.len() as f64).round() as i64
+                })
+                .collect()
+        })
+        .collect();
+    CohortData { y, ..data.clone() }
+}
+
+// The ways a cohorts.json can be broken, e.g. by an interrupted run or a bad merge, each as a
+// message for the user. An empty list means the data can be plotted.
+pub fn validate_cohort_data(data: &CohortData) -> Vec<String> {
+    let mut problems = Vec::new();
+    if data.labels.len() != data.y.len() {
+        problems.push(format!(
+            "There are {} labels for {} rows of y",
+            data.labels.len(),
+            data.y.len()
+        ));
+    }
+    let mut seen_labels = HashSet::new();
+    for label in &data.labels {
+        if !seen_labels.insert(label) {
+            problems.push(format!("The label \"{label}\" is used more than once"));
+        }
+    }
+    for (i, row) in data.y.iter().enumerate() {
+        let label = data.labels.get(i).map_or("unlabelled", String::as_str);
+        if row.len() != data.ts.len() {
+            problems.push(format!(
+                "Row {i} ({label}) has {} values for {} timestamps",
+                row.len(),
+                data.ts.len()
+            ));
+        }
+        if let Some((j, value)) = row.iter().enumerate().find(|(_, value)| **value < 0) {
+            problems.push(format!(
+                "Row {i} ({label}) has a negative value, {value}, at {}",
+                data.ts.get(j).map_or("?", String::as_str)
+            ));
         }
     }
-    CohortData { y, ts, labels }
+    if let Some(commits) = data
+        .commits
+        .as_ref()
+        .filter(|commits| commits.len() != data.ts.len())
+    {
+        problems.push(format!(
+            "There are {} commits for {} timestamps",
+            commits.len(),
+            data.ts.len()
+        ));
+    }
+    problems
+}
+
+// What's unusual in a cohorts.json but can still be plotted. Commit dates can go backwards, e.g. after
+// a rebase or with a skewed clock, and so can the timestamps of the snapshots.
+pub fn cohort_data_warnings(data: &CohortData) -> Vec<String> {
+    data.ts
+        .windows(2)
+        .filter(|pair| compare_timestamps(&pair[0], &pair[1]) == Ordering::Greater)
+        .map(|pair| {
+            format!(
+                "The timestamps go backwards: {} comes before {}",
+                pair[0], pair[1]
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_sort_labels_moves_the_rows_with_their_labels() {
+        let data = CohortData {
+            y: vec![vec![5, 1], vec![0, 7], vec![2, 3]],
+            ts: strings(&["2019-01-01", "2020-01-01"]),
+            labels: strings(&["2019", "2020", "2021"]),
+            commits: None,
+        };
+        let desc = sort_labels(data.clone(), LabelOrder::Desc);
+        assert_eq!(desc.labels, strings(&["2021", "2020", "2019"]));
+        assert_eq!(desc.y, vec![vec![2, 3], vec![0, 7], vec![5, 1]]);
+        let by_size = sort_labels(data.clone(), LabelOrder::Size);
+        assert_eq!(by_size.labels, strings(&["2020", "2021", "2019"]));
+        assert_eq!(by_size.y, vec![vec![0, 7], vec![2, 3], vec![5, 1]]);
+        assert_eq!(
+            sort_labels(data, LabelOrder::Asc).labels,
+            strings(&["2019", "2020", "2021"])
+        );
+    }
+
+    #[test]
+    fn test_keep_top_cohorts_sums_the_rest_into_other() {
+        let data = CohortData {
+            y: vec![vec![1, 2], vec![10, 20], vec![3, 0], vec![5, 5]],
+            ts: strings(&["2021-01-01", "2022-01-01"]),
+            labels: strings(&["2018", "2019", "2020", "2021"]),
+            commits: None,
+        };
+        let top = keep_top_cohorts(data.clone(), 2);
+        assert_eq!(top.labels, strings(&["2019", "2021", "Other"]));
+        assert_eq!(top.y, vec![vec![10, 20], vec![5, 5], vec![4, 2]]);
+        assert_eq!(keep_top_cohorts(data, 4).labels.len(), 4);
+    }
+
+    #[test]
+    fn test_year_label_uses_the_map_then_the_template() {
+        let mut options = FormatOptions::default();
+        assert_eq!(options.year_label(2020), "Code added in 2020");
+
+        options.label_template = Some("Code de {y}".to_string());
+        options.year_labels.insert(2019, "Avant la v1".to_string());
+        assert_eq!(options.year_label(2019), "Avant la v1");
+        assert_eq!(options.year_label(2020), "Code de 2020");
+    }
+
+    #[test]
+    fn test_merge_cohort_data_aligns_by_timestamp() {
+        let a = CohortData {
+            ts: strings(&["2020-01-01 00:00:00", "2020-02-01 00:00:00"]),
+            labels: strings(&["Code added in 2020"]),
+            y: vec![vec![10, 20]],
+            commits: None,
+        };
+        let b = CohortData {
+            ts: strings(&["2020-01-15 00:00:00"]),
+            labels: strings(&["Code added in 2019", "Code added in 2020"]),
+            y: vec![vec![5], vec![1]],
+            commits: None,
+        };
+        let merged = merge_cohort_data(&[a, b]);
+        assert_eq!(
+            merged.ts,
+            strings(&[
+                "2020-01-01 00:00:00",
+                "2020-01-15 00:00:00",
+                "2020-02-01 00:00:00"
+            ])
+        );
+        assert_eq!(
+            merged.labels,
+            strings(&["Code added in 2019", "Code added in 2020"])
+        );
+        assert_eq!(merged.y, vec![vec![0, 5, 5], vec![10, 11, 21]]);
+    }
+
+    #[test]
+    fn test_relative_time_counts_weeks_and_merges_in_order() {
+        let ts = strings(&[
+            "2019-03-01 12:00:00",
+            "2019-03-15 12:00:00",
+            "2020-03-01 12:00:00",
+        ]);
+        assert_eq!(weeks_since_start(&ts), strings(&["0", "2", "52"]));
+
+        let a = CohortData {
+            ts: strings(&["0", "2", "10"]),
+            labels: strings(&["Code added in 2019"]),
+            y: vec![vec![1, 2, 3]],
+            commits: None,
+        };
+        let b = CohortData {
+            ts: strings(&["0", "9"]),
+            labels: strings(&["Code added in 2015"]),
+            y: vec![vec![10, 20]],
+            commits: None,
+        };
+        let merged = merge_cohort_data(&[a, b]);
+        assert_eq!(merged.ts, strings(&["0", "2", "9", "10"]));
+        assert_eq!(merged.y, vec![vec![10, 10, 20, 20], vec![1, 2, 2, 3]]);
+    }
+
+    #[test]
+    fn test_smoothing_shrinks_the_window_at_the_edges() {
+        let data = CohortData {
+            ts: strings(&["a", "b", "c", "d"]),
+            labels: strings(&["Code added in 2020"]),
+            y: vec![vec![0, 3, 6, 9]],
+            commits: None,
+        };
+        assert_eq!(smooth_cohort_data(&data, 3).y, vec![vec![2, 3, 6, 8]]);
+        assert_eq!(smooth_cohort_data(&data, 1).y, data.y);
+        assert_eq!(smooth_cohort_data(&data, 3).ts, data.ts);
+    }
+
+    #[test]
+    fn test_validate_reports_each_problem() {
+        let mut data = CohortData {
+            ts: strings(&["2020-01-01 00:00:00", "2021-01-01 00:00:00"]),
+            labels: strings(&["Code added in 2020", "Code added in 2021"]),
+            y: vec![vec![5, 4], vec![0, 3]],
+            commits: None,
+        };
+        assert!(validate_cohort_data(&data).is_empty());
+
+        data.ts.reverse();
+        data.labels.pop();
+        data.y[1] = vec![-1, 3, 2];
+        assert_eq!(
+            validate_cohort_data(&data),
+            strings(&[
+                "There are 1 labels for 2 rows of y",
+                "Row 1 (unlabelled) has 3 values for 2 timestamps",
+                "Row 1 (unlabelled) has a negative value, -1, at 2021-01-01 00:00:00",
+            ])
+        );
+        // Timestamps that go backwards are only worth a warning
+        assert_eq!(
+            cohort_data_warnings(&data),
+            strings(&[
+                "The timestamps go backwards: 2021-01-01 00:00:00 comes before 2020-01-01 00:00:00"
+            ])
+        );
+    }
 }
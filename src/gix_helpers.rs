@@ -1,35 +1,172 @@
-use anyhow::Result;
-use chrono::{DateTime, Datelike, Utc};
+use crate::blame::{Keyable, LineDiffs, LineNumber};
+use crate::error::{BoxError, Result, TheseusError};
+use crate::options::{AnalysisOptions, Measure};
+use crate::repo_blame_snapshot::CommitChurn;
+use chrono::{DateTime, Datelike, FixedOffset, TimeZone, Utc, Weekday};
+use gix::bstr::ByteSlice;
 use gix::diff::blob::diff as blob_diff;
-use gix::{Commit, Repository, bstr::BStr};
-use std::{collections::BTreeMap, error::Error};
+use gix::diff::blob::intern::InternedInput;
+use gix::diff::blob::sources::byte_lines_with_terminator;
+use gix::{Commit, ObjectId, Repository, bstr::BStr};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Granularity {
-    Weekly,
+    // Weeks starting on the given day
+    Weekly(Weekday),
     Monthly,
     Yearly,
 }
 
+// A commit's time in the time zone it was made in, e.g. 23:30 on Dec 31 for a commit made then
+// in New York, even though it's already the next year in UTC. Both the time buckets and the
+// cohort years come from it, so they always agree with each other and with what the author saw.
+pub fn local_commit_time(time: gix::date::Time) -> DateTime<FixedOffset> {
+    let offset = FixedOffset::east_opt(time.offset).unwrap_or(FixedOffset::east_opt(0).unwrap());
+    DateTime::from_timestamp(time.seconds, 0)
+        .unwrap()
+        .with_timezone(&offset)
+}
+
+// The key of the time bucket a commit falls in. Keys sort in time order.
+// Weeks are keyed by the date of their first day, e.g. "2024-03-03" for the week starting
+// on Sunday 2024-03-03.
+pub fn period_key<Tz: TimeZone>(datetime: DateTime<Tz>, granularity: Granularity) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    match granularity {
+        Granularity::Weekly(week_start) => {
+            let num_days = (datetime.weekday().num_days_from_monday() + 7
+                - week_start.num_days_from_monday())
+                % 7;
+            let start_of_week = datetime - chrono::Duration::days(num_days.into());
+            start_of_week.format("%Y-%m-%d").to_string()
+        }
+        Granularity::Monthly => datetime.format("%Y-%m").to_string(),
+        Granularity::Yearly => datetime.format("%Y").to_string(),
+    }
+}
+
+// The commits reachable from `tip` but not from `base`, like git's `base..tip`.
+// Without a base, that's the whole history of the tip.
+#[derive(Debug, Clone, Copy)]
+pub struct RevisionRange {
+    pub base: Option<ObjectId>,
+    pub tip: ObjectId,
+}
+
+impl RevisionRange {
+    // HEAD usually points at a commit, but a detached HEAD can point at an annotated tag,
+    // or in odd states at some other object
+    pub fn head(repo: &Repository) -> Result<Self> {
+        let head = repo.head_id().map_err(|_| TheseusError::EmptyRepo)?;
+        let tip = peel_to_commit(head, "HEAD")?;
+        Ok(Self { base: None, tip })
+    }
+
+    // Resolves a range like "v1.0..v2.0". As in git, a missing end means HEAD,
+    // and a single revision means its whole history.
+    pub fn parse(repo: &Repository, spec: &str) -> Result<Self> {
+        if spec.contains("...") {
+            return Err(TheseusError::UnsupportedRange(spec.to_string()));
+        }
+        match spec.split_once("..") {
+            Some((base, tip)) => Ok(Self {
+                base: Some(resolve_commit(repo, base)?),
+                tip: if tip.is_empty() {
+                    Self::head(repo)?.tip
+                } else {
+                    resolve_commit(repo, tip)?
+                },
+            }),
+            None => Ok(Self {
+                base: None,
+                tip: resolve_commit(repo, spec)?,
+            }),
+        }
+    }
+}
+
+pub(crate) fn resolve_commit(repo: &Repository, spec: &str) -> Result<ObjectId> {
+    let id = repo
+        .rev_parse_single(spec)
+        .map_err(|e| revision_not_found(spec, e.into()))?;
+    peel_to_commit(id, spec)
+}
+
+fn peel_to_commit(id: gix::Id<'_>, spec: &str) -> Result<ObjectId> {
+    let commit = id
+        .object()
+        .map_err(|e| revision_not_found(spec, e.into()))?
+        .peel_to_commit()
+        .map_err(|e| revision_not_found(spec, e.into()))?;
+    Ok(commit.id)
+}
+
+// The time of the commit a tag points to, through an annotated tag if it's one
+pub fn tag_commit_time(repo: &Repository, tag: &str) -> Result<DateTime<Utc>> {
+    let mut reference = repo
+        .try_find_reference(format!("refs/tags/{tag}").as_str())
+        .map_err(TheseusError::odb)?
+        .ok_or_else(|| TheseusError::TagNotFound(tag.to_string()))?;
+    let id = reference
+        .peel_to_id_in_place()
+        .map_err(|e| revision_not_found(tag, e.into()))?;
+    let id = peel_to_commit(id, tag)?;
+    let time = repo
+        .find_commit(id)
+        .map_err(TheseusError::odb)?
+        .time()
+        .map_err(TheseusError::odb)?;
+    Ok(DateTime::from_timestamp(time.seconds, 0).unwrap())
+}
+
+fn revision_not_found(spec: &str, source: BoxError) -> TheseusError {
+    TheseusError::RevisionNotFound {
+        spec: spec.to_string(),
+        source,
+    }
+}
+
+// The first-parent commits of the range, newest first, read as the walk goes. The commit-graph
+// file makes the walk faster. gix only uses it if it loads, so a repo without one, or with a stale
+// or corrupt one, is walked without it.
+pub fn first_parent_commits(
+    repo: &Repository,
+    range: RevisionRange,
+    use_commit_graph: bool,
+) -> Result<impl Iterator<Item = Result<Commit<'_>>> + '_> {
+    let walk = repo
+        .rev_walk([range.tip])
+        .with_hidden(range.base)
+        .first_parent_only()
+        .use_commit_graph(use_commit_graph)
+        .all()
+        .map_err(TheseusError::traversal)?;
+    Ok(walk.map(|info| {
+        info.map_err(TheseusError::traversal)?
+            .object()
+            .map_err(TheseusError::odb)
+    }))
+}
+
 pub fn list_commits_with_granularity(
     repo: &Repository,
+    range: RevisionRange,
     granularity: Granularity,
     start: Option<DateTime<Utc>>,
     end: Option<DateTime<Utc>>,
-) -> Result<Vec<Commit>, Box<dyn Error>> {
-    let revwalk = repo
-        .rev_walk(repo.head_id())
-        .first_parent_only()
-        .use_commit_graph(true)
-        .all()?;
-
+    use_commit_graph: bool,
+) -> Result<Vec<Commit<'_>>> {
     let mut commits_by_period = BTreeMap::new();
 
-    for info_result in revwalk {
-        let info = info_result?;
-        let commit = info.object().unwrap();
-        let commit_time = commit.time()?;
+    for commit in first_parent_commits(repo, range, use_commit_graph)? {
+        let commit = commit?;
+        let commit_time = commit.time().map_err(TheseusError::odb)?;
         let datetime = DateTime::from_timestamp(commit_time.seconds, 0).unwrap();
+        let local_datetime = local_commit_time(commit_time);
 
         // If the commit is before the start time, end the loop early
         if let Some(start) = start {
@@ -45,51 +182,217 @@ pub fn list_commits_with_granularity(
             }
         }
 
-        let key = match granularity {
-            Granularity::Weekly => {
-                let num_days = datetime.weekday().num_days_from_sunday();
-                let start_of_week = datetime - chrono::Duration::days(num_days.into());
-                start_of_week.format("%Y-%m-%d").to_string()
-            }
-            Granularity::Monthly => datetime.format("%Y-%m").to_string(),
-            Granularity::Yearly => datetime.format("%Y").to_string(),
-        };
-
-        commits_by_period.entry(key).or_insert_with(|| commit);
+        let key = period_key(local_datetime, granularity);
+        commits_by_period
+            .entry(key)
+            .or_insert_with(|| (commit_time, commit));
     }
 
     let mut commits = commits_by_period.into_values().collect::<Vec<_>>();
-    commits.sort_by_key(|c| c.time().unwrap());
+    commits.sort_by_key(|(commit_time, _)| *commit_time);
+    Ok(commits.into_iter().map(|(_, commit)| commit).collect())
+}
+
+// Every first-parent commit of the range, oldest first, for when the changes of each commit have to
+// be told apart instead of bucketed
+pub fn list_every_commit(
+    repo: &Repository,
+    range: RevisionRange,
+    use_commit_graph: bool,
+) -> Result<Vec<Commit<'_>>> {
+    let mut commits =
+        first_parent_commits(repo, range, use_commit_graph)?.collect::<Result<Vec<_>>>()?;
+    commits.reverse();
     Ok(commits)
 }
 
+// Keeps every nth commit, plus the last one so the final snapshot still reflects HEAD.
+// Each kept commit is diffed against the previous kept one, so skipping commits only
+// makes the diffs bigger, it doesn't lose any changes.
+pub fn sample_every_nth<T>(commits: Vec<T>, n: usize) -> Vec<T> {
+    if n <= 1 {
+        return commits;
+    }
+    let last_idx = commits.len().saturating_sub(1);
+    commits
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| i % n == 0 || *i == last_idx)
+        .map(|(_, commit)| commit)
+        .collect()
+}
+
+// Keeps the last n commits. The first kept commit is diffed against an empty tree, so all the code
+// it has is credited to it, like the code at the base of a range.
+pub fn keep_most_recent<T>(mut commits: Vec<T>, n: usize) -> Vec<T> {
+    commits.drain(..commits.len().saturating_sub(n));
+    commits
+}
+
+// The number of lines of a file, split the way the diff splits them: a last line without a trailing
+// newline counts as a line, and an empty file has none. Added files must be counted like this so
+// the hunks of their later diffs line up with their blame.
+pub fn count_lines(data: &[u8]) -> LineNumber {
+    byte_lines_with_terminator(data).count() as LineNumber
+}
+
+// The size of a file in the measure of the analysis. With ignore_eol, a CRLF counts as one byte,
+// like the diffs see it.
+pub fn measure_size(data: &[u8], options: &AnalysisOptions) -> LineNumber {
+    match options.measure {
+        Measure::Lines => count_lines(data),
+        Measure::Bytes if options.ignore_eol => {
+            (data.len() - data.find_iter(b"\r\n").count()) as LineNumber
+        }
+        Measure::Bytes => data.len() as LineNumber,
+    }
+}
+
+// Turns the line ranges of the hunks into the byte ranges of those lines, for a blame over bytes
+fn to_byte_ranges<K: Keyable>(line_diffs: LineDiffs<K>, old: &[u8], new: &[u8]) -> LineDiffs<K> {
+    let line_offsets = |data: &[u8]| -> Vec<LineNumber> {
+        std::iter::once(0)
+            .chain(byte_lines_with_terminator(data).scan(0, |end, line| {
+                *end += line.len() as LineNumber;
+                Some(*end)
+            }))
+            .collect()
+    };
+    let (old_offsets, new_offsets) = (line_offsets(old), line_offsets(new));
+    line_diffs
+        .into_iter()
+        .map(|(before, after, cohort)| {
+            (
+                old_offsets[before.start as usize]..old_offsets[before.end as usize],
+                new_offsets[after.start as usize]..new_offsets[after.end as usize],
+                cohort,
+            )
+        })
+        .collect()
+}
+
 // Sets up the gix machinery to do a blob diff.
-// Returns the line diffs as a vec of (delete_range, insert_range, commit_key)
-pub fn get_blob_diff(
+// Returns the line diffs as a vec of (delete_range, insert_range, commit_key), with every
+// hunk attributed to the given cohort key.
+// Lines are compared with their line endings, so a line that gains or loses its newline or its \r
+// is changed. With ignore_eol, CRLF and LF line endings compare equal, so a commit that only
+// converts line endings doesn't re-attribute every line of the file.
+// With Measure::Bytes, the hunks are byte ranges instead.
+pub fn get_blob_diff<K: Keyable>(
     platform_borrow: &mut gix::diff::blob::Platform,
     previous_id: gix::ObjectId,
     id: gix::ObjectId,
     location: &BStr,
     objects: &gix::odb::Handle,
-    commit_key: usize,
-) -> Result<Vec<(std::ops::Range<u32>, std::ops::Range<u32>, usize)>> {
-    platform_borrow.set_resource(
+    commit_key: K,
+    options: &AnalysisOptions,
+) -> Result<LineDiffs<K>> {
+    platform_borrow
+        .set_resource(
+            previous_id,
+            gix::object::tree::EntryKind::Blob,
+            location,
+            gix::diff::blob::ResourceKind::OldOrSource,
+            objects,
+        )
+        .map_err(|e| TheseusError::diff_failed(location, e))?;
+    platform_borrow
+        .set_resource(
+            id,
+            gix::object::tree::EntryKind::Blob,
+            location,
+            gix::diff::blob::ResourceKind::NewOrDestination,
+            objects,
+        )
+        .map_err(|e| TheseusError::diff_failed(location, e))?;
+
+    let outcome = platform_borrow
+        .prepare_diff()
+        .map_err(|e| TheseusError::diff_failed(location, e))?;
+    // A binary file has no lines
+    Ok(diff_bytes(
+        outcome.old.data.as_slice().unwrap_or_default(),
+        outcome.new.data.as_slice().unwrap_or_default(),
+        commit_key,
+        options,
+    ))
+}
+
+// The lines a change to a blob adds and removes, for callers that don't need the hunks themselves
+pub fn get_blob_churn(
+    platform_borrow: &mut gix::diff::blob::Platform,
+    previous_id: gix::ObjectId,
+    id: gix::ObjectId,
+    location: &BStr,
+    objects: &gix::odb::Handle,
+    options: &AnalysisOptions,
+) -> Result<CommitChurn> {
+    let line_diffs = get_blob_diff(
+        platform_borrow,
         previous_id,
-        gix::object::tree::EntryKind::Blob,
-        location,
-        gix::diff::blob::ResourceKind::OldOrSource,
-        objects,
-    )?;
-    platform_borrow.set_resource(
         id,
-        gix::object::tree::EntryKind::Blob,
         location,
-        gix::diff::blob::ResourceKind::NewOrDestination,
         objects,
+        0usize,
+        options,
     )?;
+    Ok(CommitChurn::of_line_diffs(&line_diffs))
+}
 
-    let outcome = platform_borrow.prepare_diff()?;
-    let input = outcome.interned_input();
+// The size of an added blob as get_blob_diff will see it, so additions and later modifications
+// agree on what a line is: it goes through the same conversion, e.g. a textconv driver, and a
+// binary file has no lines.
+pub fn get_blob_size(
+    platform_borrow: &mut gix::diff::blob::Platform,
+    id: gix::ObjectId,
+    location: &BStr,
+    objects: &gix::odb::Handle,
+    options: &AnalysisOptions,
+) -> Result<LineNumber> {
+    // A null id is an empty resource, as if the file was diffed against nothing
+    for (id, kind) in [
+        (id.kind().null(), gix::diff::blob::ResourceKind::OldOrSource),
+        (id, gix::diff::blob::ResourceKind::NewOrDestination),
+    ] {
+        platform_borrow
+            .set_resource(
+                id,
+                gix::object::tree::EntryKind::Blob,
+                location,
+                kind,
+                objects,
+            )
+            .map_err(|e| TheseusError::diff_failed(location, e))?;
+    }
+    let outcome = platform_borrow
+        .prepare_diff()
+        .map_err(|e| TheseusError::diff_failed(location, e))?;
+    Ok(measure_size(
+        outcome.new.data.as_slice().unwrap_or_default(),
+        options,
+    ))
+}
+
+// Like get_blob_diff, for two versions of a file we already have in memory, e.g. one from the worktree
+pub fn diff_bytes<K: Keyable>(
+    old: &[u8],
+    new: &[u8],
+    commit_key: K,
+    options: &AnalysisOptions,
+) -> LineDiffs<K> {
+    // Normalizing only drops the \r of a \r\n, so line numbers are unaffected.
+    let (old, new) = if options.ignore_eol {
+        (
+            Cow::Owned(normalize_eol(old)),
+            Cow::Owned(normalize_eol(new)),
+        )
+    } else {
+        (Cow::Borrowed(old), Cow::Borrowed(new))
+    };
+    let input = InternedInput::new(
+        byte_lines_with_terminator(&old),
+        byte_lines_with_terminator(&new),
+    );
     let mut line_diffs = Vec::new();
     blob_diff(
         gix::diff::blob::Algorithm::Myers,
@@ -98,5 +401,146 @@ pub fn get_blob_diff(
             line_diffs.push((before, after, commit_key));
         },
     );
-    Ok(line_diffs)
+    match options.measure {
+        Measure::Lines => line_diffs,
+        Measure::Bytes => to_byte_ranges(line_diffs, &old, &new),
+    }
+}
+
+fn normalize_eol(data: &[u8]) -> Vec<u8> {
+    data.replace(b"\r\n", b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_every_nth_keeps_first_and_last() {
+        let commits: Vec<u32> = (0..10).collect();
+        assert_eq!(sample_every_nth(commits.clone(), 1), commits);
+        assert_eq!(sample_every_nth(commits.clone(), 3), vec![0, 3, 6, 9]);
+        assert_eq!(sample_every_nth(commits.clone(), 4), vec![0, 4, 8, 9]);
+        assert_eq!(sample_every_nth(commits, 20), vec![0, 9]);
+        assert_eq!(sample_every_nth(Vec::<u32>::new(), 3), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_keep_most_recent_caps_the_commits() {
+        let commits: Vec<u32> = (0..10).collect();
+        assert_eq!(keep_most_recent(commits.clone(), 3), vec![7, 8, 9]);
+        assert_eq!(keep_most_recent(commits.clone(), 20), commits);
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_period_key_weeks_start_on_sunday() {
+        let sunday_weeks = Granularity::Weekly(Weekday::Sun);
+        // 2024-03-03 is a Sunday
+        for day in [
+            "2024-03-03T00:00:00Z",
+            "2024-03-06T12:00:00Z",
+            "2024-03-09T23:59:59Z",
+        ] {
+            assert_eq!(period_key(utc(day), sunday_weeks), "2024-03-03");
+        }
+        assert_eq!(
+            period_key(utc("2024-03-10T00:00:00Z"), sunday_weeks),
+            "2024-03-10"
+        );
+        // A week can straddle two years
+        assert_eq!(
+            period_key(utc("2025-01-01T12:00:00Z"), sunday_weeks),
+            "2024-12-29"
+        );
+    }
+
+    #[test]
+    fn test_period_key_weeks_can_start_on_monday() {
+        let monday_weeks = Granularity::Weekly(Weekday::Mon);
+        // 2024-03-04 is a Monday
+        assert_eq!(
+            period_key(utc("2024-03-04T00:00:00Z"), monday_weeks),
+            "2024-03-04"
+        );
+        assert_eq!(
+            period_key(utc("2024-03-10T23:59:59Z"), monday_weeks),
+            "2024-03-04"
+        );
+        assert_eq!(
+            period_key(utc("2024-03-03T12:00:00Z"), monday_weeks),
+            "2024-02-26"
+        );
+    }
+
+    #[test]
+    fn test_period_key_months_and_years() {
+        let datetime = utc("2024-03-06T12:00:00Z");
+        assert_eq!(period_key(datetime, Granularity::Monthly), "2024-03");
+        assert_eq!(period_key(datetime, Granularity::Yearly), "2024");
+    }
+
+    #[test]
+    fn test_count_lines_matches_the_diff_tokens() {
+        assert_eq!(count_lines(b""), 0);
+        assert_eq!(count_lines(b"a"), 1);
+        assert_eq!(count_lines(b"a\n"), 1);
+        assert_eq!(count_lines(b"a\nb"), 2);
+        assert_eq!(count_lines(b"a\n\n"), 2);
+        assert_eq!(count_lines(b"a\r\nb\r\n"), 2);
+
+        // The hunks never reach past the line counts of either side
+        let (old, new): (&[u8], &[u8]) = (b"a\nb", b"a\nb\nc");
+        let line_diffs = diff_bytes(old, new, 0u32, &AnalysisOptions::default());
+        assert_eq!(line_diffs, vec![(1..2, 1..3, 0)]);
+        assert!(line_diffs.iter().all(|(before, after, _)| {
+            before.end <= count_lines(old) && after.end <= count_lines(new)
+        }));
+    }
+
+    #[test]
+    fn test_byte_measure_turns_hunks_into_byte_ranges() {
+        let bytes = AnalysisOptions {
+            measure: Measure::Bytes,
+            ..Default::default()
+        };
+        let (old, new): (&[u8], &[u8]) = (b"ab\ncd\n", b"ab\nxyz\ncd\n");
+        assert_eq!(measure_size(old, &bytes), 6);
+        assert_eq!(diff_bytes(old, new, 0u32, &bytes), vec![(3..3, 3..7, 0)]);
+
+        let ignore_eol = AnalysisOptions {
+            ignore_eol: true,
+            ..bytes
+        };
+        assert_eq!(measure_size(b"ab\r\ncd\r\n", &ignore_eol), 6);
+    }
+
+    #[test]
+    fn test_a_corrupt_commit_graph_is_walked_without() {
+        let repo = crate::test_utils::TestRepo::new();
+        repo.write("main.rs", "a\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.git(&["commit-graph", "write", "--reachable"]);
+        std::fs::write(
+            std::path::Path::new(repo.path()).join(".git/objects/info/commit-graph"),
+            b"not a commit graph",
+        )
+        .unwrap();
+
+        let gix_repo = gix::open(repo.path()).unwrap();
+        let range = RevisionRange::head(&gix_repo).unwrap();
+        for use_commit_graph in [true, false] {
+            assert_eq!(
+                list_every_commit(&gix_repo, range, use_commit_graph)
+                    .unwrap()
+                    .len(),
+                2
+            );
+        }
+    }
 }
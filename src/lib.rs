@@ -1,11 +1,33 @@
 pub mod actions;
 pub mod blame;
+pub mod checkpoint;
+pub mod churn;
+pub mod clone;
+pub mod collectors;
+pub mod commit_cache;
+pub mod commit_results;
+pub mod config;
+pub mod dry_run;
+pub mod error;
+pub mod file_history;
 pub mod file_types;
 pub mod formatter;
 pub mod gix_helpers;
+pub mod options;
 pub mod plot;
+pub mod profile;
+#[cfg(feature = "record")]
+pub mod record;
 pub mod repo_blame_snapshot;
+pub mod snapshot_stream;
+pub mod summary;
+pub mod survival;
+#[cfg(test)]
+mod test_utils;
 pub mod theseus;
+pub mod worktree;
 
+pub use error::TheseusError;
+pub use options::AnalysisOptions;
 pub use repo_blame_snapshot::RepositoryBlameSnapshot;
-pub use theseus::run_theseus;
+pub use theseus::{analyze, run_theseus, run_theseus_on_commits};
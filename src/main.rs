@@ -1,11 +1,27 @@
 use std::{
     fs::{self, File},
+    io::{BufWriter, Write},
     path::{Path, PathBuf},
+    time::Instant,
 };
 
-use anyhow::Result;
-use clap::Parser;
-use gix_of_theseus::{formatter, plot, theseus};
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use gix_of_theseus::clone::{self, TempClone};
+use gix_of_theseus::collectors::tarball::{self, Release};
+use gix_of_theseus::config::ProjectConfig;
+use gix_of_theseus::dry_run::{self, DryRunReport};
+use gix_of_theseus::formatter::{
+    CohortBoundary, CohortData, CohortGranularity, FormatOptions, LabelOrder,
+};
+use gix_of_theseus::options::{AnalysisOptions, Measure, Verbosity};
+use gix_of_theseus::plot::{ImageFormat, Palette, PlotOptions, PlotStyle, Theme};
+use gix_of_theseus::profile::PhaseTimings;
+use gix_of_theseus::summary::RunSummary;
+use gix_of_theseus::theseus::{TheseusResult, repo_name};
+use gix_of_theseus::{churn, file_history, formatter, plot, survival, theseus};
+use rayon::prelude::*;
 
 #[derive(Debug, clap::Parser)]
 #[clap(
@@ -16,6 +32,12 @@ use gix_of_theseus::{formatter, plot, theseus};
 struct Cli {
     #[clap(subcommand)]
     subcommand: Subcommands,
+    /// Don't show progress or status messages, only print the final output path
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print extra status messages
+    #[clap(short, long, global = true)]
+    verbose: bool,
 }
 
 #[derive(Debug, Parser)]
@@ -24,6 +46,13 @@ pub struct PlotArgs {
     input_file: String,
     #[clap(short, long)]
     output_file: String,
+    #[clap(flatten)]
+    text: PlotTextArgs,
+}
+#[derive(Debug, Parser)]
+pub struct ValidateArgs {
+    #[clap(short, long)]
+    input_file: String,
 }
 #[derive(Debug, Parser)]
 pub struct AnalyzeArgs {
@@ -34,57 +63,984 @@ pub struct AnalyzeArgs {
 }
 #[derive(Debug, Parser)]
 struct TheseusArgs {
-    repo_path: String,
+    #[clap(required_unless_present = "clone_url")]
+    repo_path: Option<String>,
+    /// Clone the repo at this URL to a temp dir and analyze it, instead of a local repo.
+    /// The temp dir is deleted once the analysis is done
+    #[clap(long = "clone", value_name = "URL", conflicts_with = "repo_path")]
+    clone_url: Option<String>,
+    /// Where to write the results, by default a directory named after the repo.
+    /// `-` writes the cohorts JSON to stdout instead, without writing any files or plotting
     #[clap(short, long)]
     outdir: Option<PathBuf>,
+    /// Prefix the names of the output files, so different runs can share an outdir
+    #[clap(long)]
+    prefix: Option<String>,
     #[clap(short, long)]
     no_plot: bool,
+    /// Only print what would be analyzed, without analyzing anything or writing any files
+    #[clap(long)]
+    dry_run: bool,
+    /// Print how long each phase of the run took on stderr: listing the commits, loading their trees, diffing them, diffing the changed files and accumulating their blame, formatting and plotting
+    #[clap(long)]
+    profile: bool,
+    /// Collapse the cohorts of the commits up to this revision, e.g. a release tag, into a
+    /// single one, to compare the code written before it with the code written since
+    #[clap(long, value_name = "REV")]
+    boundary: Option<String>,
+    /// The label of the collapsed cohort, "Before REV" or "After REV" by default
+    #[clap(long, requires = "boundary")]
+    boundary_label: Option<String>,
+    /// Collapse the cohorts of the commits after the boundary instead of the ones up to it
+    #[clap(long, requires = "boundary")]
+    collapse_after: bool,
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+    #[clap(flatten)]
+    text: PlotTextArgs,
+}
+impl TheseusArgs {
+    fn to_format_options(&self, repo_path: &str, verbosity: Verbosity) -> Result<FormatOptions> {
+        let mut format_options = self.analysis.to_format_options(verbosity)?;
+        if let Some(rev) = &self.boundary {
+            let side = if self.collapse_after {
+                "After"
+            } else {
+                "Before"
+            };
+            format_options.boundary = Some(CohortBoundary {
+                time: theseus::commit_time(repo_path, rev)?,
+                label: self
+                    .boundary_label
+                    .clone()
+                    .unwrap_or_else(|| format!("{side} {rev}")),
+                collapse_after: self.collapse_after,
+            });
+        }
+        Ok(format_options)
+    }
+
+    // The path and name of the repo to analyze. With --clone, the path is a temp dir that is
+    // deleted when the returned TempClone is dropped, even if the analysis fails.
+    fn repo(&self, verbosity: Verbosity) -> Result<(String, String, Option<TempClone>)> {
+        match (&self.clone_url, &self.repo_path) {
+            (Some(url), _) => {
+                if !verbosity.is_quiet() {
+                    eprintln!("Cloning {url}");
+                }
+                let cloned = clone::clone_to_temp_dir(url)?;
+                let repo_path = cloned.path().to_string();
+                Ok((repo_path, clone::repo_name_from_url(url), Some(cloned)))
+            }
+            (None, Some(repo_path)) => Ok((repo_path.clone(), repo_name(repo_path), None)),
+            (None, None) => unreachable!("clap requires a repo path or --clone"),
+        }
+    }
+}
+#[derive(Debug, Parser)]
+struct AnalyzeManyArgs {
+    #[clap(required = true)]
+    repo_paths: Vec<String>,
+    #[clap(short, long, default_value = "merged")]
+    outdir: PathBuf,
+    /// Prefix the names of the output files, so different runs can share an outdir
+    #[clap(long)]
+    prefix: Option<String>,
+    #[clap(short, long)]
+    no_plot: bool,
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+    #[clap(flatten)]
+    text: PlotTextArgs,
+}
+#[derive(Debug, Parser)]
+struct AnalyzeTarballsArgs {
+    /// The source archive of each release and its date, as DATE=ARCHIVE, e.g. 2019-06-01=foo-1.0.tar.gz
+    #[clap(required = true, value_name = "DATE=ARCHIVE")]
+    releases: Vec<Release>,
+    #[clap(short, long, default_value = "releases")]
+    outdir: PathBuf,
+    /// Prefix the names of the output files, so different runs can share an outdir
+    #[clap(long)]
+    prefix: Option<String>,
+    #[clap(short, long)]
+    no_plot: bool,
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+    #[clap(flatten)]
+    text: PlotTextArgs,
+}
+#[derive(Debug, Parser)]
+struct AnalyzeFileArgs {
+    repo_path: String,
+    /// The path of the file within the repo, e.g. src/main.rs
+    path: String,
+    #[clap(short, long, default_value = "file")]
+    outdir: PathBuf,
+    /// Prefix the names of the output files, so different runs can share an outdir
+    #[clap(long)]
+    prefix: Option<String>,
+    #[clap(short, long)]
+    no_plot: bool,
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+    #[clap(flatten)]
+    text: PlotTextArgs,
+}
+// The flags that set how the chart looks and the text around it, shared by every subcommand that plots
+#[derive(Debug, clap::Args)]
+struct PlotTextArgs {
+    /// Title of the chart, defaults to the repo name
+    #[clap(long)]
+    title: Option<String>,
+    /// Subtitle shown under the title
+    #[clap(long)]
+    subtitle: Option<String>,
+    /// Caption shown under the chart. Without a value, says how many commits were analyzed and over which dates
+    #[clap(long, num_args = 0..=1, default_missing_value = "")]
+    caption: Option<String>,
+    /// Color theme of the chart: light (the default) or dark
+    #[clap(long)]
+    theme: Option<Theme>,
+    /// Colors of the cohorts: cividis (the default), which color-blind viewers can tell apart, or distinct, the older rainbow of contrasting colors
+    #[clap(long)]
+    palette: Option<Palette>,
+    /// Reverse the palette, so the oldest cohorts get the lightest colors instead of the darkest
+    #[clap(long)]
+    palette_reverse: bool,
+    /// Color each cohort by its year rather than its position, so a year has the same color in the charts of every repo
+    #[clap(long)]
+    stable_colors: bool,
+    /// Plot a moving average over this many snapshots, to even out jagged weekly charts. cohorts.json stays raw
+    #[clap(long, value_name = "WINDOW")]
+    smooth: Option<usize>,
+    /// Read the look of the chart from a JSON file: its text, theme, palette or colors, font, size and legend position. The flags override it
+    #[clap(long, value_name = "FILE")]
+    theme_config: Option<PathBuf>,
+    /// Format of the chart image: png (the default) or svg, a vector image that stays sharp in print.
+    /// With `plot`, the extension of the output file decides by default
+    #[clap(long)]
+    format: Option<ImageFormat>,
+    // The [plot] table of the project config, used when there's no --theme-config
+    #[clap(skip)]
+    project_style: Option<PlotStyle>,
+}
+
+impl PlotTextArgs {
+    // The name of the chart image the analyze subcommands write next to cohorts.json
+    fn image_file_name(&self) -> String {
+        format!("stackplot.{}", self.format.unwrap_or_default().as_str())
+    }
+
+    fn to_plot_options(
+        &self,
+        default_title: Option<String>,
+        data: &CohortData,
+    ) -> Result<PlotOptions> {
+        let style = match &self.theme_config {
+            Some(path) => PlotStyle::from_file(path)?,
+            None => self.project_style.clone().unwrap_or_default(),
+        };
+        Ok(PlotOptions {
+            title: self.title.clone().or(style.title.clone()).or(default_title),
+            subtitle: self.subtitle.clone().or(style.subtitle.clone()),
+            caption: self
+                .caption
+                .clone()
+                .or(style.caption.clone())
+                .map(|caption| {
+                    if caption.is_empty() {
+                        plot::default_caption(data)
+                    } else {
+                        caption
+                    }
+                }),
+            theme: self.theme.or(style.theme).unwrap_or_default(),
+            palette: self.palette.or(style.palette).unwrap_or_default(),
+            palette_reverse: self.palette_reverse || style.palette_reverse.unwrap_or(false),
+            stable_colors: self.stable_colors,
+            smooth: self.smooth,
+            format: self.format,
+            style,
+        })
+    }
+}
+// The flags that map to AnalysisOptions, shared by the analyze subcommands
+#[derive(Debug, clap::Args)]
+struct AnalysisArgs {
+    /// Read the project's defaults for these flags from this TOML file, instead of the gix-of-theseus.toml at the root of the repo
+    #[clap(long, value_name = "FILE")]
+    config: Option<PathBuf>,
     #[clap(short, long, default_value = "false")]
     all_filetypes: bool,
+    /// Only analyze the files whose path matches this glob, e.g. "src/**". Can be given several times
+    #[clap(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Only analyze the files with these extensions, e.g. rs,toml, instead of the ones that look like source code
+    #[clap(
+        long,
+        value_name = "EXTS",
+        value_delimiter = ',',
+        conflicts_with = "all_filetypes"
+    )]
+    only_extensions: Vec<String>,
+    /// Only analyze the source files of these languages, e.g. rust,python
+    #[clap(
+        long,
+        value_name = "LANGS",
+        value_delimiter = ',',
+        conflicts_with = "all_filetypes"
+    )]
+    only_languages: Vec<String>,
+    /// Read the filetypes to analyze from this file, one glob like "*.rs" per line with # comments,
+    /// instead of the built-in list
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["all_filetypes", "only_extensions", "only_languages"]
+    )]
+    filetypes: Option<PathBuf>,
+    /// Treat CRLF and LF line endings as equal when diffing files
+    #[clap(long)]
+    ignore_eol: bool,
+    /// What the cohorts count: lines, or bytes so long lines weigh more. Diffs stay line by line, so a changed line counts all its bytes as new
+    #[clap(long, default_value = "lines")]
+    measure: Measure,
+    /// Also look for files copied from a file changed in the same commit. Copies still count as new code
+    #[clap(long)]
+    detect_copies: bool,
+    /// Only look for renames and copies in commits that add or delete at most N files, 0 for no limit. Defaults to 1000
+    #[clap(long, value_name = "N")]
+    rename_limit: Option<usize>,
+    /// Only analyze every Nth weekly commit, for a faster but coarser result
+    #[clap(long, value_name = "N")]
+    sample: Option<usize>,
+    /// Only analyze the N most recent weekly commits, for a quick preview. The code already there at the first of them is credited to it
+    #[clap(long, value_name = "N")]
+    max_commits: Option<usize>,
+    /// Blame the code already there at the first analyzed commit through the history before it, like git blame, so it goes to the years it was written in. Slow on big repos
+    #[clap(long, conflicts_with_all = ["checkpoint", "first_commit_cohort"])]
+    accurate_baseline: bool,
+    /// The day the weekly buckets start on, e.g. monday. Defaults to sunday
+    #[clap(long, value_name = "DAY")]
+    week_start: Option<chrono::Weekday>,
+    /// Cache the list of commits to analyze in this directory, so later runs on the same HEAD don't re-walk the history
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+    /// Put the code of the first analyzed commit in its own cohort with this label, e.g. "Pre-history", instead of counting it in that commit's year
+    #[clap(long, value_name = "LABEL")]
+    first_commit_cohort: Option<String>,
+    /// Add the short hash and the first line of the message of each analyzed commit to cohorts.json
+    #[clap(long)]
+    commit_metadata: bool,
+    /// Write each commit's stats to a temp file instead of keeping them in memory, for very long histories
+    #[clap(long)]
+    spool_results: bool,
+    /// Don't credit merge commits for the lines they bring in, credit them to when the merged branch was last committed to
+    #[clap(long, conflicts_with = "authors")]
+    skip_merges: bool,
+    /// Walk the history without the commit-graph file, e.g. when it's stale. A missing or corrupt one is ignored anyway
+    #[clap(long)]
+    no_commit_graph: bool,
+    /// Only credit the commits authored with this email, e.g. to see how much of a team's code survives. Can be given several times. Every commit is then analyzed instead of weekly ones, and the lines of the other authors are left out
+    #[clap(long = "author", value_name = "EMAIL", conflicts_with = "checkpoint")]
+    authors: Vec<String>,
+    /// Leave out this commit, e.g. a bulk import, given as a hash or any revision. Its lines are credited to the next analyzed commit instead. Can be given several times
+    #[clap(long = "exclude-commit", value_name = "REV")]
+    exclude_commits: Vec<String>,
+    /// Leave out files that have fewer than N lines when they're added, along with their later changes
+    #[clap(long, value_name = "N", default_value = "0")]
+    min_lines: u32,
+    /// Add the uncommitted changes in the worktree as a last data point
+    #[clap(long)]
+    include_worktree: bool,
+    /// Only analyze the commits of a revision range like v1.0..v2.0, measuring changes from the code at v1.0
+    #[clap(long, value_name = "A..B")]
+    range: Option<String>,
+    /// Only analyze the commits made since the commit of this tag, e.g. v2.0
+    #[clap(long, value_name = "TAG")]
+    since_tag: Option<String>,
+    /// Only analyze the commits made up to the commit of this tag
+    #[clap(long, value_name = "TAG")]
+    until_tag: Option<String>,
+    /// Save the blame state at the end of the analysis to this file, so a later run can pick up from it with --update
+    #[clap(long, value_name = "FILE", conflicts_with = "include_worktree")]
+    checkpoint: Option<PathBuf>,
+    /// Start from the state saved in --checkpoint and only analyze the commits made since, then save the new state there
+    #[clap(long, requires = "checkpoint", conflicts_with_all = ["range", "since_tag", "until_tag"])]
+    update: bool,
+    /// When a deleted file comes back with the exact content it had, give it back its old cohorts instead of counting it as new code
+    #[clap(long)]
+    revive_deleted: bool,
+    /// Only compute the composition of the code at the last commit, which is faster and writes a single snapshot
+    #[clap(long, conflicts_with = "checkpoint")]
+    final_only: bool,
+    /// Also write ever_written.json, the lines each cohort ever wrote including the deleted ones, to plot next to the surviving code
+    #[clap(long, conflicts_with = "checkpoint")]
+    ever_written: bool,
+    /// The axes to group the lines by. Cohorts are always grouped by year, year,ext also writes cohorts_by_extension.json with the cohorts of each file extension and year,dir writes cohorts_by_directory.json with the cohorts of each top-level directory
+    #[clap(
+        long,
+        value_name = "AXES",
+        value_delimiter = ',',
+        default_value = "year",
+        value_parser = ["year", "ext", "dir"],
+        conflicts_with = "checkpoint"
+    )]
+    group_by: Vec<String>,
+    /// Write the composition at each commit to this file as it's analyzed, one JSON line per commit, e.g. to ingest it live. `-` writes to stdout
+    #[clap(long, value_name = "FILE")]
+    stream_snapshots: Option<PathBuf>,
+    /// Warn about the commits whose diff adds and removes at least N lines, e.g. vendored or generated code. Defaults to 100000
+    #[clap(long, value_name = "N")]
+    large_commit_lines: Option<u64>,
+    /// How many changes can wait to be applied to the blame before the diffing threads wait too, to cap memory on huge repos. Defaults to 10000
+    #[clap(long, value_name = "N")]
+    queue_size: Option<usize>,
+    /// Record the actions sent to the blame processor to this file, one JSON object per line, to replay them in a test
+    #[cfg(feature = "record")]
+    #[clap(long, value_name = "FILE")]
+    record_actions: Option<PathBuf>,
+    /// Label the yearly cohorts with this template, where {y} is replaced by the year
+    #[clap(long, value_name = "TEMPLATE", default_value = formatter::DEFAULT_LABEL_TEMPLATE)]
+    label_template: String,
+    /// Read labels for specific years from a JSON object like {"2019": "Before the rewrite"}
+    #[clap(long, value_name = "FILE")]
+    labels_from: Option<PathBuf>,
+    /// The order the cohorts are stacked in from the bottom and listed in the legend: asc (oldest first), desc, or size (biggest at the last snapshot first)
+    #[clap(long, value_name = "ORDER", default_value = "asc")]
+    sort_labels: LabelOrder,
+    /// Split the cohorts by the year, quarter or month the code was added in, labelled like 2021, 2021-Q1 or 2021-03. The commits are still analyzed weekly
+    #[clap(long, value_name = "GRANULARITY", default_value = "year")]
+    cohort_granularity: CohortGranularity,
+    /// Only keep the N cohorts with the most lines over the whole chart, and sum the rest into an "Other" one
+    #[clap(long, value_name = "N")]
+    top_n: Option<usize>,
+    /// Replace the dates of the snapshots with the number of weeks since the first one, to compare repos that started in different years
+    #[clap(long)]
+    relative_time: bool,
+}
+
+impl AnalysisArgs {
+    fn to_options(&self, verbosity: Verbosity) -> AnalysisOptions {
+        AnalysisOptions {
+            all_filetypes: self.all_filetypes,
+            include: self.include.clone(),
+            only_extensions: self.only_extensions.clone(),
+            only_languages: self.only_languages.clone(),
+            filetypes: self.filetypes.clone(),
+            ignore_eol: self.ignore_eol,
+            measure: self.measure,
+            detect_copies: self.detect_copies,
+            rename_limit: self.rename_limit,
+            sample: self.sample,
+            max_commits: self.max_commits,
+            accurate_baseline: self.accurate_baseline,
+            week_start: self.week_start,
+            cache_dir: self.cache_dir.clone(),
+            spool_results: self.spool_results,
+            skip_merges: self.skip_merges,
+            no_commit_graph: self.no_commit_graph,
+            authors: self.authors.clone(),
+            exclude_commits: self.exclude_commits.clone(),
+            min_lines: self.min_lines,
+            include_worktree: self.include_worktree,
+            revive_deleted: self.revive_deleted,
+            final_only: self.final_only,
+            ever_written: self.ever_written,
+            by_extension: self.group_by.iter().any(|axis| axis == "ext"),
+            by_directory: self.group_by.iter().any(|axis| axis == "dir"),
+            stream_snapshots: self.stream_snapshots.clone(),
+            large_commit_lines: self.large_commit_lines,
+            queue_size: self.queue_size,
+            #[cfg(feature = "record")]
+            record_actions: self.record_actions.clone(),
+            range: self.range.clone(),
+            since_tag: self.since_tag.clone(),
+            until_tag: self.until_tag.clone(),
+            checkpoint: self.checkpoint.clone(),
+            update: self.update,
+            verbosity,
+        }
+    }
+
+    fn to_format_options(&self, verbosity: Verbosity) -> Result<FormatOptions> {
+        let year_labels = match &self.labels_from {
+            Some(path) => {
+                let file = File::open(path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                serde_json::from_reader(file).with_context(|| {
+                    format!("Failed to read year labels from {}", path.display())
+                })?
+            }
+            None => Default::default(),
+        };
+        Ok(FormatOptions {
+            first_commit_label: self.first_commit_cohort.clone(),
+            label_template: Some(self.label_template.clone()),
+            year_labels,
+            commit_metadata: self.commit_metadata,
+            label_order: self.sort_labels,
+            cohort_granularity: self.cohort_granularity,
+            boundary: None,
+            top_n: self.top_n,
+            relative_time: self.relative_time,
+            verbosity,
+        })
+    }
+}
+
+// The filetype flags conflict with each other, so when one is given on the command line, the ones in
+// the project config are all left out
+const FILETYPE_FLAGS: [&str; 4] = [
+    "all_filetypes",
+    "only_extensions",
+    "only_languages",
+    "filetypes",
+];
+
+// Fills in the flags that weren't given on the command line from the project config: the --config
+// file, or else the gix-of-theseus.toml at the root of the repo
+fn apply_project_config(
+    analysis: &mut AnalysisArgs,
+    text: &mut PlotTextArgs,
+    repo_path: Option<&str>,
+    matches: &ArgMatches,
+) -> Result<()> {
+    let config = match (&analysis.config, repo_path) {
+        (Some(path), _) => ProjectConfig::from_file(path)?,
+        (None, Some(repo_path)) => match ProjectConfig::find_in_repo(Path::new(repo_path))? {
+            Some(config) => config,
+            None => return Ok(()),
+        },
+        (None, None) => return Ok(()),
+    };
+    let on_command_line = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let mut file = config.analysis;
+    if FILETYPE_FLAGS.iter().any(|id| on_command_line(id)) {
+        file.all_filetypes = None;
+        file.only_extensions = None;
+        file.only_languages = None;
+        file.filetypes = None;
+    }
+    if analysis.accurate_baseline {
+        file.first_commit_cohort = None;
+    }
+    // The flags left to their defaults take the value of the file, as is or wrapped in Some
+    macro_rules! fill {
+        (Some: $($field:ident),*) => {$(
+            if let Some(value) = file.$field.take().filter(|_| !on_command_line(stringify!($field))) {
+                analysis.$field = Some(value);
+            }
+        )*};
+        ($($field:ident),*) => {$(
+            if let Some(value) = file.$field.take().filter(|_| !on_command_line(stringify!($field))) {
+                analysis.$field = value;
+            }
+        )*};
+    }
+    fill!(
+        all_filetypes,
+        include,
+        only_extensions,
+        only_languages,
+        ignore_eol,
+        detect_copies
+    );
+    fill!(skip_merges, min_lines, label_template);
+    fill!(Some: filetypes, rename_limit, sample, max_commits, first_commit_cohort);
+    fill!(Some: labels_from, top_n);
+    if let Some(value) = file.measure.filter(|_| !on_command_line("measure")) {
+        analysis.measure = value.parse().map_err(anyhow::Error::msg)?;
+    }
+    if let Some(value) = file.sort_labels.filter(|_| !on_command_line("sort_labels")) {
+        analysis.sort_labels = value.parse().map_err(anyhow::Error::msg)?;
+    }
+    if let Some(value) = file
+        .cohort_granularity
+        .filter(|_| !on_command_line("cohort_granularity"))
+    {
+        analysis.cohort_granularity = value.parse().map_err(anyhow::Error::msg)?;
+    }
+    if let Some(value) = file.week_start.filter(|_| !on_command_line("week_start")) {
+        analysis.week_start = Some(
+            value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("unknown week_start '{value}' in the config"))?,
+        );
+    }
+    text.project_style = Some(config.plot);
+    Ok(())
 }
 
 #[derive(Debug, clap::Subcommand)]
 enum Subcommands {
     /// Plot the data in a cohorts.json file
     Plot(PlotArgs),
+    /// Check that a cohorts.json file is well formed before plotting or sharing it
+    Validate(ValidateArgs),
     /// Analyze a repo's contents and write the data to a cohorts.json file, and optionally plot it
     Analyze(TheseusArgs),
+    /// Analyze several repos and write their combined data to a single cohorts.json file, and optionally plot it
+    AnalyzeMany(AnalyzeManyArgs),
+    /// Analyze a series of release archives (.tar.gz, .zip, ...) when the git history isn't available, and optionally plot it
+    AnalyzeTarballs(AnalyzeTarballsArgs),
+    /// Analyze the history of a single file, at every commit that changed it, and optionally plot it
+    AnalyzeFile(AnalyzeFileArgs),
+}
+
+// Where the files of one run go: `{outdir}/{prefix}-{name}`, or `{outdir}/{name}` without a prefix
+struct OutputFiles {
+    outdir: PathBuf,
+    prefix: Option<String>,
+}
+
+impl OutputFiles {
+    fn new(outdir: PathBuf, prefix: Option<String>) -> Result<Self> {
+        fs::create_dir_all(&outdir)?;
+        Ok(Self { outdir, prefix })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        match &self.prefix {
+            Some(prefix) => self.outdir.join(format!("{prefix}-{name}")),
+            None => self.outdir.join(name),
+        }
+    }
 }
 
-fn analyze_repo(repo_path: &str, outdir: PathBuf, all_filetypes: bool) -> Result<PathBuf> {
-    let res = theseus::run_theseus(repo_path, all_filetypes).expect("Error running theseus");
-    let formatted_data = formatter::format_cohort_data(res);
-    let cohorts_file = outdir.join("cohorts.json");
-    println!("Writing cohort data to {}", cohorts_file.display());
+fn analyze_repo(
+    repo_path: &str,
+    output_files: &OutputFiles,
+    options: &AnalysisOptions,
+    format_options: &FormatOptions,
+) -> Result<(PathBuf, CohortData, PhaseTimings)> {
+    let start = Instant::now();
+    let res = theseus::run_theseus(repo_path, options)?;
+    let mut phases = res.stats.phases.clone();
+    let (cohorts_file, formatted_data) = phases.time("formatting", || {
+        write_results(&res, start, output_files, options, format_options)
+    })?;
+    Ok((cohorts_file, formatted_data, phases))
+}
+
+// Tells about the files that were left out and the renames that were found, on stderr
+fn print_run_notes(res: &TheseusResult, options: &AnalysisOptions) {
+    if !res.failures.is_empty() && !options.verbosity.is_quiet() {
+        eprintln!(
+            "{} files couldn't be read or diffed and were left out from then on:",
+            res.failures.len()
+        );
+        for failure in &res.failures {
+            let reasons: Vec<String> =
+                std::iter::successors(Some(&failure.error as &dyn std::error::Error), |error| {
+                    error.source()
+                })
+                .map(|error| error.to_string())
+                .collect();
+            eprintln!(
+                "  {} at {}: {}",
+                failure.path,
+                failure.commit.to_hex_with_len(7),
+                reasons.join(": ")
+            );
+        }
+    }
+    if !res.stats.large_commits.is_empty() && !options.verbosity.is_quiet() {
+        eprintln!(
+            "{} commits have a suspiciously large diff, e.g. vendored or generated code, which swamps their cohort:",
+            res.stats.large_commits.len()
+        );
+        for commit in &res.stats.large_commits {
+            eprintln!(
+                "  {} on {}: {} lines added, {} removed",
+                commit.commit, commit.date, commit.added, commit.removed
+            );
+        }
+    }
+    if !options.verbosity.is_quiet() {
+        if options.detect_copies {
+            eprintln!(
+                "Detected {} renames and {} copies",
+                res.stats.renames_detected, res.stats.copies_detected
+            );
+        } else {
+            eprintln!("Detected {} renames", res.stats.renames_detected);
+        }
+    }
+}
+
+// Writes cohorts.json, summary.json, survival.csv and half_life.json for an analysis that started at `start`
+fn write_results(
+    res: &TheseusResult,
+    start: Instant,
+    output_files: &OutputFiles,
+    options: &AnalysisOptions,
+    format_options: &FormatOptions,
+) -> Result<(PathBuf, CohortData)> {
+    print_run_notes(res, options);
+    let formatted_data = formatter::format_cohort_data(res, format_options)?;
+    let summary = RunSummary::new(res, &formatted_data, start.elapsed());
+    let cohorts_file = output_files.path("cohorts.json");
+    if !options.verbosity.is_quiet() {
+        println!("Writing cohort data to {}", cohorts_file.display());
+    }
     serde_json::to_writer_pretty(File::create(cohorts_file.clone())?, &formatted_data)?;
-    Ok(cohorts_file)
+    let summary_file = output_files.path("summary.json");
+    if options.verbosity.is_verbose() {
+        println!("Writing run summary to {}", summary_file.display());
+    }
+    serde_json::to_writer_pretty(File::create(summary_file)?, &summary)?;
+    let survival_file = output_files.path("survival.csv");
+    if options.verbosity.is_verbose() {
+        println!("Writing per-year survival to {}", survival_file.display());
+    }
+    survival::write_csv(
+        &survival::survival_by_year(res)?,
+        BufWriter::new(File::create(survival_file)?),
+    )?;
+    let half_life_file = output_files.path("half_life.json");
+    if options.verbosity.is_verbose() {
+        println!(
+            "Writing per-year half-lives to {}",
+            half_life_file.display()
+        );
+    }
+    survival::write_half_life_json(
+        &survival::half_life_by_year(res)?,
+        BufWriter::new(File::create(half_life_file)?),
+    )?;
+    let churn_file = output_files.path("churn.json");
+    if options.verbosity.is_verbose() {
+        println!("Writing per-commit churn to {}", churn_file.display());
+    }
+    serde_json::to_writer_pretty(File::create(churn_file)?, &churn::churn_data(res))?;
+    if let Some(ever_written) = formatter::format_ever_written(res, format_options)? {
+        let ever_written_file = output_files.path("ever_written.json");
+        if !options.verbosity.is_quiet() {
+            println!(
+                "Writing the lines ever written to {}",
+                ever_written_file.display()
+            );
+        }
+        serde_json::to_writer_pretty(File::create(ever_written_file)?, &ever_written)?;
+    }
+    if let Some(by_extension) = formatter::format_by_extension(res, format_options)? {
+        let by_extension_file = output_files.path("cohorts_by_extension.json");
+        if !options.verbosity.is_quiet() {
+            println!(
+                "Writing the cohorts of each file extension to {}",
+                by_extension_file.display()
+            );
+        }
+        serde_json::to_writer_pretty(File::create(by_extension_file)?, &by_extension)?;
+    }
+    if let Some(by_directory) = formatter::format_by_directory(res, format_options)? {
+        let by_directory_file = output_files.path("cohorts_by_directory.json");
+        if !options.verbosity.is_quiet() {
+            println!(
+                "Writing the cohorts of each top-level directory to {}",
+                by_directory_file.display()
+            );
+        }
+        serde_json::to_writer_pretty(File::create(by_directory_file)?, &by_directory)?;
+    }
+    Ok((cohorts_file, formatted_data))
 }
+
+// Plots the chart next to cohorts.json. Without a Python runner to plot it, says so and prints the
+// composition as bars instead. Returns the final output of the run, the chart or cohorts.json.
+fn plot_chart(
+    data: &CohortData,
+    cohorts_file: PathBuf,
+    output_files: &OutputFiles,
+    text: &PlotTextArgs,
+    default_title: Option<String>,
+    verbosity: Verbosity,
+) -> Result<PathBuf> {
+    if plot::get_python_runner().is_none() {
+        if !verbosity.is_quiet() {
+            println!(
+                "No Python PEP 723 script runner found (tried: uv, pipx), we won't be able to plot the chart automatically and will only save the raw to cohorts.json.\nYou can install uv with `pip install uv` or pipx with `pip install pipx`"
+            );
+            // Some feedback on the composition until the chart can be plotted
+            print!("\n{}", plot::terminal_chart(data, 40));
+        }
+        return Ok(cohorts_file);
+    }
+    let image_file = output_files.path(&text.image_file_name());
+    let plot_options = text.to_plot_options(default_title, data)?;
+    plot::plot(
+        data,
+        image_file.display().to_string(),
+        &plot_options,
+        verbosity,
+    )?;
+    Ok(image_file)
+}
+
+fn print_dry_run(report: &DryRunReport) {
+    println!(
+        "History: {} to {}",
+        report.first_commit_time.as_deref().unwrap_or("?"),
+        report.last_commit_time.as_deref().unwrap_or("?")
+    );
+    println!(
+        "Commits per bucket: {} weekly, {} monthly, {} yearly",
+        report.weekly_commits, report.monthly_commits, report.yearly_commits
+    );
+    println!(
+        "Files at HEAD: {} ({} would be analyzed)",
+        report.files_at_head, report.files_to_analyze
+    );
+    println!(
+        "Work: {} commits to diff and blame",
+        report.commits_to_analyze
+    );
+}
+
 fn main() -> Result<()> {
-    let args = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let verbosity = Verbosity::from_flags(args.quiet, args.verbose);
+    // To tell the flags given on the command line from their defaults when applying the project config
+    let (_, matches) = matches.subcommand().expect("clap requires a subcommand");
     match args.subcommand {
-        Subcommands::Plot(args) => plot::run_stackplot(args.input_file, args.output_file, None),
-        Subcommands::Analyze(args) => {
-            let python_runner = plot::get_python_runner();
-            let repo_path = Path::new(&args.repo_path);
-            let repo_name = repo_path.file_name().unwrap().to_str().unwrap();
-
-            let outdir = args.outdir.unwrap_or_else(|| PathBuf::from(repo_name));
-            fs::create_dir_all(&outdir)?;
-            let cohorts_file = analyze_repo(&args.repo_path, outdir.clone(), args.all_filetypes)
-                .expect("Error analyzing repo");
-            if !args.no_plot {
-                if python_runner.is_some() {
-                    let image_file = outdir.join("stackplot.png");
-                    plot::run_stackplot(
-                        cohorts_file.display().to_string().clone(),
-                        image_file.display().to_string(),
-                        Some(repo_name.to_string()),
-                    )?;
-                } else {
+        Subcommands::Plot(args) => {
+            let data: CohortData = serde_json::from_reader(File::open(&args.input_file)?)?;
+            let plot_options = args.text.to_plot_options(None, &data)?;
+            plot::plot(&data, args.output_file.clone(), &plot_options, verbosity)?;
+            if verbosity.is_quiet() {
+                println!("{}", args.output_file);
+            }
+            Ok(())
+        }
+        Subcommands::Validate(args) => {
+            let data: CohortData = serde_json::from_reader(File::open(&args.input_file)?)
+                .with_context(|| format!("{} isn't a cohorts file", args.input_file))?;
+            if !verbosity.is_quiet() {
+                for warning in formatter::cohort_data_warnings(&data) {
+                    eprintln!("Warning: {}: {warning}", args.input_file);
+                }
+            }
+            let problems = formatter::validate_cohort_data(&data);
+            if problems.is_empty() {
+                if !verbosity.is_quiet() {
                     println!(
-                        "No Python PEP 723 script runner found (tried: uv, pipx), we won't be able to plot the chart automatically and will only save the raw to cohorts.json.\nYou can install uv with `pip install uv` or pipx with `pip install pipx`"
+                        "{}: {} cohorts over {} snapshots, no problems found",
+                        args.input_file,
+                        data.labels.len(),
+                        data.ts.len()
                     );
                 }
+                return Ok(());
+            }
+            for problem in &problems {
+                eprintln!("{}: {problem}", args.input_file);
+            }
+            anyhow::bail!("{} has {} problems", args.input_file, problems.len())
+        }
+        Subcommands::Analyze(mut args) if args.dry_run => {
+            let (repo_path, _, _clone) = args.repo(verbosity)?;
+            apply_project_config(
+                &mut args.analysis,
+                &mut args.text,
+                Some(&repo_path),
+                matches,
+            )?;
+            let options = args.analysis.to_options(verbosity);
+            print_dry_run(&dry_run::dry_run(&repo_path, &options)?);
+            Ok(())
+        }
+        Subcommands::Analyze(mut args) if args.outdir.as_deref() == Some(Path::new("-")) => {
+            let (repo_path, _, _clone) = args.repo(verbosity)?;
+            apply_project_config(
+                &mut args.analysis,
+                &mut args.text,
+                Some(&repo_path),
+                matches,
+            )?;
+            // Only cohorts.json goes to stdout, there's nowhere to write the other files
+            if args.analysis.stream_snapshots.as_deref() == Some(Path::new("-")) {
+                anyhow::bail!(
+                    "--stream-snapshots - can't be used with --outdir -, both would write to stdout"
+                );
+            }
+            if args.analysis.ever_written {
+                anyhow::bail!("--ever-written writes ever_written.json, which --outdir - can't");
+            }
+            if args.analysis.group_by.iter().any(|axis| axis != "year") {
+                anyhow::bail!(
+                    "--group-by ext,dir writes the cohorts of each group to files, which --outdir - can't"
+                );
+            }
+            let options = args.analysis.to_options(verbosity);
+            let format_options = args.to_format_options(&repo_path, verbosity)?;
+            let res = theseus::run_theseus(&repo_path, &options)?;
+            print_run_notes(&res, &options);
+            let mut phases = res.stats.phases.clone();
+            let formatted_data = phases.time("formatting", || {
+                formatter::format_cohort_data(&res, &format_options)
+            })?;
+            let mut stdout = std::io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, &formatted_data)?;
+            writeln!(stdout)?;
+            if args.profile {
+                eprint!("{}", phases.table());
+            }
+            Ok(())
+        }
+        Subcommands::Analyze(mut args) => {
+            let (repo_path, repo_name, _clone) = args.repo(verbosity)?;
+            apply_project_config(
+                &mut args.analysis,
+                &mut args.text,
+                Some(&repo_path),
+                matches,
+            )?;
+
+            let format_options = args.to_format_options(&repo_path, verbosity)?;
+            let outdir = args.outdir.unwrap_or_else(|| PathBuf::from(&repo_name));
+            let output_files = OutputFiles::new(outdir, args.prefix)?;
+            let options = args.analysis.to_options(verbosity);
+            let (cohorts_file, formatted_data, mut phases) =
+                analyze_repo(&repo_path, &output_files, &options, &format_options)?;
+            let final_output = if args.no_plot {
+                cohorts_file
+            } else {
+                phases.time("plotting", || {
+                    plot_chart(
+                        &formatted_data,
+                        cohorts_file,
+                        &output_files,
+                        &args.text,
+                        Some(repo_name),
+                        verbosity,
+                    )
+                })?
+            };
+            if args.profile {
+                eprint!("{}", phases.table());
+            }
+            if verbosity.is_quiet() {
+                println!("{}", final_output.display());
+            }
+            Ok(())
+        }
+        Subcommands::AnalyzeMany(mut args) => {
+            apply_project_config(&mut args.analysis, &mut args.text, None, matches)?;
+            let output_files = OutputFiles::new(args.outdir, args.prefix)?;
+            // The repos are analyzed concurrently, so their progress bars would fight over the terminal
+            let options = args.analysis.to_options(Verbosity::Quiet);
+            let format_options = args.analysis.to_format_options(verbosity)?;
+            // The biggest cohorts are picked once the repos are merged
+            let repo_format_options = FormatOptions {
+                top_n: None,
+                ..format_options.clone()
+            };
+            let datasets = args
+                .repo_paths
+                .par_iter()
+                .map(|repo_path| -> Result<formatter::CohortData> {
+                    let data = theseus::analyze(repo_path, &options, &repo_format_options)?;
+                    if !verbosity.is_quiet() {
+                        println!("Analyzed {repo_path}");
+                    }
+                    Ok(data)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let mut merged_data = formatter::sort_labels(
+                formatter::merge_cohort_data(&datasets),
+                format_options.label_order,
+            );
+            if let Some(n) = format_options.top_n {
+                merged_data = formatter::keep_top_cohorts(merged_data, n);
+            }
+            let cohorts_file = output_files.path("cohorts.json");
+            if !verbosity.is_quiet() {
+                println!("Writing merged cohort data to {}", cohorts_file.display());
+            }
+            serde_json::to_writer_pretty(File::create(&cohorts_file)?, &merged_data)?;
+
+            let final_output = if args.no_plot {
+                cohorts_file
+            } else {
+                let title = args
+                    .repo_paths
+                    .iter()
+                    .map(|repo_path| repo_name(repo_path))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                plot_chart(
+                    &merged_data,
+                    cohorts_file,
+                    &output_files,
+                    &args.text,
+                    Some(title),
+                    verbosity,
+                )?
+            };
+            if verbosity.is_quiet() {
+                println!("{}", final_output.display());
+            }
+            Ok(())
+        }
+        Subcommands::AnalyzeTarballs(mut args) => {
+            apply_project_config(&mut args.analysis, &mut args.text, None, matches)?;
+            let output_files = OutputFiles::new(args.outdir, args.prefix)?;
+            let options = args.analysis.to_options(verbosity);
+            let format_options = args.analysis.to_format_options(verbosity)?;
+            let start = Instant::now();
+            let res = tarball::run_tarballs(&args.releases, &options)?;
+            let (cohorts_file, formatted_data) =
+                write_results(&res, start, &output_files, &options, &format_options)?;
+
+            let final_output = if args.no_plot {
+                cohorts_file
+            } else {
+                plot_chart(
+                    &formatted_data,
+                    cohorts_file,
+                    &output_files,
+                    &args.text,
+                    None,
+                    verbosity,
+                )?
+            };
+            if verbosity.is_quiet() {
+                println!("{}", final_output.display());
+            }
+            Ok(())
+        }
+        Subcommands::AnalyzeFile(mut args) => {
+            apply_project_config(
+                &mut args.analysis,
+                &mut args.text,
+                Some(&args.repo_path),
+                matches,
+            )?;
+            let output_files = OutputFiles::new(args.outdir, args.prefix)?;
+            let options = args.analysis.to_options(verbosity);
+            let format_options = args.analysis.to_format_options(verbosity)?;
+            let start = Instant::now();
+            let res = file_history::file_history(&args.repo_path, &args.path, &options)?;
+            let (cohorts_file, formatted_data) =
+                write_results(&res, start, &output_files, &options, &format_options)?;
+
+            let final_output = if args.no_plot {
+                cohorts_file
+            } else {
+                plot_chart(
+                    &formatted_data,
+                    cohorts_file,
+                    &output_files,
+                    &args.text,
+                    Some(args.path.clone()),
+                    verbosity,
+                )?
+            };
+            if verbosity.is_quiet() {
+                println!("{}", final_output.display());
             }
             Ok(())
         }
@@ -0,0 +1,254 @@
+/// What the size of a cohort counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Measure {
+    #[default]
+    Lines,
+    /// The bytes of the lines, so long lines weigh more than short ones.
+    Bytes,
+}
+
+impl std::str::FromStr for Measure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lines" => Ok(Measure::Lines),
+            "bytes" => Ok(Measure::Bytes),
+            _ => Err(format!("unknown measure '{s}', expected lines or bytes")),
+        }
+    }
+}
+
+/// How chatty the analysis should be on the terminal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// No progress bar and no status lines, only the final output path.
+    Quiet,
+    #[default]
+    Normal,
+    /// Extra status lines about what is being analyzed.
+    Verbose,
+}
+
+impl Verbosity {
+    pub fn from_flags(quiet: bool, verbose: bool) -> Self {
+        if quiet {
+            Verbosity::Quiet
+        } else if verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
+    }
+
+    pub fn is_quiet(self) -> bool {
+        self == Verbosity::Quiet
+    }
+
+    pub fn is_verbose(self) -> bool {
+        self == Verbosity::Verbose
+    }
+}
+
+use std::path::PathBuf;
+
+/// Everything that changes how `run_theseus` analyzes a repo.
+#[derive(Debug, Clone, Default)]
+pub struct AnalysisOptions {
+    /// Count every file instead of only the ones that look like source code.
+    pub all_filetypes: bool,
+    /// Read the filetypes to analyze from this file, one glob like `*.rs` per line, instead of using
+    /// the built-in list of source code filetypes.
+    pub filetypes: Option<PathBuf>,
+    /// Treat CRLF and LF line endings as equal when diffing files.
+    pub ignore_eol: bool,
+    /// Blame the files of the first analyzed commit through the history before it, like `git blame`,
+    /// so their lines go to the years they were written in instead of all to that commit. Matters
+    /// when the analysis starts after the first commit, e.g. with a range or max_commits. Each of
+    /// those years gets a made-up commit at the start of TheseusResult::commit_cohort_info. Slow on
+    /// big repos, and only for line counts: with Measure::Bytes the files aren't blamed.
+    pub accurate_baseline: bool,
+    /// Only keep every nth weekly commit, trading timing accuracy for speed.
+    pub sample: Option<usize>,
+    /// Only analyze the most recent n commits, after sampling. The code at the first of them is
+    /// all credited to it.
+    pub max_commits: Option<usize>,
+    /// The day the weekly buckets start on. Defaults to Sunday.
+    pub week_start: Option<chrono::Weekday>,
+    /// Where to cache the list of commits to analyze between runs.
+    pub cache_dir: Option<PathBuf>,
+    /// Write the stats of each commit to a temp file instead of keeping them all in memory.
+    pub spool_results: bool,
+    /// Credit the lines brought in by a merge commit to when the merged branch was worked on.
+    pub skip_merges: bool,
+    /// Walk the history without the commit-graph file, e.g. when it's stale. A missing or corrupt
+    /// one is already ignored.
+    pub no_commit_graph: bool,
+    /// Leave these commits out, given as revisions like a hash. Each excluded commit is diffed along
+    /// with the next analyzed commit, which gets its lines. The last commit is always kept.
+    pub exclude_commits: Vec<String>,
+    /// Only credit the commits authored with one of these emails, compared case-insensitively.
+    /// Every first-parent commit is then analyzed instead of weekly ones, so the changes of each
+    /// author can be told apart. The other commits are still diffed, but their lines are left out
+    /// of the results. Empty credits everyone.
+    pub authors: Vec<String>,
+    /// Leave out files that have fewer lines than this when they're added. 0 keeps every file.
+    pub min_lines: u32,
+    /// Add the uncommitted changes in the worktree as a last data point.
+    pub include_worktree: bool,
+    /// Only analyze the files whose path matches one of these globs, e.g. `src/**`. Empty means every path.
+    pub include: Vec<String>,
+    /// Only analyze the files with these extensions, e.g. `rs`, instead of the ones that look like source code.
+    pub only_extensions: Vec<String>,
+    /// Only analyze the source files of these languages, e.g. `rust`. Combines with only_extensions.
+    pub only_languages: Vec<String>,
+    /// Only analyze the commits of a revision range like `v1.0..v2.0`, starting from the tree at
+    /// `v1.0` instead of an empty one. A single revision analyzes its whole history.
+    /// The worktree is compared to HEAD, so this doesn't mix with include_worktree unless the range ends at HEAD.
+    pub range: Option<String>,
+    /// Only analyze the commits made at or after the commit of this tag, e.g. `v2.0`. The code
+    /// already there is credited to the first week analyzed, as with max_commits.
+    pub since_tag: Option<String>,
+    /// Only analyze the commits made at or before the commit of this tag.
+    pub until_tag: Option<String>,
+    /// Save the blame state at the end of the analysis to this file, so a later run can update from it.
+    pub checkpoint: Option<PathBuf>,
+    /// Start from the blame state saved in the checkpoint and only analyze the commits made since,
+    /// then save the new state over it.
+    pub update: bool,
+    /// Give a re-added file its old blame back when its content is the same as when it was deleted.
+    pub revive_deleted: bool,
+    /// Only compute the composition at the last commit instead of at every analyzed commit.
+    /// The results then hold a single snapshot.
+    pub final_only: bool,
+    /// Also record, at each analyzed commit, every line each cohort ever added, including the ones
+    /// that were later deleted. They end up in TheseusResult::ever_written.
+    pub ever_written: bool,
+    /// Also break the cohorts down by file extension at each analyzed commit.
+    /// They end up in TheseusResult::by_extension.
+    pub by_extension: bool,
+    /// Also break the cohorts down by top-level directory at each analyzed commit.
+    /// They end up in TheseusResult::by_directory.
+    pub by_directory: bool,
+    /// Count the bytes of the lines instead of the lines. The diffs are still made line by line,
+    /// so a changed line counts all of its bytes as new.
+    pub measure: Measure,
+    /// Also look for files copied from a file changed in the same commit. A copy's lines still count as
+    /// new code, since the file it was copied from keeps its own.
+    pub detect_copies: bool,
+    /// The most files a commit can add or delete for renames and copies to be looked for among them.
+    /// None keeps gix's default of 1000, and 0 means no limit.
+    pub rename_limit: Option<usize>,
+    /// Report the analyzed commits whose diff adds and removes at least this many lines, in
+    /// RunStats::large_commits. None uses churn::DEFAULT_LARGE_COMMIT_LINES.
+    pub large_commit_lines: Option<u64>,
+    /// How many actions can wait for the blame processor before the diffing threads block, which
+    /// caps the memory of the queue between them. The changes of a commit are diffed a few per thread
+    /// at a time, so a commit that touches many files stays within it too. None uses
+    /// repo_blame_snapshot::DEFAULT_QUEUE_SIZE.
+    pub queue_size: Option<usize>,
+    /// Write the composition at each commit to this file as one NDJSON line as soon as the commit is
+    /// analyzed, flushing each line. `-` writes them to stdout.
+    pub stream_snapshots: Option<PathBuf>,
+    /// Record every action sent to the blame processor to this file, to replay them with `record::replay`.
+    #[cfg(feature = "record")]
+    pub record_actions: Option<PathBuf>,
+    pub verbosity: Verbosity,
+}
+
+// Chainable setters, so callers can build options without listing the fields they leave alone:
+// `AnalysisOptions::default().ignore_eol(true).sample(4)`. Optional fields take the value itself.
+macro_rules! setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[doc = concat!("Sets [`AnalysisOptions::", stringify!($field), "`].")]
+            pub fn $field(mut self, $field: $ty) -> Self {
+                self.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+macro_rules! optional_setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[doc = concat!("Sets [`AnalysisOptions::", stringify!($field), "`].")]
+            pub fn $field(mut self, $field: $ty) -> Self {
+                self.$field = Some($field);
+                self
+            }
+        )*
+    };
+}
+
+impl AnalysisOptions {
+    setters! {
+        all_filetypes: bool,
+        ignore_eol: bool,
+        spool_results: bool,
+        skip_merges: bool,
+        no_commit_graph: bool,
+        authors: Vec<String>,
+        exclude_commits: Vec<String>,
+        min_lines: u32,
+        include_worktree: bool,
+        include: Vec<String>,
+        only_extensions: Vec<String>,
+        only_languages: Vec<String>,
+        update: bool,
+        revive_deleted: bool,
+        final_only: bool,
+        ever_written: bool,
+        by_extension: bool,
+        by_directory: bool,
+        measure: Measure,
+        detect_copies: bool,
+        accurate_baseline: bool,
+        verbosity: Verbosity,
+    }
+
+    optional_setters! {
+        filetypes: PathBuf,
+        sample: usize,
+        max_commits: usize,
+        week_start: chrono::Weekday,
+        cache_dir: PathBuf,
+        range: String,
+        since_tag: String,
+        until_tag: String,
+        checkpoint: PathBuf,
+        rename_limit: usize,
+        stream_snapshots: PathBuf,
+        large_commit_lines: u64,
+        queue_size: usize,
+    }
+
+    /// Sets [`AnalysisOptions::record_actions`].
+    #[cfg(feature = "record")]
+    pub fn record_actions(mut self, record_actions: PathBuf) -> Self {
+        self.record_actions = Some(record_actions);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setters_only_change_their_field() {
+        let options = AnalysisOptions::default()
+            .ignore_eol(true)
+            .sample(4)
+            .include(vec!["src/**".to_string()])
+            .verbosity(Verbosity::Quiet);
+        assert!(options.ignore_eol);
+        assert_eq!(options.sample, Some(4));
+        assert_eq!(options.include, vec!["src/**"]);
+        assert_eq!(options.verbosity, Verbosity::Quiet);
+        assert!(!options.all_filetypes);
+        assert_eq!(options.max_commits, None);
+        assert_eq!(options.measure, Measure::Lines);
+    }
+}
@@ -1,8 +1,12 @@
-use anyhow::Result;
+use crate::formatter::{CohortData, smooth_cohort_data};
+use crate::options::Verbosity;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
 use std::io::Write;
-use std::process::Command;
+use std::path::Path;
+use std::process::{Command, Stdio};
 use std::sync::OnceLock;
-use std::{env, fs};
 
 const STACKPLOT_SCRIPT: &str = include_str!("stackplot.py");
 
@@ -28,43 +32,345 @@ pub fn get_python_runner() -> Option<String> {
         })
         .clone()
 }
-pub fn run_stackplot(input_file: String, output_file: String, title: Option<String>) -> Result<()> {
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    #[default]
+    Light,
+    // Light text on a dark background, for embedding in dark pages
+    Dark,
+}
+
+impl Theme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+}
+
+impl std::str::FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            _ => Err(format!("unknown theme '{s}', expected light or dark")),
+        }
+    }
+}
+
+// The colors of the cohort bands
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Palette {
+    // A sequential colormap that stays legible for color-blind viewers, from old cohorts to new ones
+    #[default]
+    Cividis,
+    // Colors picked to be as different from each other as possible
+    Distinct,
+}
+
+impl Palette {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Palette::Cividis => "cividis",
+            Palette::Distinct => "distinct",
+        }
+    }
+}
+
+impl std::str::FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cividis" => Ok(Palette::Cividis),
+            "distinct" => Ok(Palette::Distinct),
+            _ => Err(format!(
+                "unknown palette '{s}', expected cividis or distinct"
+            )),
+        }
+    }
+}
+
+// The file format of the chart image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    // A vector image that stays sharp at any size, e.g. for print or LaTeX
+    Svg,
+}
+
+impl ImageFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Svg => "svg",
+        }
+    }
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(ImageFormat::Png),
+            "svg" => Ok(ImageFormat::Svg),
+            _ => Err(format!("unknown image format '{s}', expected png or svg")),
+        }
+    }
+}
+
+// Everything about how the chart looks, read from a --theme-config JSON file.
+// Every field is optional: the flags override the ones that are set, and the rest keep their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlotStyle {
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    pub caption: Option<String>,
+    pub theme: Option<Theme>,
+    pub palette: Option<Palette>,
+    pub palette_reverse: Option<bool>,
+    // The colors of the cohort bands from the oldest cohort, e.g. "#1f77b4", in place of the palette.
+    // They repeat when there are more cohorts than colors.
+    pub colors: Option<Vec<String>>,
+    pub font_family: Option<String>,
+    pub font_size: Option<f64>,
+    // The size of the image in inches, 16x12 by default, and its dots per inch, 120 by default
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    pub dpi: Option<u32>,
+    // Where the legend goes, e.g. "upper left" (the default) or "lower right", or "none" to hide it
+    pub legend_position: Option<String>,
+}
+
+impl PlotStyle {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let file =
+            fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        serde_json::from_reader(file)
+            .with_context(|| format!("Failed to read the plot style from {}", path.display()))
+    }
+}
+
+// How the chart looks and the text around it
+#[derive(Debug, Clone, Default)]
+pub struct PlotOptions {
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    pub caption: Option<String>,
+    pub theme: Theme,
+    pub palette: Palette,
+    // Run the palette from the newest cohort to the oldest, e.g. to make the oldest code the lightest
+    pub palette_reverse: bool,
+    // Color each cohort by its year instead of its position, so a year looks the same across charts
+    pub stable_colors: bool,
+    // Draw a moving average over this many snapshots instead of the raw series
+    pub smooth: Option<usize>,
+    // The format of the image. None goes by the extension of the output file.
+    pub format: Option<ImageFormat>,
+    // The rest of the look, passed on to the script. Its text, theme and palette are already in
+    // the fields above, which the script uses instead.
+    pub style: PlotStyle,
+}
+
+// A caption saying what the chart covers, e.g. "52 commits analyzed from 2019-03-01 to 2020-02-23",
+// or "52 commits analyzed over 51 weeks" when ts holds weeks since the start
+pub fn default_caption(data: &CohortData) -> String {
+    if let Some(weeks) = data.ts.last().and_then(|ts| ts.parse::<i64>().ok()) {
+        return format!("{} commits analyzed over {weeks} weeks", data.ts.len());
+    }
+    let date = |ts: Option<&String>| {
+        ts.map(|ts| ts.split(' ').next().unwrap_or_default().to_string())
+            .unwrap_or_default()
+    };
+    format!(
+        "{} commits analyzed from {} to {}",
+        data.ts.len(),
+        date(data.ts.first()),
+        date(data.ts.last())
+    )
+}
+
+// A bar per cohort with its share of the lines at the last snapshot, for the terminal when the
+// chart can't be plotted, e.g.:
+//
+// Code added in 2019  ##########################                 65.0%
+// Code added in 2020  ##############                             35.0%
+pub fn terminal_chart(data: &CohortData, bar_width: usize) -> String {
+    let last = |row: &Vec<i64>| row.last().copied().unwrap_or(0).max(0);
+    let total: i64 = data.y.iter().map(last).sum();
+    let label_width = data
+        .labels
+        .iter()
+        .map(|label| label.chars().count())
+        .max()
+        .unwrap_or(0);
+    let mut chart = format!(
+        "{} lines at {}\n",
+        total,
+        data.ts.last().map_or("the last snapshot", String::as_str)
+    );
+    for (label, row) in data.labels.iter().zip(&data.y) {
+        let share = if total > 0 {
+            last(row) as f64 / total as f64
+        } else {
+            0.0
+        };
+        let filled = (share * bar_width as f64).round() as usize;
+        chart.push_str(&format!(
+            "{label:<label_width$}  {}{}  {:>5.1}%\n",
+            "#".repeat(filled),
+            " ".repeat(bar_width - filled),
+            100.0 * share
+        ));
+    }
+    chart
+}
+
+// Plots the data in a cohorts.json file
+pub fn run_stackplot(
+    input_file: String,
+    output_file: String,
+    options: &PlotOptions,
+    verbosity: Verbosity,
+) -> Result<()> {
+    let data: CohortData = serde_json::from_reader(
+        fs::File::open(&input_file).with_context(|| format!("Failed to open {input_file}"))?,
+    )?;
+    plot(&data, output_file, options, verbosity)
+}
+
+// Plots data that's already in memory. The data goes to the script on its stdin, and each call
+// writes the script to a temp file of its own, so charts can be plotted in parallel.
+pub fn plot(
+    data: &CohortData,
+    output_file: String,
+    options: &PlotOptions,
+    verbosity: Verbosity,
+) -> Result<()> {
     let runner = get_python_runner().ok_or_else(|| anyhow::anyhow!("No Python runner found"))?;
+    if runner != "uv" && runner != "pipx" {
+        anyhow::bail!("Unsupported runner: {}", runner);
+    }
+
+    let mut script = tempfile::Builder::new()
+        .prefix("stackplot")
+        .suffix(".py")
+        .tempfile()?;
+    script.write_all(STACKPLOT_SCRIPT.as_bytes())?;
 
-    let mut path = env::temp_dir();
-    path.push("stackplot.py");
-
-    let mut file = fs::File::create(&path)?;
-    file.write_all(STACKPLOT_SCRIPT.as_bytes())?;
-
-    let status = if runner == "uv" {
-        Command::new(&runner)
-            .arg("run")
-            .arg(&path)
-            .arg("--outfile")
-            .arg(output_file)
-            .arg("--title")
-            .arg(title.unwrap_or_default())
-            .arg(input_file)
-            .status()?
-    } else if runner == "pipx" {
-        Command::new(&runner)
-            .arg("run")
-            .arg(&path)
-            .arg("--outfile")
-            .arg(output_file)
-            .arg("--title")
-            .arg(title.unwrap_or_default())
-            .arg(input_file)
-            .status()?
+    let mut style_file = tempfile::NamedTempFile::new()?;
+    serde_json::to_writer(&mut style_file, &options.style)?;
+
+    // The script reports where it wrote the image, which we don't want in quiet mode
+    let stdout = if verbosity.is_quiet() {
+        Stdio::null()
     } else {
-        anyhow::bail!("Unsupported runner: {}", runner);
+        Stdio::inherit()
     };
+    let mut script_args = vec![
+        "--outfile".to_string(),
+        output_file,
+        "--title".to_string(),
+        options.title.clone().unwrap_or_default(),
+        "--theme".to_string(),
+        options.theme.as_str().to_string(),
+        "--palette".to_string(),
+        options.palette.as_str().to_string(),
+        "--style".to_string(),
+        style_file.path().display().to_string(),
+    ];
+    if options.palette_reverse {
+        script_args.push("--palette-reverse".to_string());
+    }
+    if options.stable_colors {
+        script_args.push("--stable".to_string());
+    }
+    if let Some(subtitle) = &options.subtitle {
+        script_args.extend(["--subtitle".to_string(), subtitle.clone()]);
+    }
+    if let Some(caption) = &options.caption {
+        script_args.extend(["--caption".to_string(), caption.clone()]);
+    }
+    if let Some(format) = options.format {
+        script_args.extend(["--format".to_string(), format.as_str().to_string()]);
+    }
+    script_args.push("-".to_string());
+    let mut child = Command::new(&runner)
+        .arg("run")
+        .arg(script.path())
+        .args(&script_args)
+        .stdin(Stdio::piped())
+        .stdout(stdout)
+        .spawn()?;
+
+    // The chart is drawn from a smoothed copy, the data stays raw
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let written = match options.smooth {
+        Some(window) => serde_json::to_writer(&mut stdin, &smooth_cohort_data(data, window)),
+        None => serde_json::to_writer(&mut stdin, data),
+    };
+    // Closing stdin lets the script start, even if it stopped reading early
+    drop(stdin);
+    let status = child.wait()?;
 
     if !status.success() {
-        anyhow::bail!("Failed to execute '{} run {}'", runner, path.display());
+        anyhow::bail!(
+            "Failed to execute '{} run {}'",
+            runner,
+            script.path().display()
+        );
     }
-
-    fs::remove_file(path)?;
+    written?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plot_style_reads_a_partial_config() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r##"{{"theme": "dark", "colors": ["#1f77b4", "#ff7f0e"], "legend_position": "lower right"}}"##
+        )
+        .unwrap();
+        let style = PlotStyle::from_file(file.path()).unwrap();
+        assert_eq!(style.theme, Some(Theme::Dark));
+        assert_eq!(style.colors.as_deref().unwrap().len(), 2);
+        assert_eq!(style.legend_position.as_deref(), Some("lower right"));
+        assert_eq!(style.palette, None);
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, r#"{{"colour": "red"}}"#).unwrap();
+        assert!(PlotStyle::from_file(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_terminal_chart_shows_the_shares_at_the_last_snapshot() {
+        let data = CohortData {
+            y: vec![vec![10, 30], vec![0, 10]],
+            ts: vec![
+                "2019-03-01 12:00:00".to_string(),
+                "2020-03-01 12:00:00".to_string(),
+            ],
+            labels: vec!["Code added in 2019".to_string(), "2020".to_string()],
+            commits: None,
+        };
+        assert_eq!(
+            terminal_chart(&data, 8),
+            "40 lines at 2020-03-01 12:00:00\n\
+             Code added in 2019  ######     75.0%\n\
+             2020                ##         25.0%\n"
+        );
+    }
+}
@@ -0,0 +1,76 @@
+// How long each phase of a run took, printed as a table with --profile, to see where the time goes
+// on a big repo and which options are worth reaching for.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PhaseTimings {
+    // In the order the phases first ran
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl PhaseTimings {
+    // Adds the duration to the phase, so a phase that runs several times is summed up
+    pub fn record(&mut self, phase: &'static str, duration: Duration) {
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += duration,
+            None => self.phases.push((phase, duration)),
+        }
+    }
+
+    pub fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, duration)| *duration).sum()
+    }
+
+    pub fn table(&self) -> String {
+        let total = self.total().as_secs_f64();
+        let width = self
+            .phases
+            .iter()
+            .map(|(name, _)| name.len())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or_default();
+        let mut table = String::new();
+        for (name, duration) in &self.phases {
+            let seconds = duration.as_secs_f64();
+            let share = if total > 0.0 {
+                100.0 * seconds / total
+            } else {
+                0.0
+            };
+            table.push_str(&format!("{name:<width$}  {seconds:>9.3}s  {share:>5.1}%\n"));
+        }
+        table.push_str(&format!("{:<width$}  {total:>9.3}s\n", "total"));
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_timings_sum_repeated_phases() {
+        let mut timings = PhaseTimings::default();
+        timings.record("tree diffs", Duration::from_millis(1500));
+        timings.record("blame accumulation", Duration::from_millis(500));
+        timings.record("tree diffs", Duration::from_millis(1000));
+        assert_eq!(timings.total(), Duration::from_secs(3));
+        assert_eq!(
+            timings.table(),
+            "tree diffs              2.500s   83.3%\n\
+             blame accumulation      0.500s   16.7%\n\
+             total                   3.000s\n"
+        );
+        assert_eq!(timings.time("plotting", || 42), 42);
+        assert_eq!(timings.phases.len(), 3);
+    }
+}
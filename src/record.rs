@@ -0,0 +1,104 @@
+// Records the actions an analysis sends to the blame processor, one JSON object per line, and
+// replays them without the repo. A bad blame found on some repo can then be reproduced in a test
+// from its recording alone.
+// A replay starts from an empty snapshot without revive_deleted, like a default analysis does.
+
+use crate::actions::Action;
+use crate::blame::Keyable;
+use crate::error::{BoxError, Result, TheseusError};
+use crate::repo_blame_snapshot::RepositoryBlameSnapshot;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub struct ActionRecorder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl ActionRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).map_err(|e| write_error(path, e.into()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn record<K: Keyable>(&mut self, action: &Action<K>) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, action)
+            .map_err(|e| write_error(&self.path, e.into()))?;
+        self.writer
+            .write_all(b"\n")
+            .map_err(|e| write_error(&self.path, e.into()))?;
+        // Flushing at the end of each commit keeps the recording usable when the analysis fails
+        if matches!(action, Action::FinishCommit) {
+            self.writer
+                .flush()
+                .map_err(|e| write_error(&self.path, e.into()))?;
+        }
+        Ok(())
+    }
+}
+
+fn write_error(path: &Path, source: BoxError) -> TheseusError {
+    TheseusError::ActionsWrite {
+        path: path.to_owned(),
+        source,
+    }
+}
+
+// Feeds the recorded actions through a fresh snapshot, failing like the analysis did if they're bad
+pub fn replay<K: Keyable>(path: &Path) -> Result<RepositoryBlameSnapshot<K>> {
+    let read_error = |source: BoxError| TheseusError::ActionsRead {
+        path: path.to_owned(),
+        source,
+    };
+    let file = File::open(path).map_err(|e| read_error(e.into()))?;
+    let mut snapshot = RepositoryBlameSnapshot::new(gix::ObjectId::null(gix::hash::Kind::Sha1));
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| read_error(e.into()))?;
+        let action: Action<K> = serde_json::from_str(&line).map_err(|e| read_error(e.into()))?;
+        snapshot.handle_action(action)?;
+    }
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{AnalysisOptions, Verbosity};
+    use crate::test_utils::TestRepo;
+    use crate::theseus::run_theseus;
+
+    #[test]
+    fn test_replaying_a_recording_gives_the_same_results() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.git(&["mv", "main.rs", "lib.rs"]);
+        repo.write("lib.rs", "a\nc\nd\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("actions.jsonl");
+        let options = AnalysisOptions {
+            record_actions: Some(path.clone()),
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+
+        let result = run_theseus(repo.path(), &options).unwrap();
+        let replayed = replay::<usize>(&path).unwrap();
+        assert_eq!(replayed.commit_results.len(), result.cohort_data.len());
+        let mut expected = result.cohort_data.last().unwrap().unwrap();
+        let mut actual = replayed.repository_cohort_stats();
+        expected.sort();
+        actual.sort();
+        assert_eq!(actual, expected);
+        assert!(
+            replayed
+                .file_blames
+                .contains_key(&gix::bstr::BString::from("lib.rs"))
+        );
+    }
+}
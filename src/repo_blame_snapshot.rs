@@ -1,14 +1,127 @@
 use crate::actions::Action;
 use crate::blame::{FileBlame, Keyable, LineDiffs, LineNumber};
-use anyhow::Result;
-use crossbeam_channel::{Sender, unbounded};
-use gix::bstr::BString;
-use std::collections::HashMap;
+use crate::commit_results::CommitResults;
+use crate::error::{Result, TheseusError};
+use crossbeam_channel::{Sender, bounded};
+use gix::bstr::{BStr, BString};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::thread::{JoinHandle, spawn};
 
+// How many deleted files we keep the blame of when reviving deleted files
+const MAX_DELETED_BLAMES: usize = 10_000;
+
+// How many actions can wait for the blame processor before the producers block, so the queue
+// doesn't pile up diffs in memory when they're made faster than they're applied
+pub const DEFAULT_QUEUE_SIZE: usize = 10_000;
+
+// The lines a commit added and removed, counting the whole files it added or deleted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitChurn {
+    pub added: u64,
+    pub removed: u64,
+}
+
+impl CommitChurn {
+    // The lines a diff adds and removes, from the lengths of its hunks
+    pub fn of_line_diffs<CommitKey: Keyable>(line_diffs: &LineDiffs<CommitKey>) -> Self {
+        line_diffs
+            .iter()
+            .fold(Self::default(), |churn, (deleted, inserted, _)| Self {
+                added: churn.added + inserted.len() as u64,
+                removed: churn.removed + deleted.len() as u64,
+            })
+    }
+}
+
+// The lines of each cohort in each group of files, e.g. the files of each extension, sorted by group
+// and then by cohort
+pub type GroupSnapshot<CommitKey> = Vec<(String, Vec<(CommitKey, i64)>)>;
+
+// Running stats kept per group of files, recorded in results with each commit's results
+#[derive(Debug)]
+pub struct GroupStats<CommitKey: Keyable> {
+    // The group of a file, from its path
+    group_of: fn(&BStr) -> String,
+    running: HashMap<String, HashMap<CommitKey, i64>>,
+    pub results: Vec<GroupSnapshot<CommitKey>>,
+}
+
+impl<CommitKey: Keyable> GroupStats<CommitKey> {
+    pub fn new(group_of: fn(&BStr) -> String) -> Self {
+        Self {
+            group_of,
+            running: HashMap::new(),
+            results: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, path: &BStr, deltas: &[(CommitKey, i64)]) {
+        let stats = self.running.entry((self.group_of)(path)).or_default();
+        for (cohort, delta) in deltas {
+            *stats.entry(*cohort).or_insert(0) += delta;
+        }
+    }
+
+    fn record(&mut self, uncredited: &HashSet<CommitKey>) {
+        let mut snapshot: GroupSnapshot<CommitKey> = self
+            .running
+            .iter()
+            .map(|(group, stats)| {
+                let mut stats: Vec<(CommitKey, i64)> = stats
+                    .iter()
+                    .filter(|(cohort, count)| **count != 0 && !uncredited.contains(cohort))
+                    .map(|(cohort, count)| (*cohort, *count))
+                    .collect();
+                // The running stats are a HashMap, so the cohorts come out in no particular order
+                stats.sort_by_key(|(cohort, _)| *cohort);
+                (group.clone(), stats)
+            })
+            .filter(|(_, stats)| !stats.is_empty())
+            .collect();
+        snapshot.sort_by(|(a, _), (b, _)| a.cmp(b));
+        self.results.push(snapshot);
+    }
+}
+
+// The ways the running stats are also broken down, each only kept when asked for
+#[derive(Debug)]
+pub struct FileGroups<CommitKey: Keyable> {
+    pub by_extension: Option<GroupStats<CommitKey>>,
+    pub by_directory: Option<GroupStats<CommitKey>>,
+}
+
+impl<CommitKey: Keyable> FileGroups<CommitKey> {
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut GroupStats<CommitKey>> {
+        self.by_extension
+            .iter_mut()
+            .chain(self.by_directory.iter_mut())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.by_extension.is_none() && self.by_directory.is_none()
+    }
+
+    // Adds line count deltas to the stats of each group the file is in
+    fn add(&mut self, path: &BStr, deltas: impl IntoIterator<Item = (CommitKey, i64)>) {
+        if self.is_empty() {
+            return;
+        }
+        let deltas: Vec<(CommitKey, i64)> = deltas.into_iter().collect();
+        for stats in self.iter_mut() {
+            stats.add(path, &deltas);
+        }
+    }
+
+    fn record(&mut self, uncredited: &HashSet<CommitKey>) {
+        for stats in self.iter_mut() {
+            stats.record(uncredited);
+        }
+    }
+}
+
 /// Represents blame information for the entire repository at a specific commit
 /// A CommitKey is a usize that is essentially a pointer into an array of commit info
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct RepositoryBlameSnapshot<CommitKey>
 where
     CommitKey: Keyable,
@@ -16,7 +129,33 @@ where
     pub commit_id: gix::ObjectId,
     pub file_blames: HashMap<BString, FileBlame<CommitKey>>,
     pub running_cohort_stats: HashMap<CommitKey, i64>,
-    pub commit_results: Vec<Vec<(CommitKey, i64)>>,
+    // Every line each cohort ever added, including the ones that were later deleted
+    pub lines_added: HashMap<CommitKey, u64>,
+    // Files we were told to leave out, whose later changes we ignore
+    pub skipped_files: HashSet<BString>,
+    // Every path an analyzed file ever had, whether it's still there or not
+    pub paths_seen: HashSet<BString>,
+    pub commit_results: CommitResults<CommitKey>,
+    // When a file is re-added with the exact content it had when it was deleted,
+    // give it back its old blame, as if the deletion had been reverted.
+    pub revive_deleted: bool,
+    // Only record the results at the end of the analysis instead of at every commit,
+    // for when only the current composition matters
+    pub final_only: bool,
+    // When set, lines_added is also recorded here with the results, so the code ever written by
+    // each cohort can be plotted next to the code that survives
+    pub ever_written: Option<CommitResults<CommitKey>>,
+    // The running stats broken down by file extension or by directory, when asked for
+    pub groups: FileGroups<CommitKey>,
+    // Cohorts whose lines are tracked so the other lines stay in the right place, but are left
+    // out of the results, e.g. the commits of authors we weren't asked about
+    pub uncredited: HashSet<CommitKey>,
+    // The churn of each finished commit, and of the one being applied
+    pub churn: Vec<CommitChurn>,
+    current_churn: CommitChurn,
+    // The blames of recently deleted files, by path and blob id, oldest first in deleted_order
+    deleted_blames: HashMap<(BString, gix::ObjectId), FileBlame<CommitKey>>,
+    deleted_order: VecDeque<(BString, gix::ObjectId)>,
 }
 
 impl<CommitKey> RepositoryBlameSnapshot<CommitKey>
@@ -24,11 +163,33 @@ where
     CommitKey: Keyable,
 {
     pub fn new(commit_id: gix::ObjectId) -> Self {
+        Self::with_commit_results(commit_id, CommitResults::in_memory())
+    }
+
+    pub fn with_commit_results(
+        commit_id: gix::ObjectId,
+        commit_results: CommitResults<CommitKey>,
+    ) -> Self {
         Self {
             commit_id,
             file_blames: HashMap::new(),
             running_cohort_stats: HashMap::new(),
-            commit_results: Vec::new(),
+            lines_added: HashMap::new(),
+            skipped_files: HashSet::new(),
+            paths_seen: HashSet::new(),
+            commit_results,
+            revive_deleted: false,
+            final_only: false,
+            ever_written: None,
+            groups: FileGroups {
+                by_extension: None,
+                by_directory: None,
+            },
+            uncredited: HashSet::new(),
+            churn: Vec::new(),
+            current_churn: CommitChurn::default(),
+            deleted_blames: HashMap::new(),
+            deleted_order: VecDeque::new(),
         }
     }
     pub fn set_commit_id(&mut self, commit_id: gix::ObjectId) {
@@ -37,111 +198,316 @@ where
 
     pub fn add_file(&mut self, path: &BString, total_lines: LineNumber, cohort: CommitKey) {
         let file_blame = FileBlame::new(total_lines, cohort);
+        self.groups
+            .add(path.as_ref(), [(cohort, total_lines as i64)]);
+        self.skipped_files.remove(path);
+        self.paths_seen.insert(path.clone());
         self.file_blames.insert(path.clone(), file_blame);
         self.running_cohort_stats
             .entry(cohort)
             .and_modify(|v| *v += total_lines as i64)
             .or_insert(total_lines as i64);
+        *self.lines_added.entry(cohort).or_insert(0) += total_lines as u64;
+        self.current_churn.added += total_lines as u64;
+    }
+
+    pub fn delete_file(&mut self, path: &BString) -> Result<()> {
+        self.take_file(path)?;
+        Ok(())
+    }
+
+    // Removes a file, and returns its blame unless it was a skipped file
+    fn take_file(&mut self, path: &BString) -> Result<Option<FileBlame<CommitKey>>> {
+        if self.skipped_files.remove(path) {
+            return Ok(None);
+        }
+        let file_blame = self
+            .file_blames
+            .remove(path)
+            .ok_or_else(|| TheseusError::FileNotFound(path.clone()))?;
+        self.subtract_from_running_stats(path.as_ref(), &file_blame);
+        self.current_churn.removed += file_blame.total_lines() as u64;
+        Ok(Some(file_blame))
+    }
+
+    // Leaves a file out of the analysis from now on. A file we had the blame of takes its lines
+    // out of the stats, e.g. when its change couldn't be read.
+    pub fn skip_file(&mut self, path: BString) {
+        if let Some(file_blame) = self.file_blames.remove(&path) {
+            self.subtract_from_running_stats(path.as_ref(), &file_blame);
+        }
+        self.skipped_files.insert(path);
     }
 
-    pub fn delete_file(&mut self, path: &BString) {
-        if let Some(file_blame) = self.file_blames.remove(path) {
-            for (cohort, line_count) in file_blame.cohort_stats() {
-                self.running_cohort_stats
-                    .entry(cohort)
-                    .and_modify(|v| *v -= line_count as i64);
+    fn remember_deleted(&mut self, path: BString, id: gix::ObjectId, blame: FileBlame<CommitKey>) {
+        if self.deleted_order.len() == MAX_DELETED_BLAMES {
+            // The entry may have been revived already, or replaced by a later deletion of the same
+            // path and content. Dropping it then only means that one can't be revived.
+            if let Some(oldest) = self.deleted_order.pop_front() {
+                self.deleted_blames.remove(&oldest);
             }
-        } else {
-            panic!("File not found for delete: {:?}", path);
         }
+        self.deleted_order.push_back((path.clone(), id));
+        self.deleted_blames.insert((path, id), blame);
     }
 
-    pub fn rename_file(&mut self, old_path: BString, new_path: BString) -> Result<(), String> {
+    // Adds a file whose lines already have their cohorts, e.g. blamed through the history before
+    // the first analyzed commit
+    pub fn add_blamed_file(&mut self, path: BString, blame: FileBlame<CommitKey>) {
+        for (cohort, line_count) in blame.cohort_stats() {
+            *self.lines_added.entry(cohort).or_insert(0) += line_count;
+        }
+        self.revive_file(path, blame);
+    }
+
+    // Puts back the blame of a deleted file. Its lines were added back then, not now,
+    // so they only go back into the running stats.
+    fn revive_file(&mut self, path: BString, blame: FileBlame<CommitKey>) {
+        for (cohort, line_count) in blame.cohort_stats() {
+            *self.running_cohort_stats.entry(cohort).or_insert(0) += line_count as i64;
+        }
+        self.groups
+            .add(path.as_ref(), signed_cohort_stats(&blame, 1));
+        self.current_churn.added += blame.total_lines() as u64;
+        self.skipped_files.remove(&path);
+        self.paths_seen.insert(path.clone());
+        self.file_blames.insert(path, blame);
+    }
+
+    fn subtract_from_running_stats(&mut self, path: &BStr, file_blame: &FileBlame<CommitKey>) {
+        for (cohort, line_count) in file_blame.cohort_stats() {
+            self.running_cohort_stats
+                .entry(cohort)
+                .and_modify(|v| *v -= line_count as i64);
+        }
+        self.groups.add(path, signed_cohort_stats(file_blame, -1));
+    }
+
+    pub fn rename_file(&mut self, old_path: BString, new_path: BString) -> Result<()> {
+        if self.skipped_files.remove(&old_path) {
+            if let Some(replaced) = self.file_blames.remove(&new_path) {
+                self.subtract_from_running_stats(new_path.as_ref(), &replaced);
+            }
+            self.skipped_files.insert(new_path);
+            return Ok(());
+        }
+        self.skipped_files.remove(&new_path);
         let file_blame = self
             .file_blames
             .remove(&old_path)
-            .ok_or_else(|| format!("File not found for rename: {:?}", old_path))?;
-        self.file_blames.insert(new_path.clone(), file_blame);
+            .ok_or_else(|| TheseusError::FileNotFound(old_path.clone()))?;
+        // The lines keep their cohorts, but can move to another extension or directory
+        if !self.groups.is_empty() {
+            let stats = signed_cohort_stats(&file_blame, 1);
+            self.groups.add(
+                old_path.as_ref(),
+                stats.iter().map(|(cohort, count)| (*cohort, -count)),
+            );
+            self.groups.add(new_path.as_ref(), stats);
+        }
+        self.paths_seen.insert(new_path.clone());
+        // The destination can still have a blame, e.g. with rename chains inside one commit.
+        // The renamed file replaces it, so the replaced file's lines leave the running stats.
+        if let Some(replaced) = self.file_blames.insert(new_path.clone(), file_blame) {
+            self.subtract_from_running_stats(new_path.as_ref(), &replaced);
+        }
         Ok(())
     }
 
-    pub fn modify_file(&mut self, path: &BString, line_diffs: LineDiffs<CommitKey>) {
-        if let Some(file_blame) = self.file_blames.get_mut(path) {
-            let old_blame = file_blame.clone();
-            let new_blame = old_blame.apply_line_diffs(line_diffs.clone());
-            let mut cohort_diff: std::collections::HashMap<CommitKey, i64> =
-                std::collections::HashMap::new();
-            for (cohort, line_count) in old_blame.cohort_stats() {
-                *cohort_diff.entry(cohort).or_insert(0) -= line_count as i64;
-            }
-            for (cohort, line_count) in new_blame.cohort_stats() {
-                *cohort_diff.entry(cohort).or_insert(0) += line_count as i64;
-            }
+    pub fn modify_file(&mut self, path: &BString, line_diffs: LineDiffs<CommitKey>) -> Result<()> {
+        if self.skipped_files.contains(path) {
+            return Ok(());
+        }
+        let file_blame = self
+            .file_blames
+            .get_mut(path)
+            .ok_or_else(|| TheseusError::FileNotFound(path.clone()))?;
+        for (_, inserted, cohort) in &line_diffs {
+            *self.lines_added.entry(*cohort).or_insert(0) += inserted.len() as u64;
+        }
+        let churn = CommitChurn::of_line_diffs(&line_diffs);
+        self.current_churn.added += churn.added;
+        self.current_churn.removed += churn.removed;
+        let old_blame = file_blame.clone();
+        let new_blame = old_blame
+            .apply_line_diffs(line_diffs.clone())
+            .map_err(|reason| TheseusError::InvalidLineDiffs {
+                path: path.clone(),
+                reason,
+            })?;
+        let mut cohort_diff: std::collections::HashMap<CommitKey, i64> =
+            std::collections::HashMap::new();
+        for (cohort, line_count) in old_blame.cohort_stats() {
+            *cohort_diff.entry(cohort).or_insert(0) -= line_count as i64;
+        }
+        for (cohort, line_count) in new_blame.cohort_stats() {
+            *cohort_diff.entry(cohort).or_insert(0) += line_count as i64;
+        }
 
-            for (cohort, delta) in cohort_diff {
-                self.running_cohort_stats
-                    .entry(cohort)
-                    .and_modify(|v| *v += delta)
-                    .or_insert(delta);
-            }
-            *file_blame = new_blame;
-        } else {
-            panic!("File not found for modify: {:?}", path);
+        self.groups.add(
+            path.as_ref(),
+            cohort_diff.iter().map(|(cohort, delta)| (*cohort, *delta)),
+        );
+        for (cohort, delta) in cohort_diff {
+            self.running_cohort_stats
+                .entry(cohort)
+                .and_modify(|v| *v += delta)
+                .or_insert(delta);
         }
+        *file_blame = new_blame;
+        Ok(())
     }
 
-    pub fn handle_action(&mut self, action: Action<CommitKey>) {
+    pub fn handle_action(&mut self, action: Action<CommitKey>) -> Result<()> {
         match action {
             Action::AddFile {
                 path,
                 total_lines,
                 cohort,
-            } => self.add_file(&path, total_lines, cohort),
-            Action::DeleteFile { path } => self.delete_file(&path),
-            Action::RenameFile { old_path, new_path } => {
-                self.rename_file(old_path, new_path).unwrap();
+                id,
+            } => {
+                let deleted_blame =
+                    id.and_then(|id| self.deleted_blames.remove(&(path.clone(), id)));
+                match deleted_blame {
+                    Some(blame) => self.revive_file(path, blame),
+                    None => self.add_file(&path, total_lines, cohort),
+                }
             }
-            Action::ModifyFile { path, line_diffs } => self.modify_file(&path, line_diffs),
+            Action::DeleteFile { path, id } => {
+                if let (true, Some(blame)) = (self.revive_deleted, self.take_file(&path)?) {
+                    self.remember_deleted(path, id, blame);
+                }
+            }
+            Action::SkipFile { path } => self.skip_file(path),
+            Action::RenameFile { old_path, new_path } => self.rename_file(old_path, new_path)?,
+            Action::ModifyFile { path, line_diffs } => self.modify_file(&path, line_diffs)?,
             Action::FinishCommit => {
-                self.commit_results.push(self.repository_cohort_stats());
+                self.churn.push(std::mem::take(&mut self.current_churn));
+                if !self.final_only {
+                    self.push_results()?;
+                }
             }
             Action::SetCommitId(id) => {
                 self.set_commit_id(id);
             }
         }
+        Ok(())
+    }
+    // The number of lines in the tracked files, skipped files excluded
+    pub fn total_lines(&self) -> u64 {
+        self.file_blames
+            .values()
+            .map(|blame| blame.total_lines() as u64)
+            .sum()
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.file_blames.len()
+    }
+
+    // Records the stats of the current commit
+    fn push_results(&mut self) -> Result<()> {
+        self.commit_results
+            .push(self.repository_cohort_stats())
+            .map_err(TheseusError::ResultsSpool)?;
+        if let Some(ever_written) = &mut self.ever_written {
+            let lines_added = self
+                .lines_added
+                .iter()
+                .filter(|(cohort, _)| !self.uncredited.contains(cohort))
+                .map(|(cohort, lines)| (*cohort, *lines as i64))
+                .collect();
+            ever_written
+                .push(lines_added)
+                .map_err(TheseusError::ResultsSpool)?;
+        }
+        self.groups.record(&self.uncredited);
+        Ok(())
     }
+
+    // The credited cohorts that still have lines, the ones that dropped to 0 would be all-zero series
     pub fn repository_cohort_stats(&self) -> Vec<(CommitKey, i64)>
     where
         CommitKey: Keyable,
     {
         self.running_cohort_stats
             .iter()
+            .filter(|(k, v)| **v != 0 && !self.uncredited.contains(k))
             .map(|(k, v)| (*k, *v))
             .collect()
     }
 }
 
+// A file's cohort stats times sign, 1 to add them to running stats or -1 to take them out
+fn signed_cohort_stats<CommitKey: Keyable>(
+    blame: &FileBlame<CommitKey>,
+    sign: i64,
+) -> Vec<(CommitKey, i64)> {
+    blame
+        .cohort_stats()
+        .into_iter()
+        .map(|(cohort, count)| (cohort, sign * count as i64))
+        .collect()
+}
+
 pub struct BlameProcessor<CommitKey>
 where
     CommitKey: Keyable,
 {
     sender: Sender<Action<CommitKey>>,
-    join_handle: Option<JoinHandle<RepositoryBlameSnapshot<CommitKey>>>,
+    join_handle: Option<JoinHandle<Result<RepositoryBlameSnapshot<CommitKey>>>>,
 }
 
 impl<CommitKey> BlameProcessor<CommitKey>
 where
     CommitKey: Keyable + Send + 'static,
 {
-    pub fn new(initial_commit_id: gix::ObjectId) -> Self {
-        let (sender, receiver) = unbounded();
-        let mut snapshot = RepositoryBlameSnapshot::new(initial_commit_id);
+    // Starts applying actions to the given snapshot, usually an empty one, in a background thread
+    pub fn new(snapshot: RepositoryBlameSnapshot<CommitKey>) -> Self {
+        Self::with_observer(snapshot, |_| Ok(()))
+    }
+
+    // Like new, but shows each action to the observer before applying it, e.g. to record them
+    pub fn with_observer(
+        snapshot: RepositoryBlameSnapshot<CommitKey>,
+        observer: impl FnMut(&Action<CommitKey>) -> Result<()> + Send + 'static,
+    ) -> Self {
+        Self::with_hooks(snapshot, DEFAULT_QUEUE_SIZE, observer, |_| Ok(()))
+    }
 
-        let join_handle = spawn(move || {
+    // Like with_observer, and also shows the snapshot to on_commit after each commit is finished,
+    // e.g. to stream the composition at each commit. Sending blocks while `queue_size` actions are
+    // already waiting.
+    pub fn with_hooks(
+        mut snapshot: RepositoryBlameSnapshot<CommitKey>,
+        queue_size: usize,
+        mut observer: impl FnMut(&Action<CommitKey>) -> Result<()> + Send + 'static,
+        mut on_commit: impl FnMut(&RepositoryBlameSnapshot<CommitKey>) -> Result<()> + Send + 'static,
+    ) -> Self {
+        let (sender, receiver) = bounded(queue_size);
+
+        // If an action fails we stop consuming, which drops the receiver and makes
+        // every further send fail, so the producers stop too.
+        let join_handle = spawn(move || -> Result<RepositoryBlameSnapshot<CommitKey>> {
             for action in receiver {
-                snapshot.handle_action(action);
+                observer(&action)?;
+                let finishes_commit = matches!(action, Action::FinishCommit);
+                snapshot.handle_action(action)?;
+                if finishes_commit {
+                    on_commit(&snapshot)?;
+                }
+            }
+            if snapshot.final_only {
+                snapshot.push_results()?;
             }
             snapshot
+                .commit_results
+                .flush()
+                .map_err(TheseusError::ResultsSpool)?;
+            if let Some(ever_written) = &mut snapshot.ever_written {
+                ever_written.flush().map_err(TheseusError::ResultsSpool)?;
+            }
+            Ok(snapshot)
         });
 
         Self {
@@ -154,13 +520,121 @@ where
         self.sender.clone()
     }
 
-    pub fn finish(mut self) -> Vec<Vec<(CommitKey, i64)>> {
+    // Waits for all the actions to be processed and returns the final snapshot
+    pub fn finish(mut self) -> Result<RepositoryBlameSnapshot<CommitKey>> {
         drop(self.sender);
         self.join_handle
             .take()
             .unwrap()
             .join()
-            .unwrap()
-            .commit_results
+            .map_err(|_| TheseusError::ProcessorStopped)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+
+    fn empty_snapshot() -> RepositoryBlameSnapshot<u32> {
+        RepositoryBlameSnapshot::new(gix::ObjectId::null(gix::hash::Kind::Sha1))
+    }
+
+    // The running stats can keep cohorts that dropped to 0 lines, which is fine
+    fn nonzero_running_stats(snapshot: &RepositoryBlameSnapshot<u32>) -> HashMap<u32, i64> {
+        snapshot
+            .running_cohort_stats
+            .iter()
+            .filter(|(_, count)| **count != 0)
+            .map(|(cohort, count)| (*cohort, *count))
+            .collect()
+    }
+
+    fn stats_from_file_blames(snapshot: &RepositoryBlameSnapshot<u32>) -> HashMap<u32, i64> {
+        let mut stats = HashMap::new();
+        for blame in snapshot.file_blames.values() {
+            for (cohort, count) in blame.cohort_stats() {
+                *stats.entry(cohort).or_insert(0) += count as i64;
+            }
+        }
+        stats
+    }
+
+    #[test]
+    fn test_rename_onto_existing_path_keeps_stats_consistent() {
+        let mut snapshot = empty_snapshot();
+        snapshot.add_file(&BString::from("a.rs"), 10, 1);
+        snapshot.add_file(&BString::from("b.rs"), 5, 2);
+        snapshot
+            .rename_file(BString::from("a.rs"), BString::from("b.rs"))
+            .unwrap();
+        assert_eq!(
+            nonzero_running_stats(&snapshot),
+            stats_from_file_blames(&snapshot)
+        );
+    }
+
+    #[test]
+    fn test_rename_onto_existing_path_drops_replaced_lines() {
+        let mut snapshot = empty_snapshot();
+        snapshot.add_file(&BString::from("a.rs"), 10, 1);
+        snapshot.add_file(&BString::from("b.rs"), 5, 2);
+        snapshot
+            .rename_file(BString::from("a.rs"), BString::from("b.rs"))
+            .unwrap();
+        assert_eq!(snapshot.file_count(), 1);
+        assert_eq!(snapshot.total_lines(), 10);
+        assert_eq!(nonzero_running_stats(&snapshot), HashMap::from([(1, 10)]));
+    }
+
+    #[test]
+    fn test_churn_of_line_diffs_sums_the_hunks() {
+        let line_diffs: LineDiffs<usize> =
+            vec![(0..2, 0..3, 1), (5..5, 6..8, 1), (9..10, 11..11, 1)];
+        assert_eq!(
+            CommitChurn::of_line_diffs(&line_diffs),
+            CommitChurn {
+                added: 5,
+                removed: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_cohorts_without_lines_are_left_out_of_the_stats() {
+        let mut snapshot = empty_snapshot();
+        snapshot.add_file(&BString::from("a.rs"), 10, 1);
+        snapshot.add_file(&BString::from("b.rs"), 5, 2);
+        snapshot.delete_file(&BString::from("b.rs")).unwrap();
+        assert_eq!(snapshot.running_cohort_stats.get(&2), Some(&0));
+        assert_eq!(snapshot.repository_cohort_stats(), vec![(1, 10)]);
+    }
+
+    proptest! {
+        #[test]
+        fn pbt_rename_preserves_running_stats(
+            files in prop::collection::vec((1u32..50, 0u32..5), 1..10),
+            renames in prop::collection::vec((any::<Index>(), 0u32..3), 0..10),
+        ) {
+            let mut snapshot = empty_snapshot();
+            for (i, (lines, cohort)) in files.iter().enumerate() {
+                snapshot.add_file(&BString::from(format!("file{i}.rs")), *lines, *cohort);
+            }
+            let before = nonzero_running_stats(&snapshot);
+            prop_assert_eq!(&stats_from_file_blames(&snapshot), &before);
+
+            for (n, (index, dir)) in renames.iter().enumerate() {
+                let mut paths: Vec<BString> = snapshot.file_blames.keys().cloned().collect();
+                paths.sort();
+                let old_path = paths[index.index(paths.len())].clone();
+                let new_path = BString::from(format!("dir{dir}/renamed{n}.rs"));
+                snapshot.rename_file(old_path, new_path).unwrap();
+
+                prop_assert_eq!(snapshot.file_blames.len(), files.len());
+                prop_assert_eq!(&nonzero_running_stats(&snapshot), &before);
+                prop_assert_eq!(&stats_from_file_blames(&snapshot), &before);
+            }
+        }
     }
 }
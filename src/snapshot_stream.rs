@@ -0,0 +1,81 @@
+// Writes the composition at each analyzed commit as one NDJSON line as soon as the commit is done,
+// e.g. to feed a time-series database while a long analysis is still running:
+// {"commit":"1a2b3c4","date":"2019-03-01 12:00:00","cohorts":{"2018":120,"2019":30}}
+// The cohorts are keyed by the year of the first commit of each cohort, so with the default
+// per-commit cohorts they're the years the lines were written in.
+
+use crate::blame::Keyable;
+use crate::error::{BoxError, Result, TheseusError};
+use crate::repo_blame_snapshot::RepositoryBlameSnapshot;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize)]
+struct SnapshotLine<'a> {
+    commit: &'a str,
+    date: &'a str,
+    cohorts: BTreeMap<u32, i64>,
+}
+
+pub struct SnapshotStream<K: Keyable> {
+    path: PathBuf,
+    writer: Box<dyn Write + Send>,
+    // The short hash and time of each commit still to be finished, in order
+    commits: std::vec::IntoIter<(String, String)>,
+    year_of: HashMap<K, u32>,
+}
+
+impl<K: Keyable> SnapshotStream<K> {
+    // `-` writes to stdout
+    pub fn create(
+        path: &Path,
+        commits: Vec<(String, String)>,
+        year_of: HashMap<K, u32>,
+    ) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(std::io::stdout())
+        } else {
+            let file = File::create(path).map_err(|e| write_error(path, e.into()))?;
+            Box::new(BufWriter::new(file))
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            writer,
+            commits: commits.into_iter(),
+            year_of,
+        })
+    }
+
+    // Writes the line of the commit the snapshot just finished, and flushes it right away
+    pub fn write(&mut self, snapshot: &RepositoryBlameSnapshot<K>) -> Result<()> {
+        let Some((commit, date)) = self.commits.next() else {
+            return Ok(());
+        };
+        let mut cohorts = BTreeMap::new();
+        for (cohort, lines) in snapshot.repository_cohort_stats() {
+            let year = self.year_of.get(&cohort).copied().unwrap_or_default();
+            *cohorts.entry(year).or_insert(0) += lines;
+        }
+        let line = SnapshotLine {
+            commit: &commit,
+            date: &date,
+            cohorts,
+        };
+        serde_json::to_writer(&mut self.writer, &line)
+            .map_err(|e| write_error(&self.path, e.into()))?;
+        self.writer
+            .write_all(b"\n")
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| write_error(&self.path, e.into()))
+    }
+}
+
+fn write_error(path: &Path, source: BoxError) -> TheseusError {
+    TheseusError::SnapshotStreamWrite {
+        path: path.to_owned(),
+        source,
+    }
+}
@@ -26,15 +26,20 @@
 
 import argparse
 import dateutil.parser
+import hashlib
 import itertools
 import json
 import matplotlib
 from matplotlib import pyplot
 import numpy
+import re
+import sys
 
 
-def generate_n_colors(n: int) -> list[tuple[float, float, float]]:
-    vs = numpy.linspace(0.4, 0.9, 6)
+def generate_n_colors(
+    n: int, low: float = 0.4, high: float = 0.9
+) -> list[tuple[float, float, float]]:
+    vs = numpy.linspace(low, high, 6)
     colors = [(0.9, 0.4, 0.4)]
 
     def euclidean(a, b):
@@ -49,6 +54,46 @@ def generate_n_colors(n: int) -> list[tuple[float, float, float]]:
     return colors
 
 
+def palette_colors(palette: str, n: int, theme: str) -> list:
+    if palette == "distinct":
+        # Brighter colors stand out better against a dark background
+        if theme == "dark":
+            return generate_n_colors(n, low=0.5, high=1.0)
+        return generate_n_colors(n)
+    # The darkest blues of cividis get lost against a dark background
+    low = 0.25 if theme == "dark" else 0.0
+    return list(matplotlib.colormaps[palette](numpy.linspace(low, 1.0, n)))
+
+
+# The span of years the stable colors are spread over, so a year gets the same color in every chart
+STABLE_FIRST_YEAR = 1970
+STABLE_LAST_YEAR = 2040
+YEAR_PATTERN = re.compile(r"\b(19|20)\d\d\b")
+
+
+def stable_colors(
+    labels: list[str], palette: str, theme: str, reverse: bool
+) -> list:
+    span = STABLE_LAST_YEAR - STABLE_FIRST_YEAR + 1
+    table = palette_colors(palette, span, theme)
+    if reverse:
+        table.reverse()
+    colors = []
+    for label in labels:
+        match = YEAR_PATTERN.search(label)
+        # The script's own max-n bucket is "other", the one --top-n makes is "Other"
+        if label.lower() == "other":
+            colors.append((0.6, 0.6, 0.6))
+        elif match:
+            year = min(max(int(match.group(0)), STABLE_FIRST_YEAR), STABLE_LAST_YEAR)
+            colors.append(table[year - STABLE_FIRST_YEAR])
+        else:
+            # Labels without a year, like a first commit cohort, still get the same color everywhere
+            digest = hashlib.md5(label.encode("utf-8")).digest()
+            colors.append(table[int.from_bytes(digest[:4], "big") % span])
+    return colors
+
+
 def stack_plot(
     input_fn: str,
     display: bool = False,
@@ -56,37 +101,86 @@ def stack_plot(
     max_n: int = 40,
     normalize: bool = False,
     title: str = "",
+    subtitle: str = "",
+    caption: str = "",
+    theme: str = "light",
+    palette: str = "cividis",
+    palette_reverse: bool = False,
+    style: str = "",
+    format: str = "",
+    stable: bool = False,
 ) -> None:
     if not display:
         matplotlib.use("Agg")
-    with open(input_fn) as f:
-        data = json.load(f)
+    # "-" reads the data from stdin, which is how gix-of-theseus passes it
+    if input_fn == "-":
+        data = json.load(sys.stdin)
+    else:
+        with open(input_fn) as f:
+            data = json.load(f)
+    # The look that has no flag of its own: colors, font, size and legend position
+    look = {}
+    if style:
+        with open(style) as f:
+            look = {k: v for k, v in json.load(f).items() if v is not None}
+    if "font_family" in look:
+        matplotlib.rcParams["font.family"] = look["font_family"]
+    if "font_size" in look:
+        matplotlib.rcParams["font.size"] = look["font_size"]
+    figsize = (look.get("width", 16), look.get("height", 12))
+    dpi = look.get("dpi", 120)
     y = numpy.array(data["y"])
     if y.shape[0] > max_n:
         js = sorted(range(len(data["labels"])), key=lambda j: max(y[j]), reverse=True)
         other_sum = y[js[max_n:]].sum(axis=0)
-        top_js = sorted(js[:max_n], key=lambda j: data["labels"][j])
+        # The labels are sorted from the oldest cohort to the newest, which the colors follow
+        top_js = sorted(js[:max_n])
         y = numpy.array([y[j] for j in top_js] + [other_sum])
         labels = [data["labels"][j] for j in top_js] + ["other"]
     else:
         labels = data["labels"]
     if normalize:
         y = 100.0 * numpy.array(y) / numpy.sum(y, axis=0)
-    pyplot.figure(figsize=(16, 12), dpi=120)
-    pyplot.style.use("ggplot")
-    if title:
-        pyplot.title(title)
-    ts = [dateutil.parser.parse(t) for t in data["ts"]]
-    colors = generate_n_colors(len(labels))
+    # The figure background comes from the style in use when the figure is created
+    if theme == "dark":
+        pyplot.style.use("dark_background")
+        pyplot.figure(figsize=figsize, dpi=dpi)
+    else:
+        pyplot.figure(figsize=figsize, dpi=dpi)
+        pyplot.style.use("ggplot")
+    if title and subtitle:
+        pyplot.suptitle(title, fontsize=16)
+        pyplot.title(subtitle)
+    elif title or subtitle:
+        pyplot.title(title or subtitle)
+    if caption:
+        pyplot.figtext(0.5, 0.01, caption, ha="center", fontsize=10)
+    # With --relative-time, the timestamps are weeks since the first snapshot instead of dates
+    relative = all(t.isdigit() for t in data["ts"])
+    if relative:
+        ts = [int(t) for t in data["ts"]]
+        pyplot.xlabel("Weeks since the first commit")
+    else:
+        ts = [dateutil.parser.parse(t) for t in data["ts"]]
+    if look.get("colors"):
+        colors = [look["colors"][i % len(look["colors"])] for i in range(len(labels))]
+    elif stable:
+        colors = stable_colors(labels, palette, theme, palette_reverse)
+    else:
+        colors = palette_colors(palette, len(labels), theme)
+    if palette_reverse and not stable:
+        colors.reverse()
     pyplot.stackplot(ts, y, labels=labels, colors=colors)
-    pyplot.legend(loc=2)
+    legend_position = look.get("legend_position", "upper left")
+    if legend_position != "none":
+        pyplot.legend(loc=legend_position)
     if normalize:
         pyplot.ylabel("Share of lines of code (%)")
         pyplot.ylim([0, 100])
     else:
         pyplot.ylabel("Lines of code")
     print(f"Writing stackplot image to {outfile}")
-    pyplot.savefig(outfile)
+    pyplot.savefig(outfile, format=format or None)
     pyplot.tight_layout()
     if display:
         pyplot.show()
@@ -113,6 +207,52 @@ def stack_plot_cmdline() -> None:
         type=str,
         help='Title of the plot (default: "")',
     )
+    parser.add_argument(
+        "--subtitle",
+        default=None,
+        type=str,
+        help='Subtitle of the plot, shown under the title (default: "")',
+    )
+    parser.add_argument(
+        "--caption",
+        default=None,
+        type=str,
+        help='Caption shown under the plot (default: "")',
+    )
+    parser.add_argument(
+        "--theme",
+        default="light",
+        choices=["light", "dark"],
+        help="Color theme of the plot (default: %(default)s)",
+    )
+    parser.add_argument(
+        "--palette",
+        default="cividis",
+        choices=["cividis", "distinct"],
+        help="Colors of the cohorts (default: %(default)s)",
+    )
+    parser.add_argument(
+        "--palette-reverse",
+        action="store_true",
+        help="Give the newest cohorts the colors of the oldest ones, and vice versa",
+    )
+    parser.add_argument(
+        "--style",
+        default="",
+        type=str,
+        help="JSON file with the colors, font, size and legend position of the plot",
+    )
+    parser.add_argument(
+        "--stable",
+        action="store_true",
+        help="Color each cohort by the year in its label rather than by its position, so a year has the same color in every chart",
+    )
+    parser.add_argument(
+        "--format",
+        default="",
+        choices=["png", "svg"],
+        help="Format of the image (default: from the extension of the output file)",
+    )
     parser.add_argument(
         "--normalize", action="store_true", help="Normalize the plot to 100%%"
     )
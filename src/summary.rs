@@ -0,0 +1,58 @@
+// A small machine-readable summary of an analysis run, written next to cohorts.json.
+
+use crate::churn::LargeCommit;
+use crate::formatter::CohortData;
+use crate::theseus::TheseusResult;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub commits_processed: usize,
+    pub first_commit_time: Option<String>,
+    pub last_commit_time: Option<String>,
+    pub files_at_head: usize,
+    pub lines_at_head: u64,
+    pub cohorts: usize,
+    // Files left out of the analysis by the filetype filter
+    pub files_skipped: usize,
+    // Files left out of the analysis for having fewer lines than --min-lines
+    pub small_files_skipped: usize,
+    // Files left out of the analysis from the commit where they couldn't be read or diffed
+    #[serde(default)]
+    pub files_failed: usize,
+    // Renames and copies found between the analyzed commits
+    #[serde(default)]
+    pub renames_detected: usize,
+    #[serde(default)]
+    pub copies_detected: usize,
+    // The distinct paths the analyzed files ever had, a denominator for ratios over the whole history
+    #[serde(default)]
+    pub files_ever_seen: usize,
+    // The commits with a suspiciously large diff, e.g. vendored or generated code
+    #[serde(default)]
+    pub large_commits: Vec<LargeCommit>,
+    pub wall_clock_seconds: f64,
+}
+
+impl RunSummary {
+    pub fn new(result: &TheseusResult, cohort_data: &CohortData, wall_clock: Duration) -> Self {
+        let commits = result.analyzed_commits();
+        Self {
+            commits_processed: commits.len(),
+            first_commit_time: commits.first().map(|info| info.time_string.clone()),
+            last_commit_time: commits.last().map(|info| info.time_string.clone()),
+            files_at_head: result.stats.files_at_head,
+            lines_at_head: result.stats.lines_at_head,
+            cohorts: cohort_data.labels.len(),
+            files_skipped: result.stats.files_skipped,
+            small_files_skipped: result.stats.small_files_skipped,
+            files_failed: result.failures.len(),
+            renames_detected: result.stats.renames_detected,
+            copies_detected: result.stats.copies_detected,
+            files_ever_seen: result.stats.files_ever_seen,
+            large_commits: result.stats.large_commits.clone(),
+            wall_clock_seconds: wall_clock.as_secs_f64(),
+        }
+    }
+}
@@ -0,0 +1,206 @@
+// How much of the code each year added is still there at HEAD.
+// Written to survival.csv next to cohorts.json, along with the half-life of each year in
+// half_life.json.
+
+use crate::error::{Result, TheseusError};
+use crate::theseus::TheseusResult;
+use chrono::{DateTime, FixedOffset};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct YearSurvival {
+    pub year: u32,
+    // Every line the year's commits added, including the ones deleted since
+    pub lines_added: u64,
+    pub lines_surviving: u64,
+}
+
+impl YearSurvival {
+    pub fn survival_percent(&self) -> f64 {
+        if self.lines_added == 0 {
+            return 0.0;
+        }
+        100.0 * self.lines_surviving as f64 / self.lines_added as f64
+    }
+}
+
+pub fn survival_by_year(result: &TheseusResult) -> Result<Vec<YearSurvival>> {
+    let commit_infos = &result.commit_cohort_info;
+    let mut by_year: BTreeMap<u32, YearSurvival> = commit_infos
+        .iter()
+        .map(|info| {
+            let survival = YearSurvival {
+                year: info.year,
+                lines_added: 0,
+                lines_surviving: 0,
+            };
+            (info.year, survival)
+        })
+        .collect();
+    for (commit_key, lines) in &result.lines_added {
+        let year = commit_infos[*commit_key].year;
+        by_year.get_mut(&year).unwrap().lines_added += lines;
+    }
+    let last_snapshot = result
+        .cohort_data
+        .last()
+        .map_err(TheseusError::ResultsSpool)?;
+    for (commit_key, lines) in last_snapshot.unwrap_or_default() {
+        let year = commit_infos[commit_key].year;
+        by_year.get_mut(&year).unwrap().lines_surviving += lines.max(0) as u64;
+    }
+    Ok(by_year.into_values().collect())
+}
+
+// How many weeks each year's code takes to go from its peak down to half of it. The time it drops
+// below half is interpolated between the two snapshots around the drop. None for the years that
+// never lost half their code, or never had any.
+pub fn half_life_by_year(result: &TheseusResult) -> Result<BTreeMap<u32, Option<f64>>> {
+    let commit_infos = &result.commit_cohort_info;
+    let snapshot_infos = &commit_infos[commit_infos.len() - result.cohort_data.len()..];
+    let times: Vec<DateTime<FixedOffset>> = snapshot_infos.iter().map(|info| info.time).collect();
+    let mut lines_by_year: BTreeMap<u32, Vec<i64>> = commit_infos
+        .iter()
+        .map(|info| (info.year, vec![0; times.len()]))
+        .collect();
+    for (snapshot_idx, snapshot) in result.cohort_data.iter().enumerate() {
+        let snapshot = snapshot.map_err(TheseusError::ResultsSpool)?;
+        for (commit_key, lines) in &snapshot {
+            let year = commit_infos[*commit_key].year;
+            lines_by_year.get_mut(&year).unwrap()[snapshot_idx] += lines;
+        }
+    }
+    Ok(lines_by_year
+        .into_iter()
+        .map(|(year, lines)| (year, half_life(&lines, &times)))
+        .collect())
+}
+
+fn half_life(lines: &[i64], times: &[DateTime<FixedOffset>]) -> Option<f64> {
+    // The first snapshot at the peak
+    let peak_idx = (0..lines.len()).rev().max_by_key(|&i| lines[i])?;
+    let half = lines[peak_idx] as f64 / 2.0;
+    if half <= 0.0 {
+        return None;
+    }
+    let drop_idx = (peak_idx + 1..lines.len()).find(|&i| (lines[i] as f64) < half)?;
+    let (before, after) = (times[drop_idx - 1], times[drop_idx]);
+    let fraction =
+        (lines[drop_idx - 1] as f64 - half) / (lines[drop_idx - 1] - lines[drop_idx]) as f64;
+    let seconds_to_drop = (before - times[peak_idx]).num_seconds() as f64
+        + fraction * (after - before).num_seconds() as f64;
+    Some(seconds_to_drop / (7.0 * 24.0 * 3600.0))
+}
+
+// Writes the half-lives as a JSON object from year to weeks, with "none" for the years that never
+// lost half their code
+pub fn write_half_life_json(
+    half_lives: &BTreeMap<u32, Option<f64>>,
+    writer: impl Write,
+) -> io::Result<()> {
+    let json: serde_json::Map<String, serde_json::Value> = half_lives
+        .iter()
+        .map(|(year, weeks)| {
+            let value = match weeks {
+                Some(weeks) => ((weeks * 10.0).round() / 10.0).into(),
+                None => "none".into(),
+            };
+            (year.to_string(), value)
+        })
+        .collect();
+    serde_json::to_writer_pretty(writer, &json)?;
+    Ok(())
+}
+
+pub fn write_csv(rows: &[YearSurvival], mut writer: impl Write) -> io::Result<()> {
+    writeln!(writer, "year,lines_added,lines_surviving,survival_percent")?;
+    for row in rows {
+        writeln!(
+            writer,
+            "{},{},{},{:.1}",
+            row.year,
+            row.lines_added,
+            row.lines_surviving,
+            row.survival_percent()
+        )?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{AnalysisOptions, Verbosity};
+    use crate::test_utils::TestRepo;
+    use crate::theseus::run_theseus;
+
+    #[test]
+    fn test_survival_by_year_counts_deleted_lines_as_added() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nx\ny\nd\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let options = AnalysisOptions {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+
+        let rows = survival_by_year(&run_theseus(repo.path(), &options).unwrap()).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                YearSurvival {
+                    year: 2019,
+                    lines_added: 4,
+                    lines_surviving: 2,
+                },
+                YearSurvival {
+                    year: 2021,
+                    lines_added: 2,
+                    lines_surviving: 2,
+                },
+            ]
+        );
+        assert_eq!(rows[0].survival_percent(), 50.0);
+
+        let mut csv = Vec::new();
+        write_csv(&rows, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "year,lines_added,lines_surviving,survival_percent\n2019,4,2,50.0\n2021,2,2,100.0\n"
+        );
+    }
+
+    #[test]
+    fn test_half_life_is_interpolated_from_the_peak() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nx\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nx\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let options = AnalysisOptions {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+
+        let half_lives = half_life_by_year(&run_theseus(repo.path(), &options).unwrap()).unwrap();
+        // 2019 has 3 of its 4 lines left in 2020 and 1 in 2021, so it's down to 2 halfway through,
+        // 366 + 182.5 days after its peak
+        let weeks = half_lives[&2019].unwrap();
+        assert!((weeks - 548.5 / 7.0).abs() < 1e-9, "{weeks}");
+        assert_eq!(half_lives[&2020], None);
+        assert_eq!(half_lives[&2021], None);
+
+        let mut json = Vec::new();
+        write_half_life_json(&half_lives, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"2019": 78.4, "2020": "none", "2021": "none"})
+        );
+    }
+}
@@ -0,0 +1,117 @@
+// Helpers to build tiny git repos with a scripted history for end-to-end tests.
+// We drive the git CLI so the repos look exactly like the ones users have.
+
+use std::process::Command;
+use tempfile::TempDir;
+
+pub struct TestRepo {
+    dir: TempDir,
+}
+
+impl TestRepo {
+    pub fn new() -> Self {
+        let repo = Self {
+            dir: TempDir::new().expect("failed to create temp dir"),
+        };
+        repo.git(&["init", "--quiet", "--initial-branch=main"]);
+        repo
+    }
+
+    pub fn path(&self) -> &str {
+        self.dir.path().to_str().expect("temp dir is not utf-8")
+    }
+
+    pub fn write(&self, path: &str, contents: impl AsRef<[u8]>) -> &Self {
+        let full_path = self.dir.path().join(path);
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(full_path, contents).unwrap();
+        self
+    }
+
+    /// Creates the directory and its parents, e.g. to `git mv` a file into it
+    pub fn mkdir(&self, path: &str) -> &Self {
+        std::fs::create_dir_all(self.dir.path().join(path)).unwrap();
+        self
+    }
+
+    pub fn remove(&self, path: &str) -> &Self {
+        std::fs::remove_file(self.dir.path().join(path)).unwrap();
+        self
+    }
+
+    /// Replaces the file at `path` with a symlink to `target`
+    #[cfg(unix)]
+    pub fn symlink(&self, path: &str, target: &str) -> &Self {
+        let full_path = self.dir.path().join(path);
+        if full_path.exists() {
+            std::fs::remove_file(&full_path).unwrap();
+        }
+        std::os::unix::fs::symlink(target, full_path).unwrap();
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn set_executable(&self, path: &str) -> &Self {
+        use std::os::unix::fs::PermissionsExt;
+        let full_path = self.dir.path().join(path);
+        std::fs::set_permissions(full_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        self
+    }
+
+    /// Commits everything in the worktree with the given author and committer date,
+    /// e.g. "2020-06-01T12:00:00+00:00".
+    pub fn commit(&self, date: &str) -> &Self {
+        self.git(&["add", "--all"]);
+        self.git_with_date(&["commit", "--quiet", "--allow-empty", "-m", date], date);
+        self
+    }
+
+    /// Like `commit`, but authored with the given email
+    pub fn commit_as(&self, date: &str, email: &str) -> &Self {
+        self.git(&["add", "--all"]);
+        let author = format!("--author=Someone <{email}>");
+        self.git_with_date(
+            &["commit", "--quiet", "--allow-empty", "-m", date, &author],
+            date,
+        );
+        self
+    }
+
+    /// Merges `branch` into the current branch with a merge commit at the given date
+    pub fn merge(&self, branch: &str, date: &str) -> &Self {
+        self.git_with_date(&["merge", "--quiet", "--no-ff", "-m", date, branch], date);
+        self
+    }
+
+    pub fn git(&self, args: &[&str]) -> String {
+        self.git_with_date(args, "2000-01-01T00:00:00+00:00")
+    }
+
+    fn git_with_date(&self, args: &[&str], date: &str) -> String {
+        let output = Command::new("git")
+            .current_dir(self.dir.path())
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .env("GIT_AUTHOR_DATE", date)
+            .env("GIT_COMMITTER_DATE", date)
+            .args([
+                "-c",
+                "user.name=Theseus",
+                "-c",
+                "user.email=theseus@example.com",
+            ])
+            .args(["-c", "core.autocrlf=false", "-c", "commit.gpgsign=false"])
+            .args(args)
+            .output()
+            .expect("failed to run git");
+        assert!(
+            output.status.success(),
+            "git {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+}
@@ -1,88 +1,575 @@
 use crate::actions::Action;
-use crate::blame::LineNumber;
-use crate::file_types::is_allowed_filetype;
-use crate::gix_helpers::{Granularity, get_blob_diff, list_commits_with_granularity};
-use crate::repo_blame_snapshot::BlameProcessor;
-use anyhow::Result;
-use gix::bstr::ByteSlice;
-use gix::date::time::CustomFormat;
+use crate::blame::{BlameSpan, FileBlame, Keyable, LineNumber};
+use crate::checkpoint::{self, Resumed};
+use crate::churn::{DEFAULT_LARGE_COMMIT_LINES, LargeCommit, large_commits};
+use crate::commit_cache::{self, SelectedCommit};
+use crate::commit_results::CommitResults;
+use crate::error::{Result, TheseusError};
+use crate::file_types::{PathFilter, file_extension, top_level_dir};
+use crate::formatter::{CohortData, FormatOptions, format_cohort_data};
+use crate::gix_helpers::{
+    Granularity, RevisionRange, diff_bytes, first_parent_commits, get_blob_diff, get_blob_size,
+    keep_most_recent, list_commits_with_granularity, list_every_commit, local_commit_time,
+    measure_size, resolve_commit, sample_every_nth, tag_commit_time,
+};
+use crate::options::{AnalysisOptions, Measure};
+use crate::profile::PhaseTimings;
+use crate::repo_blame_snapshot::{
+    BlameProcessor, CommitChurn, DEFAULT_QUEUE_SIZE, FileGroups, GroupSnapshot, GroupStats,
+    RepositoryBlameSnapshot,
+};
+use crate::snapshot_stream::SnapshotStream;
+use crate::worktree::{WorktreeChange, worktree_changes};
+use chrono::{DateTime, Datelike, FixedOffset, Weekday};
+use crossbeam_channel::Sender;
+use gix::bstr::{BStr, BString, ByteSlice};
 use gix::diff::object::TreeRefIter;
 use gix::diff::tree_with_rewrites;
 use gix::diff::tree_with_rewrites::{Action as DiffAction, Change, ChangeRef};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::IsTerminal;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use thread_local::ThreadLocal;
 
+// How often to print a progress line when stderr isn't a terminal
+const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+// How many of a commit's changes each thread gets to process before their actions are sent on
+const CHANGES_IN_FLIGHT_PER_THREAD: usize = 8;
+
 // Information about a commit that we use to make the graphs.
-// For now we only care about the year, though the time_string could
-// be used to plot weeks.
 pub struct CommitCohortInfo {
     pub id: gix::ObjectId,
+    // When the commit was made, in the time zone it was made in. time_string and year are made
+    // from it, for the labels and the ts of cohorts.json. Commits are compared by this time: the
+    // local time strings of commits made in different time zones don't sort in time order.
+    pub time: DateTime<FixedOffset>,
     pub time_string: String,
     pub year: u32,
+    // The first line of the commit message, for snapshots that come from a commit
+    pub summary: Option<String>,
 }
-pub struct TheseusResult {
+
+// The cohort key run_theseus uses: the index in commit_cohort_info of the commit that added a line.
+// Other cohort schemes can pick their own key type with run_theseus_with_cohorts.
+pub type CohortKey = usize;
+
+pub struct TheseusResult<K: Keyable = CohortKey> {
     //A table listing metadata for each commit
     //Mentions to "commit_idx" elsewhere refer to the index in this Vec
     pub commit_cohort_info: Vec<CommitCohortInfo>,
-    // One entry per commit, with the child vec being key,value pairs of commit idx + number of lines
-    pub cohort_data: Vec<Vec<(usize, i64)>>,
+    // One entry per commit, with the child vec being key,value pairs of cohort key + number of lines
+    pub cohort_data: CommitResults<K>,
+    // Every line each cohort ever added, including the ones that were later deleted
+    pub lines_added: Vec<(K, u64)>,
+    // The lines each commit added and removed, by commit_idx
+    pub churn: Vec<CommitChurn>,
+    // Every line each cohort had ever added at each commit, when asked for with AnalysisOptions::ever_written
+    pub ever_written: Option<CommitResults<K>>,
+    // The cohorts of each file extension at each commit, when asked for with AnalysisOptions::by_extension
+    pub by_extension: Option<Vec<GroupSnapshot<K>>>,
+    // The cohorts of each top-level directory at each commit, when asked for with AnalysisOptions::by_directory
+    pub by_directory: Option<Vec<GroupSnapshot<K>>>,
+    // The files that failed to be read or diffed, and were skipped instead of stopping the run
+    pub failures: Vec<FileFailure>,
+    pub stats: RunStats,
+}
+
+// A file whose change couldn't be read or diffed. It is left out of the analysis from that commit on.
+#[derive(Debug)]
+pub struct FileFailure {
+    pub commit: gix::ObjectId,
+    pub path: BString,
+    pub error: TheseusError,
+}
+
+// Counters about the whole run, as opposed to the per-commit data above.
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    pub files_at_head: usize,
+    pub lines_at_head: u64,
+    // Files that were added but not analyzed because of the filetype filter
+    pub files_skipped: usize,
+    // Files that were added but not analyzed because they had fewer lines than min_lines
+    pub small_files_skipped: usize,
+    // Renames and copies found by the tree diffs, before any path filtering
+    pub renames_detected: usize,
+    pub copies_detected: usize,
+    // The distinct paths the analyzed files ever had, including the deleted and renamed ones
+    pub files_ever_seen: usize,
+    // With accurate_baseline, the made-up commits at the start of commit_cohort_info that stand for
+    // the years before the first analyzed commit. They have no snapshots of their own.
+    pub baseline_commits: usize,
+    // The commits whose diff was at least AnalysisOptions::large_commit_lines lines
+    pub large_commits: Vec<LargeCommit>,
+    // How long listing the commits, loading their trees, diffing them and accumulating the blame took
+    pub phases: PhaseTimings,
+}
+
+impl<K: Keyable> TheseusResult<K> {
+    // The commits that were analyzed, without the made-up baseline ones
+    pub fn analyzed_commits(&self) -> &[CommitCohortInfo] {
+        &self.commit_cohort_info[self.stats.baseline_commits..]
+    }
+}
+
+impl TheseusResult<CohortKey> {
+    // The lines of each year's cohort at a snapshot, e.g. `composition_at(10)` for the code at the
+    // 11th analyzed commit. Only the last snapshot is kept with final_only. None past the last one.
+    pub fn composition_at(&self, snapshot_idx: usize) -> Result<Option<HashMap<u32, i64>>> {
+        let Some(snapshot) = self
+            .cohort_data
+            .get(snapshot_idx)
+            .map_err(TheseusError::ResultsSpool)?
+        else {
+            return Ok(None);
+        };
+        let mut composition = HashMap::new();
+        for (commit_idx, lines) in snapshot {
+            *composition
+                .entry(self.commit_cohort_info[commit_idx].year)
+                .or_insert(0) += lines;
+        }
+        Ok(Some(composition))
+    }
+}
+
+pub fn run_theseus(repo_path: &str, options: &AnalysisOptions) -> Result<TheseusResult> {
+    run_theseus_with_cohorts(repo_path, options, |commit_idx, _| commit_idx)
+}
+
+// Analyzes a repo and groups its lines into the labelled cohorts of cohorts.json, ready to plot or serialize.
+// Use run_theseus instead to get at the per-commit data and the run stats.
+pub fn analyze(
+    repo_path: &str,
+    options: &AnalysisOptions,
+    format_options: &FormatOptions,
+) -> Result<CohortData> {
+    let result = run_theseus(repo_path, options)?;
+    format_cohort_data(&result, format_options)
+}
+
+// The time of the commit a revision points to, like CommitCohortInfo::time,
+// e.g. to put a FormatOptions boundary at a release tag
+pub fn commit_time(repo_path: &str, spec: &str) -> Result<DateTime<FixedOffset>> {
+    let repo = open_repo(repo_path)?;
+    let id = resolve_commit(&repo, spec)?;
+    let commit = repo.find_commit(id).map_err(TheseusError::odb)?;
+    Ok(selected_commit(&commit)?.time)
+}
+
+// Like run_theseus, but the lines a commit adds are attributed to the cohort key
+// returned by `cohort_key(commit_idx, commit_info)` instead of to the commit's index.
+// This lets callers group lines by year, by author, or by any composite key.
+pub fn run_theseus_with_cohorts<K: Keyable>(
+    repo_path: &str,
+    options: &AnalysisOptions,
+    cohort_key: impl Fn(usize, &CommitCohortInfo) -> K,
+) -> Result<TheseusResult<K>> {
+    let repo = open_repo(repo_path)?;
+    if options.checkpoint.is_some() && options.include_worktree {
+        return Err(TheseusError::IncompatibleOptions(
+            "the worktree isn't a commit, it can't be saved in a checkpoint",
+        ));
+    }
+    if options.checkpoint.is_some() && options.final_only {
+        return Err(TheseusError::IncompatibleOptions(
+            "a checkpoint keeps the results of every commit, it can't be saved with final_only",
+        ));
+    }
+    if options.checkpoint.is_some() && options.ever_written {
+        return Err(TheseusError::IncompatibleOptions(
+            "a checkpoint doesn't keep the lines ever written at each commit",
+        ));
+    }
+    if options.checkpoint.is_some() && (options.by_extension || options.by_directory) {
+        return Err(TheseusError::IncompatibleOptions(
+            "a checkpoint doesn't keep the cohorts of each file extension or directory",
+        ));
+    }
+    if options.checkpoint.is_some() && options.accurate_baseline {
+        return Err(TheseusError::IncompatibleOptions(
+            "a checkpoint doesn't keep track of the blamed baseline",
+        ));
+    }
+    if options.checkpoint.is_some() && !options.authors.is_empty() {
+        return Err(TheseusError::IncompatibleOptions(
+            "a checkpoint doesn't keep track of the authors left out of the results",
+        ));
+    }
+    if options.skip_merges && !options.authors.is_empty() {
+        return Err(TheseusError::IncompatibleOptions(
+            "skip_merges credits a merge's lines to the commit before the branch, which may be another author's",
+        ));
+    }
+    if options.update
+        && (options.range.is_some() || options.since_tag.is_some() || options.until_tag.is_some())
+    {
+        return Err(TheseusError::IncompatibleOptions(
+            "an update analyzes the commits made since its checkpoint, it can't take a range",
+        ));
+    }
+    let resumed = match (&options.checkpoint, options.update) {
+        (Some(path), true) => Some(checkpoint::load(path, new_commit_results(options)?)?),
+        (None, true) => {
+            return Err(TheseusError::IncompatibleOptions(
+                "an update needs a checkpoint to start from",
+            ));
+        }
+        (_, false) => None,
+    };
+    let resumed_head = resumed.as_ref().map(|resumed| resumed.head);
+    let listing_start = Instant::now();
+    let mut weekly_commits = commits_to_analyze(&repo, options, resumed_head)?;
+    if options.skip_merges {
+        find_merged_branch_times(&repo, &mut weekly_commits, resumed_head, options)?;
+    }
+    let mut phases = PhaseTimings::default();
+    phases.record("commit listing", listing_start.elapsed());
+    analyze_selected_commits(
+        &repo,
+        repo_path,
+        weekly_commits,
+        resumed,
+        options,
+        cohort_key,
+        phases,
+    )
+}
+
+// Like run_theseus_with_cohorts, but analyzes exactly the given commits instead of picking weekly
+// ones, e.g. one per release tag. They are analyzed in the given order, each one diffed against
+// the one before it and the first one against an empty tree, so its lines all go to its cohort.
+// Sampling, ranges and checkpoints are about picking the commits, so they don't apply.
+pub fn run_theseus_on_commits<K: Keyable>(
+    repo_path: &str,
+    commit_ids: &[gix::ObjectId],
+    options: &AnalysisOptions,
+    cohort_key: impl Fn(usize, &CommitCohortInfo) -> K,
+) -> Result<TheseusResult<K>> {
+    if options.checkpoint.is_some() {
+        return Err(TheseusError::IncompatibleOptions(
+            "a checkpoint is updated with the commits made since, it can't be saved for a list of commits",
+        ));
+    }
+    let repo = open_repo(repo_path)?;
+    let listing_start = Instant::now();
+    let commits = commit_ids
+        .iter()
+        .map(|id| {
+            let commit = repo
+                .find_commit(*id)
+                .map_err(|e| TheseusError::RevisionNotFound {
+                    spec: id.to_string(),
+                    source: e.into(),
+                })?;
+            selected_commit(&commit)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut phases = PhaseTimings::default();
+    phases.record("commit listing", listing_start.elapsed());
+    analyze_selected_commits(&repo, repo_path, commits, None, options, cohort_key, phases)
 }
 
-pub fn run_theseus(
+// Diffs each selected commit against the one before it, and the first one against the checkpoint's
+// head when resuming or else an empty tree, and accumulates their blame
+fn analyze_selected_commits<K: Keyable>(
+    repo: &gix::Repository,
     repo_path: &str,
-    all_filetypes: bool,
-) -> Result<TheseusResult, Box<dyn std::error::Error>> {
-    let repo = gix::open(repo_path)?;
+    weekly_commits: Vec<SelectedCommit>,
+    resumed: Option<Resumed<K>>,
+    options: &AnalysisOptions,
+    cohort_key: impl Fn(usize, &CommitCohortInfo) -> K,
+    mut phases: PhaseTimings,
+) -> Result<TheseusResult<K>> {
+    let path_filter = PathFilter::from_options(options)?.with_text_attributes(repo)?;
+    if options.include_worktree && repo.workdir().is_none() {
+        return Err(TheseusError::BareRepo(repo_path.into()));
+    }
+    let resumed_head = resumed.as_ref().map(|resumed| resumed.head);
     let safe_repo = repo.clone().into_sync();
-    let weekly_commits = list_commits_with_granularity(&repo, Granularity::Weekly, None, None)?;
-    let processor = BlameProcessor::<usize>::new(weekly_commits[0].id);
-    let sender = processor.sender();
+    // When resuming, the first new commit is diffed against the last one the checkpoint covers
+    let (mut commit_infos, mut snapshot, mut base_tree_data) = match resumed {
+        Some(resumed) => {
+            let base_tree_data = repo
+                .find_commit(resumed.head)
+                .map_err(TheseusError::odb)?
+                .tree()
+                .map_err(TheseusError::odb)?
+                .detach()
+                .data;
+            (resumed.commit_infos, resumed.snapshot, base_tree_data)
+        }
+        None => {
+            let first_commit_id = weekly_commits.first().ok_or(TheseusError::EmptyRepo)?.id;
+            let snapshot = RepositoryBlameSnapshot::with_commit_results(
+                first_commit_id,
+                new_commit_results(options)?,
+            );
+            (Vec::new(), snapshot, Vec::new())
+        }
+    };
+    if options.verbosity.is_verbose() {
+        eprintln!(
+            "Analyzing {} weekly commits from {}",
+            weekly_commits.len(),
+            repo_path
+        );
+    }
+    snapshot.revive_deleted = options.revive_deleted;
+    snapshot.final_only = options.final_only;
+    if options.ever_written {
+        snapshot.ever_written = Some(new_commit_results(options)?);
+    }
+    snapshot.groups = file_groups(options);
+    let files_skipped = AtomicUsize::new(0);
+    let small_files_skipped = AtomicUsize::new(0);
+    let file_failures = Mutex::new(Vec::new());
 
-    //Each thread gets its own repo handle and its own diff cache
+    //Each thread gets its own repo handle and diff cache
     let tl = ThreadLocal::new();
     let get_thread_local_vars = || {
         tl.get_or(|| {
             let repo = safe_repo.clone().to_thread_local();
-            let platform = RefCell::new(repo.diff_resource_cache_for_tree_diff().unwrap());
-            (repo, platform)
+            let diff_platform = RefCell::new(repo.diff_resource_cache_for_tree_diff().unwrap());
+            ThreadState {
+                repo,
+                diff_platform,
+            }
         })
     };
-    let progress_bar = ProgressBar::new(weekly_commits.len() as u64).with_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta_precise}) {per_sec:0.1} {msg}")
-            .unwrap()
-            .progress_chars("=>-"),
-    );
+    // Blame the files of the first commit through its history, and add a commit for each year of it
+    let baseline = match weekly_commits.first() {
+        Some(first) if options.accurate_baseline && commit_infos.is_empty() => {
+            let has_history = repo
+                .find_commit(first.id)
+                .map_err(TheseusError::odb)?
+                .parent_ids()
+                .next()
+                .is_some();
+            if has_history {
+                Some(blame_baseline(
+                    repo,
+                    first.id,
+                    &path_filter,
+                    options,
+                    &get_thread_local_vars,
+                )?)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    let mut baseline_commit_idx = HashMap::new();
+    if let Some((files, skipped)) = &baseline {
+        files_skipped.fetch_add(*skipped, Ordering::Relaxed);
+        let first_id = weekly_commits[0].id;
+        let blamed_ids: HashSet<gix::ObjectId> = files
+            .iter()
+            .flat_map(|file| file.spans.iter().map(|(_, id)| *id))
+            .filter(|id| *id != first_id)
+            .collect();
+        let mut year_of = HashMap::new();
+        let mut newest_of_year: BTreeMap<u32, SelectedCommit> = BTreeMap::new();
+        for id in blamed_ids {
+            let commit = repo.find_commit(id).map_err(TheseusError::odb)?;
+            let commit = selected_commit(&commit)?;
+            year_of.insert(id, commit.year);
+            match newest_of_year.get(&commit.year) {
+                Some(newest) if newest.time >= commit.time => {}
+                _ => {
+                    newest_of_year.insert(commit.year, commit);
+                }
+            }
+        }
+        let mut year_idx = HashMap::new();
+        for (year, commit) in newest_of_year {
+            year_idx.insert(year, commit_infos.len());
+            commit_infos.push(CommitCohortInfo {
+                id: commit.id,
+                time: commit.time,
+                time_string: commit.time_string,
+                year,
+                summary: Some(commit.summary),
+            });
+        }
+        baseline_commit_idx = year_of
+            .into_iter()
+            .map(|(id, year)| (id, year_idx[&year]))
+            .collect();
+    }
+    let baseline_commits = if baseline.is_some() {
+        commit_infos.len()
+    } else {
+        0
+    };
+    let resumed_count = commit_infos.len();
+    // Only draw the progress bar for a human watching a terminal. When stderr is piped, e.g. in CI,
+    // an occasional plain line is printed instead, so the logs don't fill up with escape codes.
+    let plain_progress = !options.verbosity.is_quiet() && !std::io::stderr().is_terminal();
+    let progress_bar = if options.verbosity.is_quiet() || plain_progress {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(weekly_commits.len() as u64).with_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta_precise}) {per_sec:0.1} {msg}")
+                .unwrap()
+                .progress_chars("=>-"),
+        )
+    };
+    let merged_branch_times: Vec<Option<DateTime<FixedOffset>>> =
+        std::iter::repeat_n(None, resumed_count)
+            .chain(
+                weekly_commits
+                    .iter()
+                    .map(|commit| commit.merged_branch_time),
+            )
+            .collect();
+    commit_infos.extend(weekly_commits.iter().map(|commit| CommitCohortInfo {
+        id: commit.id,
+        time: commit.time,
+        time_string: commit.time_string.clone(),
+        year: commit.year,
+        summary: Some(commit.summary.clone()),
+    }));
+    // The walk only gave us the tree ids, so we load the trees themselves in parallel.
+    // Collecting a parallel iterator into a Vec keeps the commits in order.
+    let loading_start = Instant::now();
     let commit_trees_and_years: Vec<(gix::ObjectId, String, Vec<u8>, u32)> = weekly_commits
-        .into_iter()
+        .into_par_iter()
         .map(|commit| {
-            let time = commit.time().unwrap();
-            (
-                commit.id().to_owned().into(),
-                time.format(CustomFormat::new("%Y-%m-%d %H:%M:%S")),
-                commit.tree().unwrap().detach().data,
-                time.format(CustomFormat::new("%Y")).parse().unwrap(),
-            )
+            let thread = get_thread_local_vars();
+            Ok((
+                commit.id,
+                commit.time_string,
+                thread
+                    .repo
+                    .find_tree(commit.tree_id)
+                    .map_err(TheseusError::odb)?
+                    .detach()
+                    .data,
+                commit.year,
+            ))
         })
+        .collect::<Result<_>>()?;
+    phases.record("tree loading", loading_start.elapsed());
+    // The uncommitted changes come last, as a pseudo-commit with a null id made right now
+    if options.include_worktree {
+        let now = chrono::Local::now();
+        commit_infos.push(CommitCohortInfo {
+            id: gix::ObjectId::null(repo.object_hash()),
+            time: now.fixed_offset(),
+            time_string: now.format("%Y-%m-%d %H:%M:%S").to_string(),
+            year: now.year() as u32,
+            summary: None,
+        });
+    }
+    let cohort_keys: Vec<K> = commit_infos
+        .iter()
+        .enumerate()
+        .map(|(commit_idx, info)| cohort_key(commit_idx, info))
         .collect();
+    // With skip_merges, the lines a merge brings in are credited to the last analyzed commit
+    // made before the tip of the merged branch, instead of to the merge itself. Commit dates can
+    // go backwards, so this looks back from the merge rather than assuming the commits are sorted.
+    let commit_cohorts: Vec<K> = (0..commit_infos.len())
+        .map(
+            |commit_idx| match merged_branch_times.get(commit_idx).and_then(Option::as_ref) {
+                Some(merged_time) if options.skip_merges => {
+                    let credited_idx = commit_infos[..=commit_idx]
+                        .iter()
+                        .rposition(|info| info.time <= *merged_time)
+                        .unwrap_or(0);
+                    cohort_keys[credited_idx]
+                }
+                _ => cohort_keys[commit_idx],
+            },
+        )
+        .collect();
+    if !options.authors.is_empty() {
+        snapshot.uncredited =
+            uncredited_cohorts(repo, &commit_infos, &commit_cohorts, &options.authors)?;
+    }
+    if let Some((files, _)) = baseline {
+        // The first commit starts from its own tree, already blamed
+        base_tree_data = commit_trees_and_years[0].2.clone();
+        let first_cohort = commit_cohorts[resumed_count];
+        for file in files {
+            if file.total_lines < options.min_lines {
+                small_files_skipped.fetch_add(1, Ordering::Relaxed);
+                snapshot.skip_file(file.path);
+                continue;
+            }
+            let blamed_lines: LineNumber = file
+                .spans
+                .iter()
+                .map(|(lines, _)| lines.len() as LineNumber)
+                .sum();
+            let mut blame = if blamed_lines == file.total_lines && options.measure == Measure::Lines
+            {
+                FileBlame::from_spans(
+                    file.total_lines,
+                    file.spans.iter().map(|(lines, id)| {
+                        let commit_idx = baseline_commit_idx
+                            .get(id)
+                            .copied()
+                            .unwrap_or(resumed_count);
+                        BlameSpan::new(lines.start, lines.end, commit_cohorts[commit_idx])
+                    }),
+                )
+            } else {
+                // e.g. a textconv driver changed the lines, or a binary file
+                FileBlame::new(file.total_lines, first_cohort)
+            };
+            blame.merge_adjacent_ranges();
+            snapshot.add_blamed_file(file.path, blame);
+        }
+    }
+    let stream = match &options.stream_snapshots {
+        Some(path) => {
+            let mut year_of = HashMap::new();
+            for (key, info) in cohort_keys.iter().zip(&commit_infos) {
+                year_of.entry(*key).or_insert(info.year);
+            }
+            let commits = commit_infos[resumed_count..]
+                .iter()
+                .map(|info| {
+                    (
+                        info.id.to_hex_with_len(7).to_string(),
+                        info.time_string.clone(),
+                    )
+                })
+                .collect();
+            Some(SnapshotStream::create(path, commits, year_of)?)
+        }
+        None => None,
+    };
+    let processor = start_processor(snapshot, options, stream)?;
+    let sender = processor.sender();
     // First we compute the tree-diffs between each weekly commit and its preceding commit.
     // We can actually do this in parallel, which is nice.
+    let diffs_start = Instant::now();
     let commit_changes_and_cohorts: Vec<(Vec<Change>, usize)> = (0..commit_trees_and_years.len())
         .into_par_iter()
-        .map(|i| {
-            let (repo, platform_cell) = get_thread_local_vars();
-            let mut platform = platform_cell.borrow_mut();
+        .map(|i| -> Result<(Vec<Change>, usize)> {
+            let thread = get_thread_local_vars();
+            let mut platform = thread.diff_platform.borrow_mut();
 
             let mut tree_diff_state = gix::diff::tree::State::default();
-            let mut objects = &repo.objects;
+            let mut objects = &thread.repo.objects;
 
-            let (_id, _ts, current_tree_data, _year) = &commit_trees_and_years[i];
+            let (id, _ts, current_tree_data, _year) = &commit_trees_and_years[i];
             let previous_tree_data = if i > 0 {
                 commit_trees_and_years[i - 1].2.as_slice()
             } else {
-                &[]
+                base_tree_data.as_slice()
             };
 
             let mut work_todo = Vec::new();
@@ -100,237 +587,904 @@ pub fn run_theseus(
                 },
                 gix::diff::tree_with_rewrites::Options {
                     location: Some(gix::diff::tree::recorder::Location::Path),
-                    rewrites: Some(gix::diff::Rewrites::default()),
+                    rewrites: Some(rewrites(options)),
                 },
             )
-            .expect("tree diff failed");
-            (work_todo, i)
+            .map_err(|e| TheseusError::TreeDiffFailed {
+                commit: *id,
+                source: Box::new(e),
+            })?;
+            restore_copy_sources(
+                &mut work_todo,
+                TreeRefIter::from_bytes(previous_tree_data),
+                objects,
+            )?;
+            Ok((work_todo, i))
         })
-        .collect();
+        .collect::<Result<_>>()?;
+    phases.record("tree diffs", diffs_start.elapsed());
+    let (renames_detected, copies_detected) = commit_changes_and_cohorts
+        .iter()
+        .flat_map(|(work_todo, _)| work_todo)
+        .fold((0, 0), |(renames, copies), change| match change {
+            Change::Rewrite { copy: true, .. } => (renames, copies + 1),
+            Change::Rewrite { .. } => (renames + 1, copies),
+            _ => (renames, copies),
+        });
 
     // Now work_todo is a vec of changes per commit that we need to accumulate to build our incremental blame.
     // We go through it serially, but we can process each commit's changes in parallel.
-    for (work_todo, commit_idx) in progress_bar.wrap_iter(commit_changes_and_cohorts.into_iter()) {
-        sender
-            .send(Action::SetCommitId(
-                commit_trees_and_years[commit_idx].0.clone(),
-            ))
-            .unwrap();
+    let accumulate = || -> Result<()> {
+        let total_commits = commit_changes_and_cohorts.len();
+        let mut last_plain_progress = Instant::now();
+        for (work_todo, commit_idx) in
+            progress_bar.wrap_iter(commit_changes_and_cohorts.into_iter())
+        {
+            let (id, time_string, ..) = &commit_trees_and_years[commit_idx];
+            progress_bar.set_message(format!("{} {}", id.to_hex_with_len(7), time_string));
+            if plain_progress
+                && (last_plain_progress.elapsed() >= PLAIN_PROGRESS_INTERVAL
+                    || commit_idx + 1 == total_commits)
+            {
+                eprintln!(
+                    "Processing commit {}/{} ({} {})",
+                    commit_idx + 1,
+                    total_commits,
+                    id.to_hex_with_len(7),
+                    time_string
+                );
+                last_plain_progress = Instant::now();
+            }
+            send_action(&sender, Action::SetCommitId(*id))?;
+            let ctx = CommitContext {
+                sender: &sender,
+                options,
+                cohort: commit_cohorts[resumed_count + commit_idx],
+                files_skipped: &files_skipped,
+                small_files_skipped: &small_files_skipped,
+                path_filter: &path_filter,
+            };
 
-        // For any one commit, we process the changes that commit makes to the tree in parallel:
-        work_todo
-            .into_par_iter()
-            .try_for_each(
-                |change| -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-                    let (thread_repo, platform_cell) = get_thread_local_vars();
-
-                    match change {
-                        Change::Addition { location, id, .. } => {
-                            if !all_filetypes && !is_allowed_filetype(location.as_bstr()) {
-                                return Ok(());
-                            }
-                            handle_file_addition(&sender, thread_repo, id, &location, commit_idx)?;
-                        }
-                        Change::Deletion { location, .. } => {
-                            if !all_filetypes && !is_allowed_filetype(location.as_bstr()) {
-                                return Ok(());
-                            }
-                            handle_file_deletion(&sender, location)?;
-                        }
-                        Change::Modification {
-                            location,
-                            previous_entry_mode,
-                            previous_id,
-                            entry_mode,
-                            id,
-                        } => {
-                            if !all_filetypes && !is_allowed_filetype(location.as_bstr()) {
-                                return Ok(());
-                            }
-                            if handle_entry_mode_change(
-                                &sender,
-                                thread_repo,
-                                previous_entry_mode,
-                                entry_mode,
-                                id,
-                                &location,
-                                commit_idx,
-                            )? {
-                                return Ok(());
-                            }
-                            handle_file_modification(
-                                &sender,
-                                thread_repo,
-                                platform_cell,
-                                previous_id,
-                                id,
-                                &location,
-                                commit_idx,
-                            )?;
-                        }
-                        Change::Rewrite {
-                            source_location,
-                            location,
-                            diff,
-                            id,
-                            source_id,
-                            entry_mode,
-                            source_entry_mode,
-                            ..
-                        } => {
-                            let old_allowed = (all_filetypes
-                                || is_allowed_filetype(source_location.as_bstr()))
-                                && source_entry_mode.is_blob();
-                            let new_allowed = (all_filetypes
-                                || is_allowed_filetype(location.as_bstr()))
-                                && entry_mode.is_blob();
-
-                            match (old_allowed, new_allowed) {
-                                (true, true) => {
-                                    sender
-                                        .send(Action::RenameFile {
-                                            old_path: source_location,
-                                            new_path: location.clone(),
-                                        })
-                                        .unwrap();
-                                    if diff.is_some() {
-                                        handle_file_modification(
-                                            &sender,
-                                            thread_repo,
-                                            platform_cell,
-                                            source_id,
-                                            id,
-                                            &location,
-                                            commit_idx,
-                                        )?;
-                                    }
-                                }
-                                (true, false) => {
-                                    handle_file_deletion(&sender, source_location)?;
-                                    return Ok(());
-                                }
-                                (false, true) => {
-                                    handle_file_addition(
-                                        &sender,
-                                        thread_repo,
-                                        id,
-                                        &location,
-                                        commit_idx,
-                                    )?;
-                                    return Ok(());
-                                }
-                                (false, false) => {
-                                    return Ok(());
-                                }
+            // For any one commit, we process the changes that commit makes to the tree in parallel,
+            // then send their actions in the order of change_order. They're processed a chunk at a
+            // time and each chunk's actions are sent before the next chunk starts, so a commit that
+            // touches every file of a vendored dump doesn't hold all of its actions in memory
+            // before any reach the bounded queue.
+            let mut work_todo = work_todo;
+            work_todo.sort_by(|a, b| change_order(a).cmp(&change_order(b)));
+            let chunk_size = rayon::current_num_threads() * CHANGES_IN_FLIGHT_PER_THREAD;
+            let mut work_todo = work_todo.into_iter().peekable();
+            while work_todo.peek().is_some() {
+                let chunk: Vec<Change> = work_todo.by_ref().take(chunk_size).collect();
+                let change_actions = chunk
+                    .into_par_iter()
+                    .map(|change| -> Result<Vec<Action<K>>> {
+                        let thread = get_thread_local_vars();
+                        let (change_sender, change_receiver) = crossbeam_channel::unbounded();
+                        let change_ctx = CommitContext {
+                            sender: &change_sender,
+                            ..ctx
+                        };
+
+                        let location = change.location().to_owned();
+                        match handle_change(&change_ctx, thread, change) {
+                            // One unreadable file shouldn't stop the whole analysis: leave it out from here on
+                            Err(error) if error.is_file_error() => {
+                                file_failures.lock().unwrap().push(FileFailure {
+                                    commit: *id,
+                                    path: location.clone(),
+                                    error,
+                                });
+                                send_action(&change_sender, Action::SkipFile { path: location })?;
                             }
+                            handled => handled?,
                         }
-                    };
-                    Ok(())
-                },
-            )
-            .unwrap();
-        // We need to clear the diff cache every so often.
-        // Clearing it every 2, 10, 100 or 200 commits has nearly the same performance improvement:
-        // a speedup of ~10s on torvalds/linux, but it consumes 60+ GB of RAM compared to capping out at 200MB
-        // when clearing every commit. Clearing it less often than every commit is not worth it.
-        rayon::broadcast(|_| {
-            let (_, platform_cell) = get_thread_local_vars();
-            platform_cell
-                .borrow_mut()
-                .clear_resource_cache_keep_allocation();
-        });
-        sender.send(Action::FinishCommit).unwrap();
-    }
+                        Ok(change_receiver.try_iter().collect())
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                for action in change_actions.into_iter().flatten() {
+                    send_action(&sender, action)?;
+                }
+            }
+            // We need to clear the diff cache every so often.
+            // Clearing it every 2, 10, 100 or 200 commits has nearly the same performance improvement:
+            // a speedup of ~10s on torvalds/linux, but it consumes 60+ GB of RAM compared to capping out at 200MB
+            // when clearing every commit. Clearing it less often than every commit is not worth it.
+            rayon::broadcast(|_| {
+                get_thread_local_vars()
+                    .diff_platform
+                    .borrow_mut()
+                    .clear_resource_cache_keep_allocation();
+            });
+            send_action(&sender, Action::FinishCommit)?;
+        }
+        if options.include_worktree {
+            let commit_idx = commit_infos.len() - 1;
+            send_action(&sender, Action::SetCommitId(commit_infos[commit_idx].id))?;
+            let ctx = CommitContext {
+                sender: &sender,
+                options,
+                cohort: commit_cohorts[commit_idx],
+                files_skipped: &files_skipped,
+                small_files_skipped: &small_files_skipped,
+                path_filter: &path_filter,
+            };
+            for change in worktree_changes(repo)? {
+                handle_worktree_change(&ctx, change)?;
+            }
+            send_action(&sender, Action::FinishCommit)?;
+        }
+        Ok(())
+    };
+    let last_commit_id = commit_trees_and_years
+        .last()
+        .map(|(id, ..)| *id)
+        .or(resumed_head);
+    let accumulation_start = Instant::now();
+    let accumulated = accumulate();
     drop(sender);
-    let results = processor.finish();
+    // If the processor failed, the producers only saw a closed channel: report the processor's error.
+    let snapshot = processor.finish()?;
+    accumulated?;
+    phases.record("blame accumulation", accumulation_start.elapsed());
+    if let (Some(path), Some(head)) = (&options.checkpoint, last_commit_id) {
+        checkpoint::save(path, head, &commit_infos, &snapshot)?;
+    }
+    let stats = RunStats {
+        files_at_head: snapshot.file_count(),
+        lines_at_head: snapshot.total_lines(),
+        files_skipped: files_skipped.into_inner(),
+        small_files_skipped: small_files_skipped.into_inner(),
+        renames_detected,
+        copies_detected,
+        files_ever_seen: snapshot.paths_seen.len(),
+        baseline_commits,
+        large_commits: large_commits(
+            &commit_infos[baseline_commits..],
+            &snapshot.churn,
+            options
+                .large_commit_lines
+                .unwrap_or(DEFAULT_LARGE_COMMIT_LINES),
+        ),
+        phases,
+    };
 
-    let commit_infos = commit_trees_and_years
-        .iter()
-        .map(|(id, ts, _, year)| CommitCohortInfo {
-            id: id.clone(),
-            time_string: ts.clone(),
-            year: *year,
-        })
-        .collect();
     Ok(TheseusResult {
         commit_cohort_info: commit_infos,
-        cohort_data: results,
+        cohort_data: snapshot.commit_results,
+        lines_added: snapshot
+            .lines_added
+            .into_iter()
+            .filter(|(cohort, _)| !snapshot.uncredited.contains(cohort))
+            .collect(),
+        churn: snapshot.churn,
+        ever_written: snapshot.ever_written,
+        by_extension: snapshot.groups.by_extension.map(|stats| stats.results),
+        by_directory: snapshot.groups.by_directory.map(|stats| stats.results),
+        failures: file_failures.into_inner().unwrap(),
+        stats,
     })
 }
 
-fn handle_file_modification(
-    sender: &crossbeam_channel::Sender<Action<usize>>,
-    thread_repo: &gix::Repository,
-    platform_cell: &std::cell::RefCell<gix::diff::blob::Platform>,
+// Starts the blame processor, recording its actions and streaming its snapshots if asked to
+#[cfg_attr(not(feature = "record"), allow(unused_variables))]
+pub(crate) fn start_processor<K: Keyable>(
+    snapshot: RepositoryBlameSnapshot<K>,
+    options: &AnalysisOptions,
+    mut stream: Option<SnapshotStream<K>>,
+) -> Result<BlameProcessor<K>> {
+    let on_commit = move |snapshot: &RepositoryBlameSnapshot<K>| match &mut stream {
+        Some(stream) => stream.write(snapshot),
+        None => Ok(()),
+    };
+    let queue_size = options.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE);
+    #[cfg(feature = "record")]
+    if let Some(path) = &options.record_actions {
+        let mut recorder = crate::record::ActionRecorder::create(path)?;
+        return Ok(BlameProcessor::with_hooks(
+            snapshot,
+            queue_size,
+            move |action| recorder.record(action),
+            on_commit,
+        ));
+    }
+    Ok(BlameProcessor::with_hooks(
+        snapshot,
+        queue_size,
+        |_| Ok(()),
+        on_commit,
+    ))
+}
+
+pub(crate) fn new_commit_results<K: Keyable>(
+    options: &AnalysisOptions,
+) -> Result<CommitResults<K>> {
+    if options.spool_results {
+        CommitResults::spooled().map_err(TheseusError::ResultsSpool)
+    } else {
+        Ok(CommitResults::in_memory())
+    }
+}
+
+// How the tree diffs look for renames, and for copies when asked to.
+// Like git's diff.renameLimit, the limit is on the files added or deleted, but gix compares it to
+// the number of pairs it would have to check, so it gets squared.
+fn rewrites(options: &AnalysisOptions) -> gix::diff::Rewrites {
+    let defaults = gix::diff::Rewrites::default();
+    gix::diff::Rewrites {
+        copies: options
+            .detect_copies
+            .then(gix::diff::rewrites::Copies::default),
+        limit: options
+            .rename_limit
+            .unwrap_or(defaults.limit)
+            .saturating_pow(2),
+        ..defaults
+    }
+}
+
+// The breakdowns of the running stats that were asked for
+pub(crate) fn file_groups<K: Keyable>(options: &AnalysisOptions) -> FileGroups<K> {
+    FileGroups {
+        by_extension: options
+            .by_extension
+            .then(|| GroupStats::new(file_extension)),
+        by_directory: options.by_directory.then(|| GroupStats::new(top_level_dir)),
+    }
+}
+
+// Opens a repo we can analyze, which needs its full history
+pub(crate) fn open_repo(repo_path: &str) -> Result<gix::Repository> {
+    let repo = gix::open(repo_path).map_err(|e| TheseusError::RepoNotFound {
+        path: repo_path.into(),
+        source: Box::new(e),
+    })?;
+    if repo.is_shallow() {
+        return Err(TheseusError::ShallowRepo(repo_path.into()));
+    }
+    Ok(repo)
+}
+
+// The name of a repo's directory, for the output directory and the chart title, e.g. "linux" for
+// "~/src/linux/", "C:\src\linux\" or "." run from inside it.
+pub fn repo_name(repo_path: &str) -> String {
+    let name = |path: &Path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+    };
+    name(Path::new(repo_path.trim_end_matches(['/', '\\'])))
+        .or_else(|| {
+            std::fs::canonicalize(repo_path)
+                .ok()
+                .and_then(|path| name(&path))
+        })
+        .unwrap_or_else(|| "repo".to_string())
+}
+
+// The commits an analysis diffs, once the excluded ones are dropped and the rest sampled
+pub(crate) fn commits_to_analyze(
+    repo: &gix::Repository,
+    options: &AnalysisOptions,
+    resume_from: Option<gix::ObjectId>,
+) -> Result<Vec<SelectedCommit>> {
+    let mut commits = select_commits(repo, options, resume_from)?;
+    if !options.exclude_commits.is_empty() {
+        let unmatched;
+        (commits, unmatched) = exclude_commits(repo, commits, &options.exclude_commits)?;
+        if !options.verbosity.is_quiet() {
+            for spec in unmatched {
+                eprintln!(
+                    "Warning: excluding {spec} changes nothing, it isn't one of the analyzed commits"
+                );
+            }
+        }
+    }
+    if let Some(n) = options.sample {
+        commits = sample_every_nth(commits, n);
+    }
+    if let Some(n) = options.max_commits {
+        commits = keep_most_recent(commits, n);
+    }
+    Ok(commits)
+}
+
+// Lists the weekly commits to analyze, going through the commit cache if there is one.
+// When resuming from a checkpoint, these are only the commits made since the one it was saved at.
+fn select_commits(
+    repo: &gix::Repository,
+    options: &AnalysisOptions,
+    resume_from: Option<gix::ObjectId>,
+) -> Result<Vec<SelectedCommit>> {
+    let range = match (&options.range, resume_from) {
+        (_, Some(head)) => RevisionRange {
+            base: Some(head),
+            tip: RevisionRange::head(repo)?.tip,
+        },
+        (Some(spec), None) => RevisionRange::parse(repo, spec)?,
+        (None, None) => RevisionRange::head(repo)?,
+    };
+    let since = options
+        .since_tag
+        .as_deref()
+        .map(|tag| tag_commit_time(repo, tag))
+        .transpose()?;
+    let until = options
+        .until_tag
+        .as_deref()
+        .map(|tag| tag_commit_time(repo, tag))
+        .transpose()?;
+    // Cache entries are keyed by the tip only and hold weekly commits, so ranges with a base or
+    // tag bounds and author filters don't go through the cache
+    let cache_dir = options.cache_dir.as_ref().filter(|_| {
+        range.base.is_none() && since.is_none() && until.is_none() && options.authors.is_empty()
+    });
+    let week_start = options.week_start.unwrap_or(Weekday::Sun);
+    let cached =
+        cache_dir.and_then(|cache_dir| commit_cache::load(cache_dir, range.tip, week_start));
+    if let Some(commits) = cached {
+        return Ok(commits);
+    }
+
+    // The tree at the base of a range is where the analysis starts from, so its lines are
+    // all credited to the base instead of to the commits that wrote them. When resuming, the run
+    // that saved the checkpoint already analyzed it.
+    let base = match range.base {
+        Some(base) if resume_from.is_none() => {
+            Some(repo.find_commit(base).map_err(TheseusError::odb)?)
+        }
+        _ => None,
+    };
+    // A weekly diff mixes the changes of every author of the week, so with authors to credit
+    // each commit gets its own diff
+    let commits = if options.authors.is_empty() {
+        list_commits_with_granularity(
+            repo,
+            range,
+            Granularity::Weekly(week_start),
+            since,
+            until,
+            !options.no_commit_graph,
+        )?
+    } else {
+        list_every_commit(repo, range, !options.no_commit_graph)?
+            .into_iter()
+            .filter(|commit| {
+                let time = commit.time().map_or(0, |time| time.seconds);
+                since.is_none_or(|since| time >= since.timestamp())
+                    && until.is_none_or(|until| time <= until.timestamp())
+            })
+            .collect()
+    };
+    let commits = base
+        .into_iter()
+        .chain(commits)
+        .map(|commit| selected_commit(&commit))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(cache_dir) = cache_dir {
+        // The cache is only an optimization, failing to write it shouldn't fail the analysis
+        match commit_cache::store(cache_dir, range.tip, week_start, &commits) {
+            Err(e) if !options.verbosity.is_quiet() => {
+                eprintln!("Warning: failed to write the commit cache: {e}");
+            }
+            _ => {}
+        }
+    }
+    Ok(commits)
+}
+
+// Drops the given commits from the ones to analyze. The next one is then diffed against the commit
+// before the dropped one, so it gets the dropped commit's lines. The last commit is kept, so the
+// last snapshot is still of the tip. Also returns the specs of the commits that weren't to be
+// analyzed in the first place, e.g. one made earlier in its week than the week's analyzed commit.
+fn exclude_commits<'a>(
+    repo: &gix::Repository,
+    commits: Vec<SelectedCommit>,
+    specs: &'a [String],
+) -> Result<(Vec<SelectedCommit>, Vec<&'a String>)> {
+    let excluded = specs
+        .iter()
+        .map(|spec| Ok((resolve_commit(repo, spec)?, spec)))
+        .collect::<Result<Vec<_>>>()?;
+    let unmatched = excluded
+        .iter()
+        .filter(|(id, _)| !commits.iter().any(|commit| commit.id == *id))
+        .map(|(_, spec)| *spec)
+        .collect();
+    let excluded: HashSet<gix::ObjectId> = excluded.into_iter().map(|(id, _)| id).collect();
+    let last_idx = commits.len().saturating_sub(1);
+    let commits = commits
+        .into_iter()
+        .enumerate()
+        .filter(|(i, commit)| *i == last_idx || !excluded.contains(&commit.id))
+        .map(|(_, commit)| commit)
+        .collect();
+    Ok((commits, unmatched))
+}
+
+// Sets the time of the newest branch merged into each commit since the commit analyzed before it.
+// A weekly bucket is diffed as a whole, so every first-parent commit of it is checked for a merge,
+// not only the one it was picked at. The first commit is diffed against an empty tree, or against
+// the checkpoint it resumes from, so it has nothing to look back to otherwise.
+fn find_merged_branch_times(
+    repo: &gix::Repository,
+    commits: &mut [SelectedCommit],
+    resumed_head: Option<gix::ObjectId>,
+    options: &AnalysisOptions,
+) -> Result<()> {
+    let mut previous = resumed_head;
+    for commit in commits {
+        if let Some(base) = previous {
+            let range = RevisionRange {
+                base: Some(base),
+                tip: commit.id,
+            };
+            for merge in first_parent_commits(repo, range, !options.no_commit_graph)? {
+                let Some(merged_id) = merge?.parent_ids().nth(1) else {
+                    continue;
+                };
+                let merged_time = local_commit_time(
+                    repo.find_commit(merged_id)
+                        .map_err(TheseusError::odb)?
+                        .time()
+                        .map_err(TheseusError::odb)?,
+                );
+                commit.merged_branch_time = commit.merged_branch_time.max(Some(merged_time));
+            }
+        }
+        previous = Some(commit.id);
+    }
+    Ok(())
+}
+
+// The cohorts of the commits that weren't authored with one of the given emails. A cohort that
+// some commits of these authors share with others, e.g. a year, stays credited.
+fn uncredited_cohorts<K: Keyable>(
+    repo: &gix::Repository,
+    commit_infos: &[CommitCohortInfo],
+    commit_cohorts: &[K],
+    authors: &[String],
+) -> Result<HashSet<K>> {
+    let mut credited = HashSet::new();
+    let mut uncredited = HashSet::new();
+    for (info, cohort) in commit_infos.iter().zip(commit_cohorts) {
+        // The worktree has no author, it's whoever is running the analysis
+        if info.id.is_null() {
+            credited.insert(*cohort);
+            continue;
+        }
+        let commit = repo.find_commit(info.id).map_err(TheseusError::odb)?;
+        let email = commit.author().map_err(TheseusError::odb)?.email;
+        if authors
+            .iter()
+            .any(|author| email.eq_ignore_ascii_case(author.as_bytes()))
+        {
+            credited.insert(*cohort);
+        } else {
+            uncredited.insert(*cohort);
+        }
+    }
+    Ok(uncredited.difference(&credited).copied().collect())
+}
+
+pub(crate) fn selected_commit(commit: &gix::Commit) -> Result<SelectedCommit> {
+    let time = commit.time().map_err(TheseusError::odb)?;
+    let summary = commit
+        .message()
+        .map_err(TheseusError::odb)?
+        .summary()
+        .to_str_lossy()
+        .into_owned();
+    let time = local_commit_time(time);
+    Ok(SelectedCommit {
+        id: commit.id,
+        tree_id: commit.tree_id().map_err(TheseusError::odb)?.detach(),
+        time,
+        time_string: time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        year: time.year() as u32,
+        merged_branch_time: None,
+        summary,
+    })
+}
+
+// A file of the first analyzed commit, blamed through the history before it
+struct BaselineFile {
+    path: BString,
+    total_lines: LineNumber,
+    // The lines of the file each commit wrote, in order
+    spans: Vec<(Range<LineNumber>, gix::ObjectId)>,
+}
+
+// Blames the analyzed files of the first commit, along with how many files the filetype filter left out.
+fn blame_baseline<'a>(
+    repo: &gix::Repository,
+    first_commit: gix::ObjectId,
+    path_filter: &PathFilter,
+    options: &AnalysisOptions,
+    thread_state: &(dyn Fn() -> &'a ThreadState + Sync),
+) -> Result<(Vec<BaselineFile>, usize)> {
+    let mut recorder = gix::traverse::tree::Recorder::default();
+    repo.find_commit(first_commit)
+        .map_err(TheseusError::odb)?
+        .tree()
+        .map_err(TheseusError::odb)?
+        .traverse()
+        .breadthfirst(&mut recorder)
+        .map_err(TheseusError::traversal)?;
+    let blobs: Vec<_> = recorder
+        .records
+        .into_iter()
+        .filter(|entry| entry.mode.is_blob())
+        .collect();
+    let skipped = blobs
+        .iter()
+        .filter(|entry| {
+            let path = entry.filepath.as_bstr();
+            path_filter.is_included(path) && !path_filter.is_analyzed(path)
+        })
+        .count();
+    let files = blobs
+        .into_par_iter()
+        .filter(|entry| path_filter.is_analyzed(entry.filepath.as_bstr()))
+        .map(|entry| -> Result<BaselineFile> {
+            let thread = thread_state();
+            let total_lines = get_blob_size(
+                &mut thread.diff_platform.borrow_mut(),
+                entry.oid,
+                entry.filepath.as_bstr(),
+                &thread.repo.objects,
+                options,
+            )?;
+            let outcome = gix::blame::file(
+                &thread.repo.objects,
+                first_commit,
+                None,
+                &mut thread.diff_platform.borrow_mut(),
+                entry.filepath.as_bstr(),
+                Default::default(),
+            )
+            .map_err(|source| TheseusError::BlameFailed {
+                path: entry.filepath.clone(),
+                source: source.into(),
+            })?;
+            let mut spans: Vec<(Range<LineNumber>, gix::ObjectId)> = outcome
+                .entries
+                .iter()
+                .map(|blamed| {
+                    let start = blamed.start_in_blamed_file;
+                    (start..start + blamed.len.get(), blamed.commit_id)
+                })
+                .collect();
+            spans.sort_by_key(|(lines, _)| lines.start);
+            Ok(BaselineFile {
+                path: entry.filepath,
+                total_lines,
+                spans,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok((files, skipped))
+}
+
+// The state each rayon thread keeps for itself
+struct ThreadState {
+    repo: gix::Repository,
+    diff_platform: RefCell<gix::diff::blob::Platform>,
+}
+
+// What the change handlers need to know about the commit whose changes they're processing.
+#[derive(Clone, Copy)]
+pub(crate) struct CommitContext<'a, K: Keyable> {
+    pub(crate) sender: &'a Sender<Action<K>>,
+    pub(crate) options: &'a AnalysisOptions,
+    // The cohort the lines added by this commit belong to
+    pub(crate) cohort: K,
+    pub(crate) files_skipped: &'a AtomicUsize,
+    pub(crate) small_files_skipped: &'a AtomicUsize,
+    pub(crate) path_filter: &'a PathFilter,
+}
+
+impl<K: Keyable> CommitContext<'_, K> {
+    // Whether the lines of an added file get analyzed. Counts the files left out by the filetype filter.
+    pub(crate) fn is_addition_analyzed(&self, path: &BStr) -> bool {
+        let analyzed = self.path_filter.is_analyzed(path);
+        if !analyzed && self.path_filter.is_included(path) {
+            self.files_skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        analyzed
+    }
+}
+
+pub(crate) fn send_action<K: Keyable>(sender: &Sender<Action<K>>, action: Action<K>) -> Result<()> {
+    sender
+        .send(action)
+        .map_err(|_| TheseusError::ProcessorStopped)
+}
+
+fn handle_file_modification<K: Keyable>(
+    ctx: &CommitContext<K>,
+    thread: &ThreadState,
     previous_id: gix::ObjectId,
     id: gix::ObjectId,
     location: &gix::bstr::BString,
-    commit_idx: usize,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut platform_borrow = platform_cell.borrow_mut();
+) -> Result<()> {
+    let mut platform_borrow = thread.diff_platform.borrow_mut();
     let line_diffs = get_blob_diff(
         &mut platform_borrow,
         previous_id,
         id,
         location.as_ref(),
-        &thread_repo.objects,
-        commit_idx,
+        &thread.repo.objects,
+        ctx.cohort,
+        ctx.options,
     )?;
-    sender
-        .send(Action::ModifyFile {
+    send_action(
+        ctx.sender,
+        Action::ModifyFile {
             path: location.clone(),
             line_diffs,
-        })
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-    Ok(())
+        },
+    )
 }
 
-fn handle_file_addition(
-    sender: &crossbeam_channel::Sender<Action<usize>>,
-    thread_repo: &gix::Repository,
+fn handle_file_addition<K: Keyable>(
+    ctx: &CommitContext<K>,
+    thread: &ThreadState,
     id: gix::ObjectId,
     location: &gix::bstr::BString,
-    commit_idx: usize,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let blob = thread_repo.find_blob(id)?;
-    sender
-        .send(Action::AddFile {
+) -> Result<()> {
+    let mut platform = thread.diff_platform.borrow_mut();
+    let total_lines = get_blob_size(
+        &mut platform,
+        id,
+        location.as_ref(),
+        &thread.repo.objects,
+        ctx.options,
+    )?;
+    // Only the size of an added blob is needed, so its buffer goes back to the free list right away
+    // and the next blob is decoded into it. Otherwise every file a commit adds stays in memory
+    // until the end of the commit, which adds up for commits that vendor large files.
+    platform.clear_resource_cache_keep_allocation();
+    send_file_addition(ctx, location, total_lines, Some(id))
+}
+
+pub(crate) fn send_file_addition<K: Keyable>(
+    ctx: &CommitContext<K>,
+    location: &gix::bstr::BString,
+    total_lines: LineNumber,
+    id: Option<gix::ObjectId>,
+) -> Result<()> {
+    if total_lines < ctx.options.min_lines {
+        ctx.small_files_skipped.fetch_add(1, Ordering::Relaxed);
+        return send_action(
+            ctx.sender,
+            Action::SkipFile {
+                path: location.clone(),
+            },
+        );
+    }
+    send_action(
+        ctx.sender,
+        Action::AddFile {
             path: location.clone(),
-            total_lines: blob.data.lines().count() as LineNumber,
-            cohort: commit_idx,
-        })
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-    Ok(())
+            total_lines,
+            cohort: ctx.cohort,
+            id,
+        },
+    )
+}
+
+fn handle_worktree_change<K: Keyable>(
+    ctx: &CommitContext<K>,
+    change: WorktreeChange,
+) -> Result<()> {
+    match change {
+        WorktreeChange::Addition { path, data } => {
+            if !ctx.is_addition_analyzed(path.as_bstr()) {
+                return Ok(());
+            }
+            send_file_addition(ctx, &path, measure_size(&data, ctx.options), None)
+        }
+        WorktreeChange::Deletion { path, id } => {
+            if !ctx.path_filter.is_analyzed(path.as_bstr()) {
+                return Ok(());
+            }
+            handle_file_deletion(ctx, path, id)
+        }
+        WorktreeChange::Modification { path, old, new } => {
+            if !ctx.path_filter.is_analyzed(path.as_bstr()) {
+                return Ok(());
+            }
+            let line_diffs = diff_bytes(&old, &new, ctx.cohort, ctx.options);
+            send_action(ctx.sender, Action::ModifyFile { path, line_diffs })
+        }
+    }
 }
 
-fn handle_file_deletion(
-    sender: &crossbeam_channel::Sender<Action<usize>>,
+pub(crate) fn handle_file_deletion<K: Keyable>(
+    ctx: &CommitContext<K>,
     location: gix::bstr::BString,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    sender
-        .send(Action::DeleteFile { path: location })
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+    id: gix::ObjectId,
+) -> Result<()> {
+    send_action(ctx.sender, Action::DeleteFile { path: location, id })
+}
+
+// The order the changes of a commit are applied in. They're diffed in parallel, but their actions are
+// sent in this order so every run builds the same blame: deletions free their paths first, then
+// renames move their files, then modifications, and additions last since they can reuse a path a
+// deletion or rename just freed. Changes of the same kind go by path.
+fn change_order(change: &Change) -> (u8, &BStr) {
+    let rank = match change {
+        Change::Deletion { .. } => 0,
+        Change::Rewrite { copy: false, .. } => 1,
+        Change::Modification { .. } => 2,
+        Change::Rewrite { copy: true, .. } | Change::Addition { .. } => 3,
+    };
+    (rank, change.location())
+}
+
+// gix reports the file a copy was made from only as the source of the copy, not with its own change,
+// e.g. a file that was modified in the same commit it was copied in. Adds that change back from the
+// file's entry in the previous tree, so the edits to the source aren't lost.
+fn restore_copy_sources(
+    work_todo: &mut Vec<Change>,
+    previous_tree: TreeRefIter<'_>,
+    objects: &gix::odb::Handle,
+) -> Result<()> {
+    let changed: HashSet<BString> = work_todo
+        .iter()
+        .filter(|change| !matches!(change, Change::Rewrite { copy: true, .. }))
+        .map(|change| change.location().to_owned())
+        .collect();
+    let mut sources: Vec<(BString, gix::ObjectId, gix::object::tree::EntryMode)> = work_todo
+        .iter()
+        .filter_map(|change| match change {
+            Change::Rewrite {
+                source_location,
+                source_id,
+                source_entry_mode,
+                copy: true,
+                ..
+            } if !changed.contains(source_location) => {
+                Some((source_location.clone(), *source_id, *source_entry_mode))
+            }
+            _ => None,
+        })
+        .collect();
+    // A file can be the source of several copies
+    sources.sort();
+    sources.dedup();
+    let mut buf = Vec::new();
+    for (location, id, entry_mode) in sources {
+        let previous = previous_tree
+            .lookup_entry(objects, &mut buf, location.split_str("/"))
+            .map_err(TheseusError::odb)?;
+        match previous {
+            Some(previous) if previous.oid == id && previous.mode == entry_mode => {}
+            Some(previous) => work_todo.push(Change::Modification {
+                location,
+                previous_entry_mode: previous.mode,
+                previous_id: previous.oid,
+                entry_mode,
+                id,
+            }),
+            None => work_todo.push(Change::Addition {
+                location,
+                relation: None,
+                entry_mode,
+                id,
+            }),
+        }
+    }
     Ok(())
 }
 
+// Sends the actions for one change of a commit's tree
+fn handle_change<K: Keyable>(
+    ctx: &CommitContext<K>,
+    thread: &ThreadState,
+    change: Change,
+) -> Result<()> {
+    match change {
+        Change::Addition { location, id, .. } => {
+            if !ctx.is_addition_analyzed(location.as_bstr()) {
+                return Ok(());
+            }
+            handle_file_addition(ctx, thread, id, &location)
+        }
+        Change::Deletion { location, id, .. } => {
+            if !ctx.path_filter.is_analyzed(location.as_bstr()) {
+                return Ok(());
+            }
+            handle_file_deletion(ctx, location, id)
+        }
+        Change::Modification {
+            location,
+            previous_entry_mode,
+            previous_id,
+            entry_mode,
+            id,
+        } => {
+            if !ctx.path_filter.is_analyzed(location.as_bstr()) {
+                return Ok(());
+            }
+            if handle_entry_mode_change(
+                ctx,
+                thread,
+                previous_entry_mode,
+                entry_mode,
+                previous_id,
+                id,
+                &location,
+            )? {
+                return Ok(());
+            }
+            handle_file_modification(ctx, thread, previous_id, id, &location)
+        }
+        Change::Rewrite {
+            source_location,
+            location,
+            id,
+            source_id,
+            entry_mode,
+            source_entry_mode,
+            copy,
+            ..
+        } => {
+            // The source of a copy is still there with its own blame, so the copy is new code
+            if copy {
+                if !entry_mode.is_blob() || !ctx.is_addition_analyzed(location.as_bstr()) {
+                    return Ok(());
+                }
+                return handle_file_addition(ctx, thread, id, &location);
+            }
+            let old_allowed = ctx.path_filter.is_analyzed(source_location.as_bstr())
+                && source_entry_mode.is_blob();
+            // A file renamed to a path that isn't analyzed is counted like a file added there
+            let new_allowed = entry_mode.is_blob() && ctx.is_addition_analyzed(location.as_bstr());
+
+            match (old_allowed, new_allowed) {
+                (true, true) => {
+                    send_action(
+                        ctx.sender,
+                        Action::RenameFile {
+                            old_path: source_location,
+                            new_path: location.clone(),
+                        },
+                    )?;
+                    // A pure rename keeps the same blob and carries its blame over untouched.
+                    // Otherwise the edit follows the rename in this change's actions, which are sent
+                    // together, so the new path always exists by the time it is modified.
+                    if source_id == id {
+                        return Ok(());
+                    }
+                    handle_file_modification(ctx, thread, source_id, id, &location)
+                }
+                (true, false) => handle_file_deletion(ctx, source_location, source_id),
+                (false, true) => handle_file_addition(ctx, thread, id, &location),
+                (false, false) => Ok(()),
+            }
+        }
+    }
+}
+
 // Returns true if the entry mode change was handled and no more processing is needed
-fn handle_entry_mode_change(
-    sender: &crossbeam_channel::Sender<Action<usize>>,
-    thread_repo: &gix::Repository,
+fn handle_entry_mode_change<K: Keyable>(
+    ctx: &CommitContext<K>,
+    thread: &ThreadState,
     previous_entry_mode: gix::object::tree::EntryMode,
     entry_mode: gix::object::tree::EntryMode,
+    previous_id: gix::ObjectId,
     id: gix::ObjectId,
     location: &gix::bstr::BString,
-    commit_idx: usize,
-) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<bool> {
     if previous_entry_mode != entry_mode {
         let prev_is_blob = previous_entry_mode.is_blob();
         let new_is_blob = entry_mode.is_blob();
         if !prev_is_blob && new_is_blob {
-            handle_file_addition(sender, thread_repo, id, location, commit_idx)?;
+            handle_file_addition(ctx, thread, id, location)?;
             return Ok(true);
         } else if prev_is_blob && !new_is_blob {
-            handle_file_deletion(sender, location.clone())?;
+            handle_file_deletion(ctx, location.clone(), previous_id)?;
             return Ok(true);
         } else if !prev_is_blob && !new_is_blob {
             return Ok(true);
@@ -338,3 +1492,1311 @@ fn handle_entry_mode_change(
     }
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatter::{CohortBoundary, CohortGranularity};
+    use crate::options::Verbosity;
+    use crate::test_utils::TestRepo;
+
+    fn quiet_options() -> AnalysisOptions {
+        AnalysisOptions {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        }
+    }
+
+    // The (label, line count) pairs of the last snapshot
+    fn final_composition(repo: &TestRepo, options: &AnalysisOptions) -> Vec<(String, i64)> {
+        let format_options = FormatOptions {
+            verbosity: options.verbosity,
+            ..Default::default()
+        };
+        final_formatted_composition(repo, options, &format_options)
+    }
+
+    fn final_formatted_composition(
+        repo: &TestRepo,
+        options: &AnalysisOptions,
+        format_options: &FormatOptions,
+    ) -> Vec<(String, i64)> {
+        let data = format_cohort_data(&run_theseus(repo.path(), options).unwrap(), format_options)
+            .unwrap();
+        data.labels
+            .into_iter()
+            .zip(data.y.iter().map(|row| *row.last().unwrap()))
+            .collect()
+    }
+
+    fn composition(counts: &[(u32, i64)]) -> Vec<(String, i64)> {
+        counts
+            .iter()
+            .map(|(year, lines)| (format!("Code added in {year}"), *lines))
+            .collect()
+    }
+
+    #[test]
+    fn test_run_theseus_with_year_cohorts() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\ne\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let result =
+            run_theseus_with_cohorts(repo.path(), &quiet_options(), |_, info| info.year).unwrap();
+        let mut last_snapshot = result.cohort_data.last().unwrap().unwrap();
+        last_snapshot.sort();
+        assert_eq!(last_snapshot, vec![(2019, 3), (2021, 2)]);
+    }
+
+    #[test]
+    fn test_deleted_files_leave_the_composition() {
+        let repo = TestRepo::new();
+        repo.write("a.rs", "a\nb\n")
+            .write("b.rs", "c\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("b.rs", "c\nd\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.remove("a.rs").commit("2021-03-01T12:00:00+00:00");
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 1), (2020, 1), (2021, 0)])
+        );
+    }
+
+    #[test]
+    fn test_files_ever_seen_counts_deleted_and_renamed_paths() {
+        let repo = TestRepo::new();
+        repo.write("a.rs", "a\n")
+            .write("b.rs", "b\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.remove("a.rs").commit("2020-03-01T12:00:00+00:00");
+        repo.git(&["mv", "b.rs", "c.rs"]);
+        repo.commit("2021-03-01T12:00:00+00:00");
+        // Re-adding a deleted path doesn't count it twice
+        repo.write("a.rs", "a\n")
+            .commit("2022-03-01T12:00:00+00:00");
+
+        let result = run_theseus(repo.path(), &quiet_options()).unwrap();
+        assert_eq!(result.stats.files_at_head, 2);
+        assert_eq!(result.stats.files_ever_seen, 3);
+    }
+
+    #[test]
+    fn test_all_filetypes_also_counts_non_source_files() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\n")
+            .write("README.md", "b\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 1)])
+        );
+        let options = AnalysisOptions {
+            all_filetypes: true,
+            ..quiet_options()
+        };
+        assert_eq!(
+            final_composition(&repo, &options),
+            composition(&[(2019, 3)])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mode_changes_keep_or_drop_the_lines() {
+        let repo = TestRepo::new();
+        repo.write("main.py", "a\nb\n")
+            .write("lib.py", "c\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        // Making a file executable doesn't change its lines
+        repo.set_executable("main.py")
+            .commit("2020-03-01T12:00:00+00:00");
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 3), (2020, 0)])
+        );
+
+        // A symlink has no lines, and the file that comes back in its place is new code
+        repo.symlink("lib.py", "main.py")
+            .commit("2021-03-01T12:00:00+00:00");
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 2), (2020, 0), (2021, 0)])
+        );
+        repo.remove("lib.py")
+            .write("lib.py", "c\n")
+            .commit("2022-03-01T12:00:00+00:00");
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 2), (2020, 0), (2021, 0), (2022, 1)])
+        );
+    }
+
+    #[test]
+    fn test_renamed_files_keep_their_cohorts() {
+        let repo = TestRepo::new();
+        repo.write("src/main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.mkdir("lib/core")
+            .git(&["mv", "src/main.rs", "lib/core/main.rs"]);
+        repo.commit("2021-03-01T12:00:00+00:00");
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 3), (2021, 0)])
+        );
+
+        // Renamed and edited in the same commit
+        repo.mkdir("app")
+            .git(&["mv", "lib/core/main.rs", "app/main.rs"]);
+        repo.write("app/main.rs", "a\nb\nc\nd\n")
+            .commit("2023-03-01T12:00:00+00:00");
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 3), (2021, 0), (2023, 1)])
+        );
+    }
+
+    #[test]
+    fn test_many_files_renamed_and_edited_in_one_commit() {
+        let repo = TestRepo::new();
+        // No two files share a line, so each one can only be paired with its own rename
+        let lines = |i: usize, names: &str| -> String {
+            names.chars().map(|name| format!("{name}{i}\n")).collect()
+        };
+        for i in 0..50 {
+            repo.write(&format!("old/file{i}.rs"), lines(i, "abcdef"));
+        }
+        repo.commit("2019-03-01T12:00:00+00:00");
+        repo.git(&["mv", "old", "new"]);
+        for i in 0..50 {
+            repo.write(&format!("new/file{i}.rs"), lines(i, "abcdefg"));
+        }
+        repo.commit("2021-03-01T12:00:00+00:00");
+
+        let result = run_theseus(repo.path(), &quiet_options()).unwrap();
+        assert_eq!(result.stats.renames_detected, 50);
+        assert_eq!(
+            result.composition_at(1).unwrap(),
+            Some(HashMap::from([(2019, 300), (2021, 50)]))
+        );
+
+        // Like git's diff.renameLimit, the limit is on the number of files, not of pairs
+        let limited = quiet_options().rename_limit(10);
+        let result = run_theseus(repo.path(), &limited).unwrap();
+        assert_eq!(result.stats.renames_detected, 0);
+    }
+
+    #[test]
+    fn test_detected_renames_and_copies_are_counted() {
+        let repo = TestRepo::new();
+        let lines = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        repo.write("main.rs", lines)
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.git(&["mv", "main.rs", "lib.rs"]);
+        repo.commit("2020-03-01T12:00:00+00:00");
+        // A copy is only looked for among the files changed in the same commit
+        repo.write("lib.rs", format!("{lines}i\n"))
+            .write("copy.rs", lines)
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let result = run_theseus(repo.path(), &quiet_options()).unwrap();
+        assert_eq!(result.stats.renames_detected, 1);
+        assert_eq!(result.stats.copies_detected, 0);
+
+        let options = AnalysisOptions {
+            detect_copies: true,
+            ..quiet_options()
+        };
+        let result = run_theseus(repo.path(), &options).unwrap();
+        assert_eq!(result.stats.renames_detected, 1);
+        assert_eq!(result.stats.copies_detected, 1);
+        // The copy is new code and the file it was copied from keeps its lines
+        assert_eq!(result.stats.lines_at_head, 17);
+        assert_eq!(
+            final_composition(&repo, &options),
+            composition(&[(2019, 8), (2020, 0), (2021, 9)])
+        );
+    }
+
+    #[test]
+    fn test_copies_and_renames_to_skipped_filetypes_are_counted() {
+        let repo = TestRepo::new();
+        let lines = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        repo.write("lib.rs", lines)
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("lib.rs", format!("{lines}i\n"))
+            .write("copy.bin", lines)
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.git(&["mv", "lib.rs", "lib.bin"]);
+        repo.commit("2021-03-01T12:00:00+00:00");
+
+        let options = AnalysisOptions {
+            detect_copies: true,
+            ..quiet_options()
+        };
+        let result = run_theseus(repo.path(), &options).unwrap();
+        assert_eq!(result.stats.copies_detected, 1);
+        assert_eq!(result.stats.renames_detected, 1);
+        assert_eq!(result.stats.files_skipped, 2);
+        assert_eq!(result.stats.lines_at_head, 0);
+    }
+
+    #[test]
+    fn test_crlf_to_lf_conversion_keeps_cohort_with_ignore_eol() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\r\nb\r\nc\r\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let ignore_eol = AnalysisOptions {
+            ignore_eol: true,
+            ..quiet_options()
+        };
+        assert_eq!(
+            final_composition(&repo, &ignore_eol),
+            composition(&[(2019, 3), (2021, 0)])
+        );
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 0), (2021, 3)])
+        );
+    }
+
+    #[test]
+    fn test_first_commit_cohort_gets_its_own_label() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\ne\n")
+            .commit("2019-06-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\ne\nf\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let format_options = FormatOptions {
+            first_commit_label: Some("Pre-history".to_string()),
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        let mut expected = vec![("Pre-history".to_string(), 3)];
+        expected.extend(composition(&[(2019, 2), (2021, 1)]));
+        assert_eq!(
+            final_formatted_composition(&repo, &quiet_options(), &format_options),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_cohorts_can_be_split_by_quarter_or_month() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-01-15T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2019-02-15T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\ne\nf\n")
+            .commit("2019-11-15T12:00:00+00:00");
+
+        let labels = |granularity| {
+            let format_options = FormatOptions {
+                cohort_granularity: granularity,
+                verbosity: Verbosity::Quiet,
+                ..Default::default()
+            };
+            final_formatted_composition(&repo, &quiet_options(), &format_options)
+        };
+        let expected = |counts: &[(&str, i64)]| -> Vec<(String, i64)> {
+            counts
+                .iter()
+                .map(|(period, lines)| (format!("Code added in {period}"), *lines))
+                .collect()
+        };
+        assert_eq!(labels(CohortGranularity::Year), composition(&[(2019, 6)]));
+        assert_eq!(
+            labels(CohortGranularity::Quarter),
+            expected(&[("2019-Q1", 4), ("2019-Q4", 2)])
+        );
+        assert_eq!(
+            labels(CohortGranularity::Month),
+            expected(&[("2019-01", 3), ("2019-02", 1), ("2019-11", 2)])
+        );
+    }
+
+    #[test]
+    fn test_boundary_collapses_the_cohorts_on_one_side() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2018-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.git(&["tag", "v1"]);
+        repo.write("main.rs", "a\nb\nc\nd\ne\nf\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\ne\nf\ng\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let time = commit_time(repo.path(), "v1").unwrap();
+        let mut format_options = FormatOptions {
+            boundary: Some(CohortBoundary {
+                time,
+                label: "Before v1".to_string(),
+                collapse_after: false,
+            }),
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        let mut expected = vec![("Before v1".to_string(), 4)];
+        expected.extend(composition(&[(2020, 2), (2021, 1)]));
+        assert_eq!(
+            final_formatted_composition(&repo, &quiet_options(), &format_options),
+            expected
+        );
+
+        let boundary = format_options.boundary.as_mut().unwrap();
+        boundary.label = "After v1".to_string();
+        boundary.collapse_after = true;
+        let mut expected = composition(&[(2018, 3), (2019, 1)]);
+        expected.push(("After v1".to_string(), 3));
+        assert_eq!(
+            final_formatted_composition(&repo, &quiet_options(), &format_options),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_boundary_compares_commit_times_across_time_zones() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2018-03-01T12:00:00+00:00");
+        // At 03:00 UTC, so the commit after it is later even though its local time is earlier
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2019-03-01T12:00:00+09:00");
+        repo.git(&["tag", "v1"]);
+        repo.write("main.rs", "a\nb\nc\nd\ne\n")
+            .commit("2019-03-01T06:00:00-05:00");
+
+        let format_options = FormatOptions {
+            boundary: Some(CohortBoundary {
+                time: commit_time(repo.path(), "v1").unwrap(),
+                label: "Before v1".to_string(),
+                collapse_after: false,
+            }),
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        let mut expected = vec![("Before v1".to_string(), 3)];
+        expected.extend(composition(&[(2019, 2)]));
+        assert_eq!(
+            final_formatted_composition(&repo, &quiet_options(), &format_options),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_composition_at_a_past_snapshot() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nd\ne\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let result = run_theseus(repo.path(), &quiet_options()).unwrap();
+        assert_eq!(
+            result.composition_at(0).unwrap(),
+            Some(HashMap::from([(2019, 3)]))
+        );
+        assert_eq!(
+            result.composition_at(1).unwrap(),
+            Some(HashMap::from([(2019, 2), (2021, 2)]))
+        );
+        assert_eq!(result.composition_at(2).unwrap(), None);
+    }
+
+    #[test]
+    fn test_changes_of_a_commit_apply_the_same_on_every_run() {
+        let repo = TestRepo::new();
+        repo.write("a.rs", "a\nb\nc\nd\n")
+            .write("b.rs", "e\nf\n")
+            .write("c.rs", "g\nh\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.git(&["mv", "a.rs", "moved.rs"]);
+        repo.remove("b.rs")
+            .write("c.rs", "g\nh\ni\n")
+            .write("d.rs", "j\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        for _ in 0..3 {
+            assert_eq!(
+                final_composition(&repo, &quiet_options()),
+                composition(&[(2019, 6), (2021, 2)])
+            );
+        }
+    }
+
+    #[test]
+    fn test_stream_snapshots_writes_a_line_per_commit() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nd\ne\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("snapshots.ndjson");
+
+        let options = quiet_options().stream_snapshots(path.clone());
+        run_theseus(repo.path(), &options).unwrap();
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["cohorts"], serde_json::json!({"2019": 3}));
+        assert_eq!(
+            lines[1]["cohorts"],
+            serde_json::json!({"2019": 2, "2021": 2})
+        );
+        assert_eq!(
+            lines[1]["commit"],
+            repo.git(&["rev-parse", "--short=7", "HEAD"]).trim()
+        );
+    }
+
+    #[test]
+    fn test_accurate_baseline_blames_the_first_commit_through_its_history() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\ne\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\ne\nf\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let last_commit_only = quiet_options().max_commits(1);
+        assert_eq!(
+            final_composition(&repo, &last_commit_only),
+            composition(&[(2021, 6)])
+        );
+
+        let accurate_baseline = last_commit_only.accurate_baseline(true);
+        assert_eq!(
+            final_composition(&repo, &accurate_baseline),
+            composition(&[(2019, 3), (2020, 2), (2021, 1)])
+        );
+        let result = run_theseus(repo.path(), &accurate_baseline).unwrap();
+        assert_eq!(result.stats.baseline_commits, 2);
+        assert_eq!(result.analyzed_commits().len(), 1);
+    }
+
+    #[test]
+    fn test_spooled_results_match_in_memory_ones() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nc\nd\ne\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let spooled = AnalysisOptions {
+            spool_results: true,
+            ..quiet_options()
+        };
+        assert_eq!(
+            final_composition(&repo, &spooled),
+            composition(&[(2019, 2), (2021, 2)])
+        );
+    }
+
+    #[test]
+    fn test_skip_merges_credits_merged_lines_to_the_branch() {
+        let repo = TestRepo::new();
+        repo.write("a.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.git(&["checkout", "--quiet", "-b", "feature"]);
+        repo.write("b.rs", "x\ny\n")
+            .commit("2019-06-01T12:00:00+00:00");
+        repo.git(&["checkout", "--quiet", "main"]);
+        repo.write("a.rs", "a\nb\nc\nd\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.merge("feature", "2021-03-01T12:00:00+00:00");
+
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 3), (2020, 1), (2021, 2)])
+        );
+        let skip_merges = AnalysisOptions {
+            skip_merges: true,
+            ..quiet_options()
+        };
+        assert_eq!(
+            final_composition(&repo, &skip_merges),
+            composition(&[(2019, 5), (2020, 1), (2021, 0)])
+        );
+    }
+
+    #[test]
+    fn test_skip_merges_compares_commit_times_across_time_zones() {
+        let repo = TestRepo::new();
+        repo.write("a.rs", "a\nb\nc\n")
+            .commit("2018-03-01T12:00:00+00:00");
+        repo.git(&["checkout", "--quiet", "-b", "feature"]);
+        // At 03:00 UTC, before the commit on main even though its local time is later
+        repo.write("b.rs", "x\ny\n")
+            .commit("2019-01-01T12:00:00+09:00");
+        repo.git(&["checkout", "--quiet", "main"]);
+        repo.write("a.rs", "a\nb\nc\nd\n")
+            .commit("2019-01-01T06:00:00-05:00");
+        repo.merge("feature", "2021-03-01T12:00:00+00:00");
+
+        let skip_merges = AnalysisOptions {
+            skip_merges: true,
+            ..quiet_options()
+        };
+        assert_eq!(
+            final_composition(&repo, &skip_merges),
+            composition(&[(2018, 5), (2019, 1), (2021, 0)])
+        );
+    }
+
+    #[test]
+    fn test_skip_merges_finds_merges_before_the_last_commit_of_the_week() {
+        let repo = TestRepo::new();
+        repo.write("a.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.git(&["checkout", "--quiet", "-b", "feature"]);
+        repo.write("b.rs", "x\ny\n")
+            .commit("2019-06-01T12:00:00+00:00");
+        repo.git(&["checkout", "--quiet", "main"]);
+        repo.write("a.rs", "a\nb\nc\nd\n")
+            .commit("2020-03-02T12:00:00+00:00");
+        // The merge is on Monday and the week's commit is the one after it
+        repo.merge("feature", "2021-03-01T12:00:00+00:00");
+        repo.write("a.rs", "a\nb\nc\nd\ne\n")
+            .commit("2021-03-03T12:00:00+00:00");
+
+        let skip_merges = AnalysisOptions {
+            skip_merges: true,
+            ..quiet_options()
+        };
+        assert_eq!(
+            final_composition(&repo, &skip_merges),
+            composition(&[(2019, 6), (2020, 1), (2021, 0)])
+        );
+    }
+
+    #[test]
+    fn test_skip_merges_cant_be_combined_with_authors() {
+        let repo = TestRepo::new();
+        repo.write("a.rs", "a\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        let options = AnalysisOptions {
+            skip_merges: true,
+            authors: vec!["test@example.com".to_string()],
+            ..quiet_options()
+        };
+        assert!(matches!(
+            run_theseus(repo.path(), &options),
+            Err(TheseusError::IncompatibleOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_authors_leave_out_the_lines_of_other_authors() {
+        let repo = TestRepo::new();
+        repo.write("a.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("a.rs", "a\nb\nc\nd\ne\n")
+            .commit_as("2020-03-01T12:00:00+00:00", "other@example.com");
+        // The same week, which weekly commits would lump together
+        repo.write("a.rs", "a\nb\nc\nd\ne\nf\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        repo.write("a.rs", "a\nb\nc\nd\ne\nf\ng\n")
+            .commit_as("2021-03-02T12:00:00+00:00", "other@example.com");
+
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 3), (2020, 2), (2021, 2)])
+        );
+        let options = quiet_options().authors(vec!["Theseus@example.com".to_string()]);
+        assert_eq!(
+            final_composition(&repo, &options),
+            composition(&[(2019, 3), (2020, 0), (2021, 1)])
+        );
+        let result = run_theseus(repo.path(), &options).unwrap();
+        assert_eq!(result.cohort_data.len(), 4);
+        assert_eq!(result.lines_added.len(), 2);
+    }
+
+    #[test]
+    fn test_excluded_commit_lines_go_to_the_next_commit() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("vendor.rs", "v\nw\nx\ny\nz\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        let bulk_import = repo.git(&["rev-parse", "--short", "HEAD"]);
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let options = quiet_options().exclude_commits(vec![bulk_import.trim().to_string()]);
+        assert_eq!(
+            final_composition(&repo, &options),
+            composition(&[(2019, 3), (2021, 6)])
+        );
+        let result = run_theseus(repo.path(), &options).unwrap();
+        assert_eq!(result.cohort_data.len(), 2);
+    }
+
+    #[test]
+    fn test_excluding_a_commit_that_isnt_analyzed_is_reported() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        // Two commits in the same week, of which only the last one is analyzed
+        repo.write("main.rs", "a\nb\n")
+            .commit("2019-03-04T12:00:00+00:00");
+        let earlier_in_week = repo.git(&["rev-parse", "HEAD"]);
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-06T12:00:00+00:00");
+        let last_of_week = repo.git(&["rev-parse", "HEAD"]);
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let gix_repo = open_repo(repo.path()).unwrap();
+        let commits = select_commits(&gix_repo, &quiet_options(), None).unwrap();
+        assert_eq!(commits.len(), 3);
+        let specs = vec![
+            earlier_in_week.trim().to_string(),
+            last_of_week.trim().to_string(),
+        ];
+        let (commits, unmatched) = exclude_commits(&gix_repo, commits, &specs).unwrap();
+        assert_eq!(commits.len(), 2);
+        assert_eq!(unmatched, vec![&specs[0]]);
+    }
+
+    #[test]
+    fn test_run_times_each_phase() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        let result = run_theseus(repo.path(), &quiet_options()).unwrap();
+        let phases: Vec<&str> = result
+            .stats
+            .phases
+            .phases
+            .iter()
+            .map(|(name, _)| *name)
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                "commit listing",
+                "tree loading",
+                "tree diffs",
+                "blame accumulation"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_a_queue_of_one_action_gives_the_same_composition() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .write("lib.rs", "x\ny\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nc\nd\ne\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let options = quiet_options().queue_size(1);
+        assert_eq!(
+            final_composition(&repo, &options),
+            final_composition(&repo, &quiet_options())
+        );
+        assert_eq!(
+            final_composition(&repo, &options),
+            composition(&[(2019, 4), (2021, 2)])
+        );
+    }
+
+    #[test]
+    fn test_a_commit_with_more_changes_than_a_chunk_is_applied_in_order() {
+        let repo = TestRepo::new();
+        let files = rayon::current_num_threads() * CHANGES_IN_FLIGHT_PER_THREAD * 3;
+        for i in 0..files {
+            repo.write(&format!("f{i}.rs"), format!("a{i}\nb{i}\n"));
+        }
+        repo.commit("2019-03-01T12:00:00+00:00");
+        for i in 0..files {
+            if i % 2 == 0 {
+                repo.remove(&format!("f{i}.rs"));
+            } else {
+                repo.write(&format!("f{i}.rs"), format!("a{i}\nb{i}\nc{i}\n"));
+            }
+        }
+        repo.commit("2021-03-01T12:00:00+00:00");
+
+        let options = quiet_options().queue_size(1);
+        assert_eq!(
+            final_composition(&repo, &options),
+            composition(&[(2019, files as i64), (2021, (files / 2) as i64)])
+        );
+    }
+
+    #[test]
+    fn test_min_lines_skips_small_files_and_their_changes() {
+        let repo = TestRepo::new();
+        repo.write("big.rs", "a\nb\nc\n")
+            .write("tiny.rs", "a\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("big.rs", "a\nb\nc\nd\n")
+            .write("tiny.rs", "a\nb\nc\nd\ne\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        repo.git(&["rm", "--quiet", "tiny.rs"]);
+        repo.commit("2023-03-01T12:00:00+00:00");
+
+        let min_lines = AnalysisOptions {
+            min_lines: 2,
+            ..quiet_options()
+        };
+        let result = run_theseus(repo.path(), &min_lines).unwrap();
+        assert_eq!(result.stats.small_files_skipped, 1);
+        assert_eq!(result.stats.files_at_head, 1);
+        assert_eq!(
+            final_composition(&repo, &min_lines),
+            composition(&[(2019, 3), (2021, 1), (2023, 0)])
+        );
+    }
+
+    #[test]
+    fn test_cohort_year_uses_the_commit_time_zone() {
+        let repo = TestRepo::new();
+        // Already 2020 in UTC, but still 2019 where it was made
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-12-31T23:30:00-05:00");
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2020-03-01T12:00:00+00:00");
+
+        let result = run_theseus(repo.path(), &quiet_options()).unwrap();
+        let first_commit = &result.commit_cohort_info[0];
+        assert_eq!(first_commit.year, 2019);
+        assert_eq!(first_commit.time_string, "2019-12-31 23:30:00");
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 3), (2020, 1)])
+        );
+    }
+
+    #[test]
+    fn test_include_worktree_adds_uncommitted_changes() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .write("old.rs", "a\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nx\ny\n")
+            .write("new.rs", "a\nb\n");
+        repo.git(&["add", "new.rs"]);
+        std::fs::remove_file(std::path::Path::new(repo.path()).join("old.rs")).unwrap();
+
+        let include_worktree = AnalysisOptions {
+            include_worktree: true,
+            ..quiet_options()
+        };
+        let result = run_theseus(repo.path(), &include_worktree).unwrap();
+        assert_eq!(result.commit_cohort_info.len(), 2);
+        assert!(result.commit_cohort_info[1].id.is_null());
+        let this_year = chrono::Local::now().year() as u32;
+        assert_eq!(
+            final_composition(&repo, &include_worktree),
+            composition(&[(2019, 2), (this_year, 4)])
+        );
+    }
+
+    #[test]
+    fn test_include_worktree_runs_the_files_through_the_clean_filter() {
+        let repo = TestRepo::new();
+        repo.git(&["config", "filter.nocomments.clean", "sed -e /^#/d"]);
+        repo.git(&["config", "filter.nocomments.smudge", "cat"]);
+        // The comment never makes it into the commit
+        repo.write(".gitattributes", "*.py filter=nocomments\n")
+            .write("main.py", "# note\na\nb\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.py", "# note\na\nb\nc\n");
+
+        let include_worktree = AnalysisOptions {
+            include_worktree: true,
+            ..quiet_options()
+        };
+        let this_year = chrono::Local::now().year() as u32;
+        assert_eq!(
+            final_composition(&repo, &include_worktree),
+            composition(&[(2019, 2), (this_year, 1)])
+        );
+    }
+
+    #[test]
+    fn test_files_marked_as_text_by_gitattributes_are_analyzed() {
+        let repo = TestRepo::new();
+        repo.write(".gitattributes", "*.recipe diff\n")
+            .write("bread.recipe", "flour\nwater\nsalt\n")
+            .write("cake.mix", "eggs\nsugar\n")
+            .commit("2019-03-01T12:00:00+00:00");
+
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 3)])
+        );
+    }
+
+    #[test]
+    fn test_include_worktree_fails_on_bare_repos() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        let bare = tempfile::TempDir::new().unwrap();
+        let bare_path = bare.path().to_str().unwrap();
+        repo.git(&["clone", "--quiet", "--bare", ".", bare_path]);
+
+        let include_worktree = AnalysisOptions {
+            include_worktree: true,
+            ..quiet_options()
+        };
+        assert!(matches!(
+            run_theseus(bare_path, &include_worktree),
+            Err(TheseusError::BareRepo(_))
+        ));
+    }
+
+    #[test]
+    fn test_revive_deleted_restores_old_cohorts() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.git(&["rm", "--quiet", "main.rs"]);
+        repo.commit("2020-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 0), (2020, 0), (2021, 3)])
+        );
+        let revive_deleted = AnalysisOptions {
+            revive_deleted: true,
+            ..quiet_options()
+        };
+        assert_eq!(
+            final_composition(&repo, &revive_deleted),
+            composition(&[(2019, 3), (2020, 0), (2021, 0)])
+        );
+    }
+
+    #[test]
+    fn test_range_starts_from_the_tree_at_its_base() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.git(&["tag", "v1"]);
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        repo.git(&["tag", "v2"]);
+        repo.write("main.rs", "a\nb\nc\nd\ne\n")
+            .commit("2022-03-01T12:00:00+00:00");
+
+        let range = AnalysisOptions {
+            range: Some("v1..v2".to_string()),
+            ..quiet_options()
+        };
+        let result = run_theseus(repo.path(), &range).unwrap();
+        assert_eq!(result.commit_cohort_info.len(), 2);
+        assert_eq!(
+            final_composition(&repo, &range),
+            composition(&[(2020, 3), (2021, 1)])
+        );
+
+        // Up to HEAD
+        let range = AnalysisOptions {
+            range: Some("v2..".to_string()),
+            ..quiet_options()
+        };
+        assert_eq!(
+            final_composition(&repo, &range),
+            composition(&[(2021, 4), (2022, 1)])
+        );
+    }
+
+    #[test]
+    fn test_tags_bound_the_analyzed_commits_by_date() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.git(&["tag", "-a", "v1", "-m", "v1"]);
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        repo.git(&["tag", "v2"]);
+        repo.write("main.rs", "a\nb\nc\nd\ne\n")
+            .commit("2022-03-01T12:00:00+00:00");
+
+        let options = quiet_options()
+            .since_tag("v1".to_string())
+            .until_tag("v2".to_string());
+        let result = run_theseus(repo.path(), &options).unwrap();
+        assert_eq!(result.commit_cohort_info.len(), 2);
+        assert_eq!(
+            final_composition(&repo, &options),
+            composition(&[(2020, 3), (2021, 1)])
+        );
+
+        let missing = quiet_options().since_tag("v9".to_string());
+        assert!(matches!(
+            run_theseus(repo.path(), &missing),
+            Err(TheseusError::TagNotFound(tag)) if tag == "v9"
+        ));
+    }
+
+    #[test]
+    fn test_include_only_analyzes_matching_paths() {
+        let repo = TestRepo::new();
+        repo.write("src/main.rs", "a\nb\n")
+            .write("tests/main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("src/main.rs", "a\nb\nc\n")
+            .write("tests/main.rs", "a\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        // Moving a file out of the included paths takes its lines out of the analysis
+        repo.git(&["mv", "src/main.rs", "tests/other.rs"]);
+        repo.write("src/lib.rs", "a\n")
+            .commit("2022-03-01T12:00:00+00:00");
+
+        let include = AnalysisOptions {
+            include: vec!["src/**".to_string()],
+            ..quiet_options()
+        };
+        let result = run_theseus(repo.path(), &include).unwrap();
+        assert_eq!(result.stats.files_at_head, 1);
+        assert_eq!(
+            final_composition(&repo, &include),
+            composition(&[(2019, 0), (2021, 0), (2022, 1)])
+        );
+    }
+
+    #[test]
+    fn test_analyze_returns_the_formatted_cohorts() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let data = analyze(repo.path(), &quiet_options(), &FormatOptions::default()).unwrap();
+        assert_eq!(data.ts.len(), 2);
+        assert_eq!(
+            data.labels,
+            vec!["Code added in 2019", "Code added in 2021"]
+        );
+        assert_eq!(data.y, vec![vec![2, 2], vec![0, 1]]);
+    }
+
+    #[test]
+    fn test_file_without_trailing_newline_keeps_consistent_blame() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb")
+            .commit("2019-03-01T12:00:00+00:00");
+        // Adding a line rewrites the last one, which gains a newline
+        repo.write("main.rs", "a\nb\nc")
+            .commit("2021-03-01T12:00:00+00:00");
+
+        let result = run_theseus(repo.path(), &quiet_options()).unwrap();
+        assert_eq!(result.stats.lines_at_head, 3);
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 1), (2021, 2)])
+        );
+
+        // Only the last line gets rewritten each time, the ones before keep their cohorts
+        repo.write("main.rs", "a\nb\nc\nd")
+            .commit("2022-03-01T12:00:00+00:00");
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 1), (2021, 1), (2022, 2)])
+        );
+    }
+
+    #[test]
+    fn test_commit_metadata_is_only_added_when_asked_for() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        let head = repo.git(&["rev-parse", "--short=7", "HEAD"]);
+
+        let data = analyze(repo.path(), &quiet_options(), &FormatOptions::default()).unwrap();
+        assert!(data.commits.is_none());
+        assert!(!serde_json::to_string(&data).unwrap().contains("commits"));
+
+        let format_options = FormatOptions {
+            commit_metadata: true,
+            ..Default::default()
+        };
+        let data = analyze(repo.path(), &quiet_options(), &format_options).unwrap();
+        let commits = data.commits.unwrap();
+        assert_eq!(commits[0].hash, head.trim());
+        assert_eq!(commits[0].summary, "2019-03-01T12:00:00+00:00");
+    }
+
+    #[test]
+    fn test_update_from_checkpoint_matches_a_full_run() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        let dir = tempfile::TempDir::new().unwrap();
+        let checkpoint = AnalysisOptions {
+            checkpoint: Some(dir.path().join("checkpoint.json")),
+            ..quiet_options()
+        };
+        run_theseus(repo.path(), &checkpoint).unwrap();
+
+        repo.write("main.rs", "a\nc\nd\ne\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let update = AnalysisOptions {
+            update: true,
+            ..checkpoint.clone()
+        };
+        let updated = run_theseus(repo.path(), &update).unwrap();
+        let full = run_theseus(repo.path(), &quiet_options()).unwrap();
+        assert_eq!(updated.commit_cohort_info.len(), 3);
+        assert_eq!(updated.cohort_data.len(), full.cohort_data.len());
+        let mut updated_snapshot = updated.cohort_data.last().unwrap().unwrap();
+        let mut full_snapshot = full.cohort_data.last().unwrap().unwrap();
+        updated_snapshot.sort();
+        full_snapshot.sort();
+        assert_eq!(updated_snapshot, full_snapshot);
+
+        // Nothing new since the last update
+        assert_eq!(
+            final_composition(&repo, &update),
+            composition(&[(2019, 2), (2020, 1), (2021, 1)])
+        );
+
+        let without_checkpoint = AnalysisOptions {
+            update: true,
+            ..quiet_options()
+        };
+        assert!(matches!(
+            run_theseus(repo.path(), &without_checkpoint),
+            Err(TheseusError::IncompatibleOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_final_only_keeps_the_last_snapshot() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nc\nd\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let final_only = AnalysisOptions {
+            final_only: true,
+            ..quiet_options()
+        };
+
+        let data = analyze(repo.path(), &final_only, &FormatOptions::default()).unwrap();
+        assert_eq!(data.ts, vec!["2021-03-01 12:00:00".to_string()]);
+        assert_eq!(data.y, vec![vec![2], vec![1]]);
+        assert_eq!(
+            final_composition(&repo, &final_only),
+            final_composition(&repo, &quiet_options())
+        );
+    }
+
+    #[test]
+    fn test_ever_written_keeps_the_deleted_lines() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nc\nd\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let ever_written = AnalysisOptions {
+            ever_written: true,
+            ..quiet_options()
+        };
+
+        let result = run_theseus(repo.path(), &ever_written).unwrap();
+        let data = crate::formatter::format_ever_written(&result, &FormatOptions::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(data.y, vec![vec![3, 3], vec![0, 1]]);
+        let surviving = format_cohort_data(&result, &FormatOptions::default()).unwrap();
+        assert_eq!(surviving.y, vec![vec![3, 2], vec![0, 1]]);
+        assert!(
+            run_theseus(repo.path(), &quiet_options())
+                .unwrap()
+                .ever_written
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_by_extension_follows_renames_across_extensions() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\nc\n")
+            .write("tool.py", "x\ny\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\n");
+        repo.git(&["mv", "tool.py", "tool.rs"]);
+        repo.commit("2021-03-01T12:00:00+00:00");
+        let by_extension = AnalysisOptions {
+            by_extension: true,
+            ..quiet_options()
+        };
+
+        let result = run_theseus(repo.path(), &by_extension).unwrap();
+        let snapshots = result.by_extension.as_ref().unwrap();
+        assert_eq!(
+            snapshots[0],
+            vec![
+                ("py".to_string(), vec![(0, 2)]),
+                ("rs".to_string(), vec![(0, 3)])
+            ]
+        );
+        assert_eq!(snapshots[1], vec![("rs".to_string(), vec![(0, 5), (1, 1)])]);
+
+        let data = crate::formatter::format_by_extension(&result, &FormatOptions::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(data["py"].y, vec![vec![2, 0], vec![0, 0]]);
+        assert_eq!(data["rs"].y, vec![vec![3, 5], vec![0, 1]]);
+    }
+
+    #[test]
+    fn test_by_directory_follows_files_moved_between_directories() {
+        let repo = TestRepo::new();
+        repo.write("src/main.rs", "a\nb\nc\n")
+            .write("src/util.rs", "x\ny\n")
+            .write("build.rs", "z\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.mkdir("tests")
+            .git(&["mv", "src/util.rs", "tests/util.rs"]);
+        repo.write("tests/util.rs", "x\ny\nw\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let by_directory = AnalysisOptions {
+            by_directory: true,
+            ..quiet_options()
+        };
+
+        let result = run_theseus(repo.path(), &by_directory).unwrap();
+        assert!(result.by_extension.is_none());
+        let snapshots = result.by_directory.as_ref().unwrap();
+        assert_eq!(
+            snapshots[0],
+            vec![
+                (".".to_string(), vec![(0, 1)]),
+                ("src".to_string(), vec![(0, 5)])
+            ]
+        );
+        assert_eq!(
+            snapshots[1],
+            vec![
+                (".".to_string(), vec![(0, 1)]),
+                ("src".to_string(), vec![(0, 3)]),
+                ("tests".to_string(), vec![(0, 2), (1, 1)])
+            ]
+        );
+
+        let data = crate::formatter::format_by_directory(&result, &FormatOptions::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(data["src"].y, vec![vec![5, 3], vec![0, 0]]);
+        assert_eq!(data["tests"].y, vec![vec![0, 2], vec![0, 1]]);
+    }
+
+    #[test]
+    fn test_run_on_commits_only_snapshots_the_given_commits() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2020-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\nd\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        let commit_id =
+            |spec: &str| gix::ObjectId::from_hex(repo.git(&["rev-parse", spec]).trim().as_bytes());
+        let ids = vec![commit_id("HEAD~2").unwrap(), commit_id("HEAD").unwrap()];
+
+        let result =
+            run_theseus_on_commits(repo.path(), &ids, &quiet_options(), |commit_idx, _| {
+                commit_idx
+            })
+            .unwrap();
+        assert_eq!(result.commit_cohort_info.len(), 2);
+        assert_eq!(result.commit_cohort_info[1].year, 2021);
+        let mut last_snapshot = result.cohort_data.last().unwrap().unwrap();
+        last_snapshot.sort();
+        // The line of the skipped 2020 commit is credited to the 2021 one
+        assert_eq!(last_snapshot, vec![(0, 2), (1, 2)]);
+    }
+
+    #[test]
+    fn test_unreadable_file_is_skipped_instead_of_failing_the_run() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\n")
+            .write("other.rs", "x\ny\nz\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.write("main.rs", "a\nb\nc\n")
+            .commit("2021-03-01T12:00:00+00:00");
+        // Lose the new blob of main.rs, as if the object were corrupt
+        let blob = repo.git(&["rev-parse", "HEAD:main.rs"]);
+        let blob = blob.trim();
+        std::fs::remove_file(
+            std::path::Path::new(repo.path())
+                .join(".git/objects")
+                .join(&blob[..2])
+                .join(&blob[2..]),
+        )
+        .unwrap();
+
+        let result = run_theseus(repo.path(), &quiet_options()).unwrap();
+        assert_eq!(result.failures.len(), 1);
+        assert_eq!(result.failures[0].path, "main.rs");
+        assert_eq!(result.cohort_data.last().unwrap().unwrap(), vec![(0, 3)]);
+    }
+
+    #[test]
+    fn test_head_at_an_annotated_tag_is_peeled_to_its_commit() {
+        let repo = TestRepo::new();
+        repo.write("main.rs", "a\nb\n")
+            .commit("2019-03-01T12:00:00+00:00");
+        repo.git(&["tag", "--annotate", "v1", "--message", "v1"]);
+        let tag = repo.git(&["rev-parse", "v1"]);
+        // git refuses to point HEAD at anything but a commit, so we write it ourselves
+        repo.write(".git/HEAD", &tag);
+        assert_eq!(
+            final_composition(&repo, &quiet_options()),
+            composition(&[(2019, 2)])
+        );
+
+        let tree = repo.git(&["rev-parse", "v1^{tree}"]);
+        repo.write(".git/HEAD", &tree);
+        assert!(matches!(
+            run_theseus(repo.path(), &quiet_options()),
+            Err(TheseusError::RevisionNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_repo_name_ignores_trailing_separators() {
+        assert_eq!(repo_name("src/linux"), "linux");
+        assert_eq!(repo_name("src/linux/"), "linux");
+        assert_eq!(repo_name("src/linux\\"), "linux");
+        let current_dir = std::env::current_dir().unwrap();
+        assert_eq!(
+            repo_name("."),
+            current_dir.file_name().unwrap().to_string_lossy()
+        );
+    }
+}
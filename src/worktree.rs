@@ -0,0 +1,128 @@
+// Reads the uncommitted changes in the worktree, so they can be analyzed as one last pseudo-commit.
+// We compare the files of HEAD's tree to their worktree version, and also pick up files that were
+// added to the index but not committed yet. Untracked files are left out, like git status does
+// with its default settings for changes to be committed.
+// The worktree files go through the repo's filters first, e.g. clean filters and line ending
+// conversion, so they're compared in the form they'd be committed in.
+
+use crate::error::{Result, TheseusError};
+use gix::bstr::{BStr, BString};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+pub enum WorktreeChange {
+    Addition {
+        path: BString,
+        data: Vec<u8>,
+    },
+    Deletion {
+        path: BString,
+        id: gix::ObjectId,
+    },
+    Modification {
+        path: BString,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+}
+
+pub fn worktree_changes(repo: &gix::Repository) -> Result<Vec<WorktreeChange>> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| TheseusError::BareRepo(repo.git_dir().to_owned()))?;
+
+    let mut recorder = gix::traverse::tree::Recorder::default();
+    repo.head_commit()
+        .map_err(|_| TheseusError::EmptyRepo)?
+        .tree()
+        .map_err(TheseusError::odb)?
+        .traverse()
+        .breadthfirst(&mut recorder)
+        .map_err(TheseusError::traversal)?;
+    let head_blobs: HashMap<BString, gix::ObjectId> = recorder
+        .records
+        .into_iter()
+        .filter(|entry| entry.mode.is_blob())
+        .map(|entry| (entry.filepath, entry.oid))
+        .collect();
+
+    let (mut pipeline, index) = repo.filter_pipeline(None).map_err(TheseusError::odb)?;
+    let mut changes = Vec::new();
+    for (path, id) in &head_blobs {
+        let old = repo
+            .find_blob(*id)
+            .map_err(TheseusError::odb)?
+            .detach()
+            .data;
+        match read_worktree_file(workdir, path.as_ref(), &mut pipeline, &index)? {
+            None => changes.push(WorktreeChange::Deletion {
+                path: path.clone(),
+                id: *id,
+            }),
+            Some(new) if new != old => changes.push(WorktreeChange::Modification {
+                path: path.clone(),
+                old,
+                new,
+            }),
+            Some(_) => {}
+        }
+    }
+
+    for entry in index.entries() {
+        let path = entry.path(&index);
+        if head_blobs.contains_key(path) {
+            continue;
+        }
+        if let Some(data) = read_worktree_file(workdir, path, &mut pipeline, &index)? {
+            changes.push(WorktreeChange::Addition {
+                path: path.to_owned(),
+                data,
+            });
+        }
+    }
+    Ok(changes)
+}
+
+// Returns None if there is no regular file at that path anymore
+fn read_worktree_file(
+    workdir: &Path,
+    path: &BStr,
+    pipeline: &mut gix::filter::Pipeline<'_>,
+    index: &gix::index::State,
+) -> Result<Option<Vec<u8>>> {
+    let full_path = workdir.join(gix::path::from_bstr(path));
+    match std::fs::symlink_metadata(&full_path) {
+        Ok(metadata) if metadata.is_file() => {}
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => {
+            return Err(TheseusError::WorktreeRead {
+                path: full_path,
+                source,
+            });
+        }
+    }
+    filtered_contents(&full_path, path, pipeline, index)
+        .map(Some)
+        .map_err(|source| TheseusError::WorktreeRead {
+            path: full_path,
+            source,
+        })
+}
+
+// A worktree file in the form it would be committed in
+fn filtered_contents(
+    full_path: &Path,
+    path: &BStr,
+    pipeline: &mut gix::filter::Pipeline<'_>,
+    index: &gix::index::State,
+) -> std::io::Result<Vec<u8>> {
+    let file = std::fs::File::open(full_path)?;
+    let mut data = Vec::new();
+    pipeline
+        .convert_to_git(file, &gix::path::from_bstr(path), index)
+        .map_err(std::io::Error::other)?
+        .read_to_end(&mut data)?;
+    Ok(data)
+}